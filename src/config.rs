@@ -1,14 +1,23 @@
+use std::path::PathBuf;
+
 use clap::{AppSettings, Clap};
 
-use crate::{environments::EnvironmentType, models::ModelType};
+use crate::{
+    environment::OpponentType,
+    environments::EnvironmentType,
+    models::{checkpoint::CheckpointConfig, network::NetworkSpec, ModelType},
+    pixels::PixelConfig,
+    wrappers::WrapperConfig,
+};
 
 #[derive(Clone, Debug, Clap)]
 #[clap(setting = AppSettings::ColoredHelp)]
 pub struct Config {
-    #[clap(short, long, possible_values = &["acrobot", "cartpole", "mountaincar", "pendulum", "flappy", "breakout"])]
+    // Registered id like CartPole-v1, case insensitive, without a version the latest
+    #[clap(short, long, case_insensitive = true, possible_values = &EnvironmentType::possible_values())]
     pub environment: Option<EnvironmentType>,
 
-    #[clap(short, long, default_value = "pg", possible_values = &["ppo", "pg", "neat"])]
+    #[clap(short, long, default_value = "pg", possible_values = &["ppo", "pg", "dqn", "sac", "neat"])]
     pub model: ModelType,
 
     #[clap(short, long)]
@@ -16,4 +25,143 @@ pub struct Config {
 
     #[clap(short, long)]
     pub simulation: bool,
+
+    // Copies of the environment pg and ppo train on at once with --simulation
+    #[clap(long, default_value = "1")]
+    pub num_envs: usize,
+
+    // Seeds environments and models so a run can be repeated
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    // Checkpoint directory to resume training from
+    #[clap(long)]
+    pub checkpoint: Option<PathBuf>,
+
+    // Where checkpoints are written
+    #[clap(long, default_value = "runs")]
+    pub run_dir: PathBuf,
+
+    // Play a saved neat champion, see runs/neat/<env>/champion.json
+    #[clap(long)]
+    pub load_genome: Option<PathBuf>,
+
+    // Observe rendered pixels instead of the environment's values
+    #[clap(long)]
+    pub pixels: bool,
+
+    // Width and height of pixel observations
+    #[clap(long, default_value = "84")]
+    pub pixel_size: usize,
+
+    // Color pixel observations, grayscale otherwise
+    #[clap(long)]
+    pub rgb: bool,
+
+    // Network for pg, ppo and dqn, like mlp:64,64, mlp:64,64:relu or cnn
+    #[clap(long)]
+    pub network: Option<NetworkSpec>,
+
+    // Replay buffer capacity for dqn and sac, by default their own unless the observations
+    // are too big for it, like pixels
+    #[clap(long)]
+    pub replay_size: Option<usize>,
+
+    // Second player of two player environments, scripted, human (keyboard) or selfplay
+    // (a frozen snapshot of the model being trained)
+    #[clap(long, default_value = "scripted", possible_values = &["scripted", "human", "selfplay"])]
+    pub opponent: OpponentType,
+
+    // Environment config toml, defaults to params/env/<config_name>.toml of the registry when there is one, see params/examples/cartpole_long_pole.toml
+    #[clap(long)]
+    pub env_config: Option<PathBuf>,
+
+    // Print the environment's default config as toml, to start a params/env/<config_name>.toml from
+    #[clap(long)]
+    pub print_env_config: bool,
+
+    // Wrappers toml file, see params/wrappers.toml, the flags below override it
+    #[clap(long)]
+    pub wrappers: Option<PathBuf>,
+
+    // Normalize observations with their running mean and variance
+    #[clap(long)]
+    pub normalize_observation: bool,
+
+    // Clip observations to +-, after normalizing
+    #[clap(long)]
+    pub clip_observation: Option<f32>,
+
+    #[clap(long)]
+    pub reward_scale: Option<f32>,
+
+    // Clip rewards to +-, after scaling
+    #[clap(long)]
+    pub clip_reward: Option<f32>,
+
+    // Stack the last n observations, defaults to 4 with --pixels and 1 otherwise
+    #[clap(long)]
+    pub frame_stack: Option<usize>,
+
+    // Apply each action for n physics steps
+    #[clap(long)]
+    pub action_repeat: Option<usize>,
+
+    // Cut episodes short after n steps
+    #[clap(long)]
+    pub time_limit: Option<usize>,
+}
+
+impl Config {
+    pub fn checkpoint_config(&self) -> CheckpointConfig {
+        CheckpointConfig {
+            run_dir: self.run_dir.clone(),
+            resume: self.checkpoint.clone(),
+            genome: self.load_genome.clone(),
+        }
+    }
+
+    pub fn wrapper_config(&self) -> WrapperConfig {
+        let defaults = WrapperConfig {
+            frame_stack: if self.pixels { 4 } else { 1 },
+            ..Default::default()
+        };
+        let mut config = match &self.wrappers {
+            Some(path) => WrapperConfig::load(path, defaults),
+            None => defaults,
+        };
+        if self.normalize_observation {
+            config.normalize_observation = true;
+        }
+        if self.clip_observation.is_some() {
+            config.clip_observation = self.clip_observation;
+        }
+        if let Some(reward_scale) = self.reward_scale {
+            config.reward_scale = reward_scale;
+        }
+        if self.clip_reward.is_some() {
+            config.clip_reward = self.clip_reward;
+        }
+        if let Some(frame_stack) = self.frame_stack {
+            config.frame_stack = frame_stack;
+        }
+        if let Some(action_repeat) = self.action_repeat {
+            config.action_repeat = action_repeat;
+        }
+        if self.time_limit.is_some() {
+            config.time_limit = self.time_limit;
+        }
+        config
+    }
+
+    pub fn pixel_config(&self) -> Option<PixelConfig> {
+        if !self.pixels {
+            return None;
+        }
+        Some(PixelConfig {
+            width: self.pixel_size,
+            height: self.pixel_size,
+            grayscale: !self.rgb,
+        })
+    }
 }
@@ -1,37 +1,440 @@
-#[derive(Debug)]
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use bevy::{
+    ecs::{component::Component, system::CommandQueue},
+    prelude::*,
+};
+use bevy_rapier2d::prelude::InteractionGroups;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    error::GymError,
+    models::{checkpoint::CheckpointConfig, network::NetworkSpec, ModelType},
+    multi_agent::MultiAgentState,
+    pixels::{self, PixelConfig},
+    space::{Action, Space},
+    wrappers::{WrapperConfig, Wrappers},
+};
+
+#[derive(Debug, Clone)]
 pub struct Environment {
-    pub action_space: usize,
-    pub observation_space: usize,
+    pub action_space: Space,
+    pub observation_space: Space,
 }
 
 #[derive(Debug, Clone)]
 pub struct EnvironmentState {
-    pub action: Option<usize>,
+    pub action: Option<Action>,
     pub observation: Vec<f32>,
     pub reward: f32,
     pub is_done: Option<bool>,
+    pub info: Info,
 }
 
+pub struct EnvironmentConfig {
+    pub render: bool,
+    pub human: bool,
+    pub model: ModelType,
+    pub seed: Option<u64>,
+    pub checkpoint: CheckpointConfig,
+    // Observe rendered pixels instead of the environment's own values
+    pub pixels: Option<PixelConfig>,
+    // Wrapped around every step, see wrappers
+    pub wrappers: WrapperConfig,
+    // Network for the tch models, each has its own default
+    pub network: Option<NetworkSpec>,
+    // Replay buffer capacity for dqn and sac, each has its own default scaled down for
+    // big observations, see replay::default_capacity
+    pub replay_size: Option<usize>,
+    // What drives the second player of two player environments
+    pub opponent: OpponentType,
+    // Agents in multi-agent environments that have a choice, like flappy's birds
+    pub agents: Option<usize>,
+    // Environment config toml, in place of params/env/<config_name>.toml, see load_env_config
+    pub env_config: Option<PathBuf>,
+}
 
-// Helper function for adding the 2 resources uses to by the gym
-pub fn insert_env_resources(
-    app: &mut bevy::prelude::AppBuilder,
-    action_space: usize,
-    observation_space: usize,
-) {
-    app.insert_resource(Environment {
-        action_space,
-        observation_space,
-    })
-    .insert_resource(EnvironmentState {
-        observation: vec![0.0; observation_space],
-        reward: 0.0,
-        is_done: None,
-        action: None,
-    });
+// All randomness in an environment comes from here, so an episode can be replayed
+pub struct EnvRng(pub Pcg64);
+
+impl EnvRng {
+    // No seed picks one from entropy
+    pub fn new(seed: Option<u64>) -> Self {
+        match seed {
+            Some(seed) => Self(Pcg64::seed_from_u64(seed)),
+            None => Self(Pcg64::from_entropy()),
+        }
+    }
 }
 
-pub struct EnvironmentConfig {
-    pub render: bool,
+// Extra values an environment wants to report, like score
+pub type Info = HashMap<&'static str, f32>;
+
+// Every entity of an environment is tagged with its instance, so several copies
+// can run side by side in one world, each offset from the last, see VecEnv
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instance {
+    pub index: usize,
+    pub origin: Vec2,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            index: 0,
+            origin: Vec2::ZERO,
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(index: usize, offset: Vec2) -> Self {
+        Self {
+            index,
+            origin: offset * index as f32,
+        }
+    }
+
+    // Only collide with our own instance, past 32 instances groups are shared
+    // and we rely on the offset to keep them apart
+    pub fn collision_groups(&self) -> InteractionGroups {
+        let group = 1 << (self.index % 32);
+        InteractionGroups::new(group, group)
+    }
+
+    // Same as collision_groups with the instance split in two layers, 0 and 1, that only
+    // collide with each other, so bodies of a kind don't touch, like flappy's birds.
+    // Groups are shared past 16 instances.
+    pub fn layer_collision_groups(&self, layer: u32) -> InteractionGroups {
+        let group = 1 << ((self.index % 16) * 2);
+        InteractionGroups::new(group << layer, group << (1 - layer))
+    }
+}
+
+// The environment functions, lets Gym and VecEnv drive any environment
+#[derive(Clone, Copy)]
+pub struct EnvironmentFns {
+    pub reset: fn(&mut World, &Instance) -> Vec<f32>,
+    // None while the last action is still being repeated, see Wrappers
+    pub step: fn(&mut World, &Instance, &Action, &mut dyn FnMut(&mut World)) -> Option<Step>,
+    pub instance_offset: fn(&World) -> Vec2,
+}
+
+// Marker resource, when present the environment is stepped by hand and the
+// systems added by add_env_systems and add_multi_agent_systems do nothing
+pub struct ManualStep;
+
+// The second player of a two player environment, the environment fills in its
+// observation each step and applies its action, whatever set it
+#[derive(Debug, Default)]
+pub struct Opponent {
+    pub observation: Vec<f32>,
+    pub action: Option<Action>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpponentType {
+    // Built into the environment
+    Scripted,
+    // Keyboard, see the environment for keys
+    Human,
+    // A frozen snapshot of the model being trained, see models::self_play
+    SelfPlay,
+}
+
+impl FromStr for OpponentType {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "scripted" => Ok(OpponentType::Scripted),
+            "human" => Ok(OpponentType::Human),
+            "selfplay" => Ok(OpponentType::SelfPlay),
+            _ => Err("no opponent match"),
+        }
+    }
+}
+
+// Result of a single step, what any agent gets back after taking an action
+#[derive(Debug, Clone, Default)]
+pub struct Step {
+    pub observation: Vec<f32>,
+    pub reward: f32,
+    pub is_done: bool,
+    pub info: Info,
+}
+
+// Gym style environment
+//
+// Environments are bevy plugins, so everything here works against the world directly.
+// An agent sets an action, physics runs, then we observe the result.
+// Each call is for a single instance, observations are relative to its origin.
+// The world is passed everywhere so an environment can size itself from its config resources.
+pub trait Env: Send + Sync + 'static {
+    // The action and observation spaces
+    fn environment(world: &World) -> Environment;
+
+    // Space between instances, needs to be large enough they never overlap
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, 20.0)
+    }
+
+    // How much of the world around an instance's origin pixel observations show
+    fn view_half_size(_: &World) -> Vec2 {
+        Vec2::new(10.0, 10.0)
+    }
+
+    // Clear the instance and spawn it in its initial state, returns first observation
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32>;
+
+    // Apply the action, physics will run after this, the action is always in the action space
+    fn act(world: &mut World, instance: &Instance, action: &Action);
+
+    // Read the current state of the instance after physics has run
+    fn observe(world: &mut World, instance: &Instance) -> Step;
+
+    // Act, advance the world, then observe, one step as an agent sees it
+    //
+    // advance runs physics, it's up to the caller as several instances can share one tick
+    fn step(world: &mut World, instance: &Instance, action: &Action, advance: &mut dyn FnMut(&mut World)) -> Step {
+        Self::act(world, instance, action);
+        advance(world);
+        Self::observe(world, instance)
+    }
+}
+
+// Adds the resources and systems used to drive an environment, these
+// drive the first instance using EnvironmentState
+//
+// Order each frame:
+// Update (start) - models pick an action from the last observation
+// Update (end)   - reset if done, otherwise apply the action
+// Rapier stages  - physics step
+// PostUpdate     - observe and update EnvironmentState
+pub fn add_env_systems<E: Env>(app: &mut AppBuilder) {
+    let mut environment = E::environment(app.world());
+    let (seed, pixels, wrappers) = match app.world().get_resource::<EnvironmentConfig>() {
+        Some(config) => (config.seed, config.pixels, config.wrappers.clone()),
+        None => (None, None, WrapperConfig::default()),
+    };
+    if let Some(pixels) = pixels {
+        environment.observation_space = pixels.space();
+        app.insert_resource(pixels);
+    }
+    let wrappers = Wrappers::new(wrappers, &environment.observation_space);
+    environment.observation_space = wrappers.space(&environment.observation_space);
+    app.insert_resource(wrappers);
+    app.insert_resource(EnvRng::new(seed))
+        .insert_resource(EnvironmentState {
+            observation: vec![0.0; environment.observation_space.flat_size()],
+            reward: 0.0,
+            is_done: None,
+            action: None,
+            info: Info::default(),
+        })
+        .insert_resource(environment)
+        .insert_resource(EnvironmentFns {
+            reset: reset_instance::<E>,
+            step: step_instance::<E>,
+            instance_offset: E::instance_offset,
+        })
+        .add_startup_system(env_reset_system::<E>.exclusive_system())
+        .add_system(env_act_system::<E>.exclusive_system().at_end())
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            env_observe_system::<E>.exclusive_system().at_start(),
+        );
+}
+
+// Stepped by hand, or the multi-agent systems drive the environment
+fn env_systems_off(world: &World) -> bool {
+    world.contains_resource::<ManualStep>() || world.contains_resource::<MultiAgentState>()
+}
+
+fn env_reset_system<E: Env>(world: &mut World) {
+    if env_systems_off(world) {
+        return;
+    }
+    let observation = reset_instance::<E>(world, &Instance::default());
+    let mut state = world.get_resource_mut::<EnvironmentState>().unwrap();
+    state.observation = observation;
+    state.reward = 0.0;
+    state.is_done = None;
+    state.info.clear();
+}
+
+fn env_act_system<E: Env>(world: &mut World) {
+    if env_systems_off(world) {
+        return;
+    }
+    let state = world.get_resource::<EnvironmentState>().unwrap();
+    if state.is_done == Some(true) {
+        env_reset_system::<E>(world);
+        return;
+    }
+    if let Some(action) = state.action.clone() {
+        let environment = world.get_resource::<Environment>().unwrap();
+        if !environment.action_space.contains(&action) {
+            panic!("{}: {:?} not in {:?}", GymError::InvalidAction, action, environment.action_space);
+        }
+        E::act(world, &Instance::default(), &action);
+    }
+}
+
+fn env_observe_system<E: Env>(world: &mut World) {
+    if env_systems_off(world) {
+        return;
+    }
+    let step = match observe_instance::<E>(world, &Instance::default()) {
+        Some(step) => step,
+        None => return,
+    };
+    let mut state = world.get_resource_mut::<EnvironmentState>().unwrap();
+    state.observation = step.observation;
+    state.reward = step.reward;
+    state.is_done = Some(step.is_done);
+    state.info = step.info;
+}
+
+// Reset, returning pixels when the environment is set to observe them, then wrapped
+pub fn reset_instance<E: Env>(world: &mut World, instance: &Instance) -> Vec<f32> {
+    let mut observation = E::reset(world, instance);
+    if let Some(config) = world.get_resource::<PixelConfig>().cloned() {
+        let view_half_size = E::view_half_size(world);
+        observation = pixels::rasterize(world, instance, view_half_size, &config);
+    }
+    let mut wrappers = world.get_resource_mut::<Wrappers>().unwrap();
+    wrappers.reset(instance.index, observation)
 }
 
+// Observe, swapping in pixels when the environment is set to observe them, then wrapped
+//
+// None while the last action is still being repeated
+pub fn observe_instance<E: Env>(world: &mut World, instance: &Instance) -> Option<Step> {
+    let step = E::observe(world, instance);
+    wrap_step::<E>(world, instance, step)
+}
+
+// Same as observe_instance for a whole step, see Env::step
+pub fn step_instance<E: Env>(
+    world: &mut World,
+    instance: &Instance,
+    action: &Action,
+    advance: &mut dyn FnMut(&mut World),
+) -> Option<Step> {
+    let step = E::step(world, instance, action, advance);
+    wrap_step::<E>(world, instance, step)
+}
+
+fn wrap_step<E: Env>(world: &mut World, instance: &Instance, mut step: Step) -> Option<Step> {
+    if !world.get_resource_mut::<Wrappers>().unwrap().repeat(instance.index, &mut step) {
+        return None;
+    }
+    if let Some(config) = world.get_resource::<PixelConfig>().cloned() {
+        let view_half_size = E::view_half_size(world);
+        step.observation = pixels::rasterize(world, instance, view_half_size, &config);
+    }
+    world.get_resource_mut::<Wrappers>().unwrap().step(instance.index, &mut step);
+    Some(step)
+}
+
+// An environment's physics and rules, its default with a toml file on top
+//
+// The file is --env-config when given, otherwise params/env/<config_name>.toml from the
+// registry when there is one, see load_environment. Only the keys in the file change,
+// unknown keys are an error so a typo can't silently leave a default in place. Call
+// before add_env_systems, the spaces may depend on it.
+pub fn load_env_config<T: Serialize + DeserializeOwned>(world: &World, default: T) -> T {
+    let path = match world.get_resource::<EnvironmentConfig>().and_then(|c| c.env_config.clone()) {
+        Some(path) => path,
+        None => return default,
+    };
+    let file = match fs::read_to_string(&path) {
+        Ok(file) => file,
+        Err(e) => panic!("Couldn't read environment config {}: {}", path.display(), e),
+    };
+    let file: toml::Value = match toml::from_str(&file) {
+        Ok(file) => file,
+        Err(e) => panic!("Couldn't parse environment config {}: {}", path.display(), e),
+    };
+
+    let mut config = toml::Value::try_from(default).unwrap();
+    merge_toml(&mut config, file);
+    match config.try_into() {
+        Ok(config) => config,
+        Err(e) => panic!("Invalid environment config {}: {}", path.display(), e),
+    }
+}
+
+// Tables are merged key by key, anything else is replaced
+fn merge_toml(value: &mut toml::Value, other: toml::Value) {
+    match (value, other) {
+        (toml::Value::Table(table), toml::Value::Table(other)) => {
+            for (key, other) in other {
+                match table.get_mut(&key) {
+                    Some(value) => merge_toml(value, other),
+                    None => {
+                        table.insert(key, other);
+                    }
+                }
+            }
+        }
+        (value, other) => *value = other,
+    }
+}
+
+// Runs commands against the world right away, lets environments reuse
+// their spawn functions from exclusive systems
+pub fn run_commands(world: &mut World, f: impl FnOnce(&mut Commands, &World)) {
+    let mut queue = CommandQueue::default();
+    {
+        let mut commands = Commands::new(&mut queue, world);
+        f(&mut commands, world);
+    }
+    queue.apply(world);
+}
+
+// Despawn every entity of the instance with the marker component T, used when resetting
+pub fn despawn_instance<T: Component>(world: &mut World, instance: &Instance) {
+    let entities: Vec<Entity> = world
+        .query_filtered::<(Entity, &Instance), With<T>>()
+        .iter(world)
+        .filter(|(_, i)| i.index == instance.index)
+        .map(|(e, _)| e)
+        .collect();
+    run_commands(world, |commands, _| {
+        for e in entities {
+            commands.entity(e).despawn_recursive();
+        }
+    });
+}
+
+// Headless config for tests, no model runs, the test drives the environment
+#[cfg(test)]
+pub fn test_config(seed: Option<u64>) -> EnvironmentConfig {
+    EnvironmentConfig {
+        render: false,
+        human: false,
+        model: ModelType::PolicyGradient,
+        seed: seed,
+        checkpoint: CheckpointConfig {
+            run_dir: PathBuf::from("runs"),
+            resume: None,
+            genome: None,
+        },
+        pixels: None,
+        wrappers: WrapperConfig::default(),
+        network: None,
+        replay_size: None,
+        opponent: OpponentType::Scripted,
+        agents: None,
+        env_config: None,
+    }
+}
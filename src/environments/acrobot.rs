@@ -1,130 +1,255 @@
 use bevy::{ecs::component::Component, prelude::*};
 use bevy_rapier2d::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
 
-use crate::environment::*;
+use crate::{environment::*, space::*};
 
 #[derive(Debug)]
 pub struct AcrobotPlugin {
     pub render: bool,
+    pub human: bool,
+}
+
+// Physics and rules, see load_env_config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcrobotConfig {
+    pub link_size_half: (f32, f32),
+    pub action_torque: f32,
+    pub goal_height: f32, // done once the tip is above this
+    pub max_steps: usize,
+    pub init_limit: f32, // both angles and angular velocities start within ±init_limit
+}
+
+impl Default for AcrobotConfig {
+    fn default() -> Self {
+        Self {
+            link_size_half: (0.2, 1.0),
+            action_torque: 1000.0,
+            goal_height: 2.0, // one link above the anchor, same as gym
+            max_steps: 500,
+            init_limit: 0.1,
+        }
+    }
 }
 
 impl Plugin for AcrobotPlugin {
     fn build(&self, app: &mut AppBuilder) {
-
-        app.add_startup_system(setup_environment.system());
+        let config = load_env_config(app.world(), AcrobotConfig::default());
+        app.insert_resource(config);
+        add_env_systems::<AcrobotPlugin>(app);
 
         if self.render {
-            app.add_system_to_stage(CoreStage::Update, update_human.system());
+            app.add_startup_system(setup_camera.system());
+        }
+        if self.human && self.render {
+            app.add_system(keyboard_input.system());
             println!("Keys: A and D");
         }
-
-        println!("WARNING: No models really support this.");
     }
 }
 
-const RAPIER_SCALE: f32 = 50.0;
-const LINK_SIZE_HALF_X: f32 = 0.2;
-const LINK_SIZE_HALF_Y: f32 = 1.0;
-const ACTION_FORCE: f32 = 1000.0;
-
-fn update_human(
-    keyboard: Res<Input<KeyCode>>,
-    mut link: Query<&mut RigidBodyForces, With<Link1>>,
-    params: Res<IntegrationParameters>,
-) {
-    for mut rb_f in link.iter_mut() {
-        if keyboard.pressed(KeyCode::A) {
-            rb_f.force = Vec2::new(-ACTION_FORCE * params.dt, 0.0).into();
+impl Env for AcrobotPlugin {
+    fn environment(_: &World) -> Environment {
+        // Same as gym, cos and sin of both joint angles then their angular velocities
+        Environment {
+            action_space: Space::Discrete(3),
+            observation_space: Space::boxed(
+                vec![-1.0, -1.0, -1.0, -1.0, f32::NEG_INFINITY, f32::NEG_INFINITY],
+                vec![1.0, 1.0, 1.0, 1.0, f32::INFINITY, f32::INFINITY],
+            ),
         }
-        if keyboard.pressed(KeyCode::D) {
-            rb_f.force = Vec2::new(ACTION_FORCE * params.dt, 0.0).into();
+    }
+
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, 10.0)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        world.get_resource_mut::<RapierConfiguration>().unwrap().scale = RAPIER_SCALE;
+        despawn_instance::<AcrobotClean>(world, instance);
+        world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+            run_commands(world, |commands, world| {
+                let config = world.get_resource::<AcrobotConfig>().unwrap();
+                spawn_environment(commands, instance, config, &mut rng.0)
+            });
+        });
+        observe_links(world, instance).observation
+    }
+
+    // Torque at the second joint, 0 is negative, 1 is none and 2 is positive
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+        let action_torque = world.get_resource::<AcrobotConfig>().unwrap().action_torque;
+        let torque = match action.discrete() {
+            0 => -action_torque * dt,
+            1 => 0.0,
+            2 => action_torque * dt,
+            _ => panic!("action invalid: {:?}", action),
+        };
+        // The joint pushes both links, in opposite directions
+        let mut link1 = world.query_filtered::<(&Instance, &mut RigidBodyForces), With<Link1>>();
+        for (_, mut rb_f) in link1.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            rb_f.torque = -torque;
+        }
+        let mut link2 = world.query_filtered::<(&Instance, &mut RigidBodyForces), With<Link2>>();
+        for (_, mut rb_f) in link2.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            rb_f.torque = torque;
         }
     }
+
+    // Counts the step, done at the goal, truncated after max_steps
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let mut link1 = world.query::<(&Instance, &mut Link1)>();
+        for (_, mut link1) in link1.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            link1.steps += 1;
+        }
+        observe_links(world, instance)
+    }
 }
 
-#[allow(dead_code)]
-fn update_pg(
-    link1: Query<(&RigidBodyPosition, &RigidBodyVelocity), With<Link1>>,
-    link2: Query<(&RigidBodyPosition, &RigidBodyVelocity), With<Link2>>,
-) {
-    // Find our observables
-    let mut _link1_pos_x = 0.0;
-    let mut _link1_vel = 0.0;
-    let mut _link2_pos_x = 0.0;
-    let mut _link2_vel = 0.0;
-
-    for (rb_pos, rb_vel) in link1.iter() {
-        _link1_pos_x = rb_pos.position.translation.x;
-        _link1_vel = rb_vel.linvel[0];
+const RAPIER_SCALE: f32 = 50.0;
+
+// The step as it stands, without counting it
+fn observe_links(world: &mut World, instance: &Instance) -> Step {
+    let config = world.get_resource::<AcrobotConfig>().unwrap().clone();
+    // Angles are 0 hanging straight down, theta2 is relative to the first link
+    let (mut theta1, mut theta1_vel, mut steps) = (0.0, 0.0, 0);
+    let mut link1 = world.query::<(&Instance, &Link1, &RigidBodyPosition, &RigidBodyVelocity)>();
+    for (_, link1, rb_pos, rb_vel) in link1.iter(world).filter(|(i, ..)| i.index == instance.index) {
+        theta1 = rb_pos.position.rotation.angle();
+        theta1_vel = rb_vel.angvel;
+        steps = link1.steps;
+    }
+    let (mut theta2, mut theta2_vel, mut tip_y) = (0.0, 0.0, 0.0);
+    let mut link2 = world.query_filtered::<(&Instance, &RigidBodyPosition, &RigidBodyVelocity), With<Link2>>();
+    for (_, rb_pos, rb_vel) in link2.iter(world).filter(|(i, ..)| i.index == instance.index) {
+        let angle = rb_pos.position.rotation.angle();
+        theta2 = angle - theta1;
+        theta2_vel = rb_vel.angvel - theta1_vel;
+        // Bottom of the second link
+        tip_y = rb_pos.position.translation.y - instance.origin.y - config.link_size_half.1 * angle.cos();
     }
 
-    for (rb_pos, rb_vel) in link2.iter() {
-        _link2_pos_x = rb_pos.position.translation.x;
-        _link2_vel = rb_vel.linvel[0];
+    let mut step = Step {
+        observation: vec![
+            theta1.cos(),
+            theta1.sin(),
+            theta2.cos(),
+            theta2.sin(),
+            theta1_vel,
+            theta2_vel,
+        ],
+        reward: -1.0,
+        is_done: tip_y > config.goal_height,
+        ..Default::default()
+    };
+    if !step.is_done && steps >= config.max_steps {
+        step.is_done = true;
+        step.info.insert("truncated", 1.0);
     }
+    step
+}
 
-    // TODO: Update state using that info
+fn keyboard_input(keyboard: Res<Input<KeyCode>>, mut env_state: ResMut<EnvironmentState>) {
+    env_state.action = if keyboard.pressed(KeyCode::A) {
+        Some(Action::Discrete(0))
+    } else if keyboard.pressed(KeyCode::D) {
+        Some(Action::Discrete(2))
+    } else {
+        Some(Action::Discrete(1))
+    };
 }
+
 // Makers
-struct Link1;
+struct Link1 {
+    steps: usize, // steps taken this episode
+}
 struct Link2;
 struct Goal;
+struct AcrobotClean;
 
-fn setup_environment(
-    mut commands: Commands,
-    mut rapier_config: ResMut<RapierConfiguration>,
-    config: Res<EnvironmentConfig>,
-) {
-    rapier_config.scale = RAPIER_SCALE;
-
-    if config.render {
-        let mut camera = OrthographicCameraBundle::new_2d();
-        camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 100.0));
-        commands.spawn_bundle(camera);
-    }
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 100.0));
+    commands.spawn_bundle(camera);
+}
 
+fn spawn_environment(commands: &mut Commands, instance: &Instance, config: &AcrobotConfig, rng: &mut Pcg64) {
     // Create static mount point
     let anchor = commands
         .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(0.0, 0.0).into(),
+            position: instance.origin.into(),
             body_type: RigidBodyType::Static,
             ..Default::default()
         })
         .insert_bundle(ColliderBundle {
-            shape: ColliderShape::ball(LINK_SIZE_HALF_X),
+            shape: ColliderShape::ball(config.link_size_half.0),
             //collider_type: ColliderType::Sensor,
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
             ..Default::default()
         })
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(Color::YELLOW))
+        .insert(AcrobotClean)
+        .insert(*instance)
         .id();
 
+    // Random start like gym, both angles and their velocities near hanging still
+    let limit = config.init_limit;
+    let (theta1, theta2) = (rng.gen_range(-limit..=limit), rng.gen_range(-limit..=limit));
+    let (theta1_vel, theta2_vel) = (rng.gen_range(-limit..=limit), rng.gen_range(-limit..=limit));
+    // Offset from the top of a link, rotated by its angle, to its center and its bottom
+    let half = |angle: f32| Vec2::new(angle.sin(), -angle.cos()) * config.link_size_half.1;
+    // Velocity of a point spinning around another at angvel
+    let spin = |angvel: f32, offset: Vec2| Vec2::new(-offset.y, offset.x) * angvel;
+    let elbow = half(theta1) * 2.0;
+
     // Create links(arms)
     let link1 = create_link(
-        &mut commands,
-        Vec2::new(0.0, -LINK_SIZE_HALF_Y),
+        commands,
+        instance,
+        config,
+        Isometry::new((instance.origin + half(theta1)).into(), theta1),
+        RigidBodyVelocity {
+            linvel: spin(theta1_vel, half(theta1)).into(),
+            angvel: theta1_vel,
+        },
         Color::GRAY,
-        Link1,
+        Link1 { steps: 0 },
     );
 
-    if config.render {
-        // Add Joint Visualization - cosmetic only
-        commands
-            .spawn_bundle(ColliderBundle {
-                shape: ColliderShape::ball(LINK_SIZE_HALF_X),
-                collider_type: ColliderType::Sensor,
-                ..Default::default()
-            })
-            .insert(ColliderParent {
-                handle: link1.handle(),
-                pos_wrt_parent: Vec2::new(0.0, -LINK_SIZE_HALF_Y).into(),
-            })
-            .insert(ColliderPositionSync::Discrete)
-            .insert(ColliderDebugRender::from(Color::BLACK));
-    }
+    // Add Joint Visualization - cosmetic only, always there so pixels look the same rendered or not
+    commands
+        .spawn_bundle(ColliderBundle {
+            shape: ColliderShape::ball(config.link_size_half.0),
+            collider_type: ColliderType::Sensor,
+            ..Default::default()
+        })
+        .insert(ColliderParent {
+            handle: link1.handle(),
+            pos_wrt_parent: Vec2::new(0.0, -config.link_size_half.1).into(),
+        })
+        .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(Color::BLACK))
+        .insert(AcrobotClean)
+        .insert(*instance);
+
+    let angle2 = theta1 + theta2;
     let l2 = create_link(
-        &mut commands,
-        Vec2::new(0.0, -LINK_SIZE_HALF_Y * 3.0),
+        commands,
+        instance,
+        config,
+        Isometry::new((instance.origin + elbow + half(angle2)).into(), angle2),
+        RigidBodyVelocity {
+            linvel: (spin(theta1_vel, elbow) + spin(theta1_vel + theta2_vel, half(angle2))).into(),
+            angvel: theta1_vel + theta2_vel,
+        },
         Color::GRAY,
         Link2,
     );
@@ -132,25 +257,29 @@ fn setup_environment(
     // Add 1st Ball joint
     let joint = BallJoint::new(
         Vec2::ZERO.into(),                       // static anchor
-        Vec2::new(0.0, LINK_SIZE_HALF_Y).into(), // top of first link
+        Vec2::new(0.0, config.link_size_half.1).into(), // top of first link
     );
     commands
         .spawn()
-        .insert(JointBuilderComponent::new(joint, anchor, link1));
+        .insert(JointBuilderComponent::new(joint, anchor, link1))
+        .insert(AcrobotClean)
+        .insert(*instance);
 
     // Add 2nd Ball joint
     let joint2 = BallJoint::new(
-        Vec2::new(0.0, -LINK_SIZE_HALF_Y).into(), // bottom first link
-        Vec2::new(0.0, LINK_SIZE_HALF_Y).into(),  // top of second link
+        Vec2::new(0.0, -config.link_size_half.1).into(), // bottom first link
+        Vec2::new(0.0, config.link_size_half.1).into(),  // top of second link
     );
     commands
         .spawn()
-        .insert(JointBuilderComponent::new(joint2, link1, l2));
+        .insert(JointBuilderComponent::new(joint2, link1, l2))
+        .insert(AcrobotClean)
+        .insert(*instance);
 
     // Create the goal line
     commands
         .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(0.0, 2.5).into(),
+            position: (instance.origin + Vec2::new(0.0, config.goal_height)).into(),
             body_type: RigidBodyType::Static,
             ..Default::default()
         })
@@ -161,22 +290,28 @@ fn setup_environment(
         })
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(Color::BLACK))
-        .insert(Goal);
+        .insert(Goal)
+        .insert(AcrobotClean)
+        .insert(*instance);
 }
 
 fn create_link(
     commands: &mut Commands,
-    pos: Vec2,
+    instance: &Instance,
+    config: &AcrobotConfig,
+    position: Isometry<f32>,
+    velocity: RigidBodyVelocity,
     color: Color,
     component: impl Component,
 ) -> Entity {
     commands
         .spawn_bundle(RigidBodyBundle {
-            position: pos.into(),
+            position: position.into(),
+            velocity: velocity,
             ..Default::default()
         })
         .insert_bundle(ColliderBundle {
-            shape: ColliderShape::cuboid(LINK_SIZE_HALF_X, LINK_SIZE_HALF_Y),
+            shape: ColliderShape::cuboid(config.link_size_half.0, config.link_size_half.1),
             material: ColliderMaterial {
                 restitution: 0.7,
                 ..Default::default()
@@ -187,5 +322,7 @@ fn create_link(
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(color))
         .insert(component)
+        .insert(AcrobotClean)
+        .insert(*instance)
         .id()
 }
@@ -0,0 +1,491 @@
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+use crate::{environment::*, space::*};
+
+// Based on gym's BipedalWalker, walk to the end of the terrain without falling
+//
+// Sizes are gym's, in meters, with the same 30 pixels per meter. Hips and knees are
+// ball joints driven by velocity motors, the legs only collide with the ground.
+pub struct BipedalWalkerPlugin {
+    pub render: bool,
+    pub human: bool,
+}
+
+// Physics and rules, see load_env_config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BipedalWalkerConfig {
+    pub gravity: f32,
+    pub max_steps: usize,
+    pub initial_vel_limit: f32,
+    pub motors_torque: f32,
+    pub speed_hip: f32,
+    pub speed_knee: f32,
+    pub torque_cost: f32,
+}
+
+impl Default for BipedalWalkerConfig {
+    fn default() -> Self {
+        Self {
+            gravity: -10.0,
+            max_steps: 1600,
+            initial_vel_limit: 1.0,
+            motors_torque: 80.0,
+            speed_hip: 4.0,
+            speed_knee: 6.0,
+            torque_cost: 0.00035,
+        }
+    }
+}
+
+// Makers
+struct Hull {
+    steps: usize,
+    crashed: bool,    // touched the ground
+    torque_cost: f32, // last action
+    prev_shaping: Option<f32>,
+    lidar: [f32; LIDAR_RAYS], // distance to the ground along each ray, 0..1 of LIDAR_RANGE
+}
+struct UpperLeg {
+    side: usize,
+}
+struct LowerLeg {
+    side: usize,
+    contact: bool,
+}
+// Joint motor, by its index in the action
+struct Motor(usize);
+struct Ground;
+struct BipedalWalkerClean;
+
+impl Plugin for BipedalWalkerPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let config = load_env_config(app.world(), BipedalWalkerConfig::default());
+        app.insert_resource(config);
+        add_env_systems::<BipedalWalkerPlugin>(app);
+        app.add_system(walker_contacts.system())
+            .add_system(lidar_system.system());
+
+        if self.render {
+            app.add_startup_system(setup_camera.system())
+                .add_system(follow_camera.system());
+        }
+        if self.human && self.render {
+            app.add_system(keyboard_input.system());
+            println!("Keys: Q and A left hip, W and S left knee, E and D right hip, R and F right knee");
+        }
+    }
+}
+
+impl Env for BipedalWalkerPlugin {
+    fn environment(_: &World) -> Environment {
+        // hip and knee motors for each leg, speed in the sign and torque in the size
+        // hull angle, angular velocity, x and y velocity, then for each leg hip angle and
+        // speed, knee angle and speed and ground contact, then the lidar
+        Environment {
+            action_space: Space::boxed(vec![-1.0; 4], vec![1.0; 4]),
+            observation_space: Space::unbounded(14 + LIDAR_RAYS),
+        }
+    }
+
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, 20.0)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        let gravity = world.get_resource::<BipedalWalkerConfig>().unwrap().gravity;
+        let mut rapier_config = world.get_resource_mut::<RapierConfiguration>().unwrap();
+        rapier_config.scale = RAPIER_SCALE;
+        rapier_config.gravity = Vec2::new(0.0, gravity).into();
+
+        despawn_instance::<BipedalWalkerClean>(world, instance);
+        world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+            run_commands(world, |commands, world| {
+                let config = world.get_resource::<BipedalWalkerConfig>().unwrap();
+                spawn_terrain(commands, instance, &mut rng.0);
+                spawn_walker(commands, instance, config, &mut rng.0);
+            });
+        });
+        Self::observe(world, instance).observation
+    }
+
+    // Like gym, the sign picks the motor direction and the size how much torque it gets
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let values: Vec<f32> = match action {
+            Action::Box(values) => values.iter().map(|v| v.clamp(-1.0, 1.0)).collect(),
+            _ => panic!("action invalid: {:?}", action),
+        };
+        let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+        let config = world.get_resource::<BipedalWalkerConfig>().unwrap().clone();
+
+        let mut motors = world.query::<(&Instance, &Motor, &JointHandleComponent)>();
+        let handles: Vec<(usize, JointHandle)> = motors
+            .iter(world)
+            .filter(|(i, ..)| i.index == instance.index)
+            .map(|(_, motor, joint)| (motor.0, joint.handle()))
+            .collect();
+        let mut joints = world.get_resource_mut::<JointSet>().unwrap();
+        for (index, handle) in handles {
+            if let Some(joint) = joints.get_mut(handle) {
+                if let JointParams::BallJoint(ball) = &mut joint.params {
+                    let speed = if index % 2 == 0 { config.speed_hip } else { config.speed_knee };
+                    ball.configure_motor_velocity(speed * values[index].signum(), 1.0);
+                    ball.motor_max_impulse = config.motors_torque * values[index].abs() * dt;
+                }
+            }
+        }
+
+        let mut hulls = world.query::<(&Instance, &mut Hull)>();
+        for (_, mut hull) in hulls.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            hull.torque_cost = values.iter().map(|v| config.torque_cost * config.motors_torque * v.abs()).sum();
+            hull.steps += 1;
+        }
+    }
+
+    // Same reward as gym, forward progress less torque and keeping the hull level,
+    // -100 for falling over or walking backwards off the start
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let config = world.get_resource::<BipedalWalkerConfig>().unwrap().clone();
+        let mut uppers = [(0.0, 0.0); 2];
+        let mut query = world.query::<(&Instance, &UpperLeg, &RigidBodyPosition, &RigidBodyVelocity)>();
+        for (_, leg, rb_pos, rb_vel) in query.iter(world).filter(|(i, ..)| i.index == instance.index) {
+            uppers[leg.side] = (rb_pos.position.rotation.angle(), rb_vel.angvel);
+        }
+        let mut lowers = [(0.0, 0.0, 0.0); 2];
+        let mut query = world.query::<(&Instance, &LowerLeg, &RigidBodyPosition, &RigidBodyVelocity)>();
+        for (_, leg, rb_pos, rb_vel) in query.iter(world).filter(|(i, ..)| i.index == instance.index) {
+            let contact = if leg.contact { 1.0 } else { 0.0 };
+            lowers[leg.side] = (rb_pos.position.rotation.angle(), rb_vel.angvel, contact);
+        }
+
+        let mut step = Step::default();
+        let mut hulls = world.query::<(&Instance, &mut Hull, &RigidBodyPosition, &RigidBodyVelocity)>();
+        for (_, mut hull, rb_pos, rb_vel) in hulls.iter_mut(world).filter(|(i, ..)| i.index == instance.index) {
+            let angle = rb_pos.position.rotation.angle();
+            // Scaled like gym's
+            let mut observation = vec![
+                angle,
+                2.0 * rb_vel.angvel / FPS,
+                0.3 * rb_vel.linvel.x * VIEWPORT_W / FPS,
+                0.3 * rb_vel.linvel.y * VIEWPORT_H / FPS,
+            ];
+            for side in 0..2 {
+                let (upper_angle, upper_vel) = uppers[side];
+                let (lower_angle, lower_vel, contact) = lowers[side];
+                observation.extend_from_slice(&[
+                    upper_angle - angle,
+                    (upper_vel - rb_vel.angvel) / config.speed_hip,
+                    lower_angle - upper_angle + 1.0,
+                    (lower_vel - upper_vel) / config.speed_knee,
+                    contact,
+                ]);
+            }
+            observation.extend_from_slice(&hull.lidar);
+            step.observation = observation;
+
+            let x = rb_pos.position.translation.x - instance.origin.x;
+            let shaping = 130.0 * x / RAPIER_SCALE - 5.0 * angle.abs();
+            step.reward = hull.prev_shaping.map_or(0.0, |prev| shaping - prev) - hull.torque_cost;
+            hull.prev_shaping = Some(shaping);
+
+            if hull.crashed || x < 0.0 {
+                step.reward = -100.0;
+                step.is_done = true;
+            } else if x > TERRAIN_END {
+                step.is_done = true;
+            } else if hull.steps >= config.max_steps {
+                step.is_done = true;
+                step.info.insert("truncated", 1.0);
+            }
+        }
+        step
+    }
+}
+
+const RAPIER_SCALE: f32 = 30.0;
+const FPS: f32 = 50.0; // gym's, only used to scale observations the same
+const VIEWPORT_W: f32 = 600.0 / RAPIER_SCALE;
+const VIEWPORT_H: f32 = 400.0 / RAPIER_SCALE;
+
+const TERRAIN_STEP: f32 = 14.0 / RAPIER_SCALE;
+const TERRAIN_LENGTH: usize = 200;
+const TERRAIN_HEIGHT: f32 = VIEWPORT_H / 4.0;
+const TERRAIN_STARTPAD: usize = 20; // flat steps at the start
+const TERRAIN_GRASS: usize = 10; // done this many steps from the end
+const TERRAIN_END: f32 = (TERRAIN_LENGTH - TERRAIN_GRASS) as f32 * TERRAIN_STEP;
+
+const LEG_DOWN: f32 = -8.0 / RAPIER_SCALE;
+const LEG_SIZE_HALF_X: f32 = 4.0 / RAPIER_SCALE;
+const LEG_SIZE_HALF_Y: f32 = 17.0 / RAPIER_SCALE;
+const HIP_LIMITS: [f32; 2] = [-0.8, 1.1]; // same joint ranges as gym
+const KNEE_LIMITS: [f32; 2] = [-1.6, -0.1];
+
+const LIDAR_RAYS: usize = 10;
+const LIDAR_RANGE: f32 = 160.0 / RAPIER_SCALE;
+
+// Walker parts only push against the ground, contacts with each other are ignored
+const GROUND_SOLVER_GROUP: u32 = 0b01;
+const WALKER_SOLVER_GROUP: u32 = 0b10;
+
+// Mark leg contacts and crashes, only touching the ground counts
+// This causes an additional 1-frame-lag, same as breakout
+fn walker_contacts(
+    mut contact_events: EventReader<ContactEvent>,
+    ground: Query<&Ground>,
+    mut legs: Query<&mut LowerLeg>,
+    mut hulls: Query<&mut Hull>,
+) {
+    for contact_event in contact_events.iter() {
+        let (h1, h2, started) = match contact_event {
+            ContactEvent::Started(h1, h2) => (h1, h2, true),
+            ContactEvent::Stopped(h1, h2) => (h1, h2, false),
+        };
+        let other = if ground.get(h1.entity()).is_ok() {
+            h2.entity()
+        } else if ground.get(h2.entity()).is_ok() {
+            h1.entity()
+        } else {
+            continue;
+        };
+        if let Ok(mut leg) = legs.get_mut(other) {
+            leg.contact = started;
+        }
+        if let Ok(mut hull) = hulls.get_mut(other) {
+            hull.crashed |= started;
+        }
+    }
+}
+
+// Rays fan out from straight down to forward of the hull, only the ground is hit
+// Read by observe, so it lags a frame like the contacts
+fn lidar_system(
+    query_pipeline: Res<QueryPipeline>,
+    collider_query: QueryPipelineColliderComponentsQuery,
+    ground: Query<&Ground>,
+    mut hulls: Query<(&Instance, &RigidBodyPosition, &mut Hull)>,
+) {
+    let collider_set = QueryPipelineColliderComponentsSet(&collider_query);
+    let is_ground = |handle: ColliderHandle| ground.get(handle.entity()).is_ok();
+    for (instance, rb_pos, mut hull) in hulls.iter_mut() {
+        let origin = Point::from(rb_pos.position.translation.vector);
+        for i in 0..LIDAR_RAYS {
+            let angle = 1.5 * i as f32 / LIDAR_RAYS as f32;
+            let ray = Ray::new(origin, Vec2::new(angle.sin(), -angle.cos()).into());
+            hull.lidar[i] = match query_pipeline.cast_ray(
+                &collider_set,
+                &ray,
+                LIDAR_RANGE,
+                true,
+                instance.collision_groups(),
+                Some(&is_ground),
+            ) {
+                Some((_, toi)) => toi / LIDAR_RANGE,
+                None => 1.0,
+            };
+        }
+    }
+}
+
+fn keyboard_input(keyboard_input: Res<Input<KeyCode>>, mut env_state: ResMut<EnvironmentState>) {
+    let keys = [
+        (KeyCode::Q, KeyCode::A),
+        (KeyCode::W, KeyCode::S),
+        (KeyCode::E, KeyCode::D),
+        (KeyCode::R, KeyCode::F),
+    ];
+    let values = keys
+        .iter()
+        .map(|(up, down)| {
+            if keyboard_input.pressed(*up) {
+                1.0
+            } else if keyboard_input.pressed(*down) {
+                -1.0
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    env_state.action = Some(Action::Box(values));
+}
+
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 100.0));
+    commands.spawn_bundle(camera);
+}
+
+// The terrain is long, keep the first walker in view
+fn follow_camera(
+    hulls: Query<(&Instance, &Transform), With<Hull>>,
+    mut cameras: Query<&mut Transform, (With<Camera>, Without<Hull>)>,
+) {
+    for (_, hull) in hulls.iter().filter(|(i, _)| i.index == 0) {
+        for mut camera in cameras.iter_mut() {
+            camera.translation.x = hull.translation.x;
+        }
+    }
+}
+
+// Flat start then a random walk, like gym's grass terrain
+fn spawn_terrain(commands: &mut Commands, instance: &Instance, rng: &mut Pcg64) {
+    let mut y = TERRAIN_HEIGHT;
+    let mut velocity = 0.0;
+    let mut vertices: Vec<Point<Real>> = Vec::with_capacity(TERRAIN_LENGTH);
+    for i in 0..TERRAIN_LENGTH {
+        velocity = 0.8 * velocity + 0.01 * (TERRAIN_HEIGHT - y).signum();
+        if i > TERRAIN_STARTPAD {
+            velocity += rng.gen_range(-1.0..1.0) / RAPIER_SCALE;
+        }
+        y += velocity;
+        vertices.push(Vec2::new(i as f32 * TERRAIN_STEP, y).into());
+    }
+
+    commands
+        .spawn_bundle(RigidBodyBundle {
+            position: instance.origin.into(),
+            body_type: RigidBodyType::Static,
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            shape: ColliderShape::polyline(vertices, None),
+            material: ColliderMaterial {
+                friction: 2.5,
+                ..Default::default()
+            },
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                solver_groups: InteractionGroups::new(GROUND_SOLVER_GROUP, WALKER_SOLVER_GROUP),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(Color::DARK_GREEN))
+        .insert(Ground)
+        .insert(BipedalWalkerClean)
+        .insert(*instance);
+}
+
+fn walker_part(
+    commands: &mut Commands,
+    instance: &Instance,
+    position: Vec2,
+    shape: ColliderShape,
+    density: f32,
+    color: Color,
+) -> Entity {
+    commands
+        .spawn_bundle(RigidBodyBundle {
+            position: (instance.origin + position).into(),
+            activation: RigidBodyActivation::cannot_sleep(),
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            shape: shape,
+            material: ColliderMaterial {
+                density: density,
+                friction: 0.2,
+                ..Default::default()
+            },
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                solver_groups: InteractionGroups::new(WALKER_SOLVER_GROUP, GROUND_SOLVER_GROUP),
+                active_events: ActiveEvents::CONTACT_EVENTS,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(color))
+        .insert(BipedalWalkerClean)
+        .insert(*instance)
+        .id()
+}
+
+// Hull over the start pad with a random push, each leg is a hip and a knee
+fn spawn_walker(commands: &mut Commands, instance: &Instance, config: &BipedalWalkerConfig, rng: &mut Pcg64) {
+    let start = Vec2::new(
+        TERRAIN_STEP * TERRAIN_STARTPAD as f32 / 2.0,
+        TERRAIN_HEIGHT + 4.0 * LEG_SIZE_HALF_Y,
+    );
+    // gym's hull polygon, in pixels
+    let hull_shape: Vec<Point<Real>> = [(-30.0, 9.0), (6.0, 9.0), (34.0, 1.0), (34.0, -8.0), (-30.0, -8.0)]
+        .iter()
+        .map(|(x, y)| (Vec2::new(*x, *y) / RAPIER_SCALE).into())
+        .collect();
+    let hull = walker_part(
+        commands,
+        instance,
+        start,
+        ColliderShape::convex_hull(&hull_shape).unwrap(),
+        5.0,
+        Color::PURPLE,
+    );
+    commands
+        .entity(hull)
+        .insert(Hull {
+            steps: 0,
+            crashed: false,
+            torque_cost: 0.0,
+            prev_shaping: None,
+            lidar: [1.0; LIDAR_RAYS],
+        })
+        .insert(RigidBodyVelocity {
+            linvel: Vec2::new(rng.gen_range(-config.initial_vel_limit..config.initial_vel_limit), 0.0).into(),
+            angvel: 0.0,
+        });
+
+    for side in 0..2 {
+        // Same as gym, the top of the leg is -LEG_DOWN above the start
+        let upper_position = start + Vec2::new(0.0, -LEG_DOWN - LEG_SIZE_HALF_Y);
+        let upper = walker_part(
+            commands,
+            instance,
+            upper_position,
+            ColliderShape::cuboid(LEG_SIZE_HALF_X, LEG_SIZE_HALF_Y),
+            1.0,
+            Color::GRAY,
+        );
+        commands.entity(upper).insert(UpperLeg { side: side });
+
+        let lower = walker_part(
+            commands,
+            instance,
+            upper_position - Vec2::new(0.0, LEG_SIZE_HALF_Y * 2.0),
+            ColliderShape::cuboid(LEG_SIZE_HALF_X * 0.8, LEG_SIZE_HALF_Y),
+            1.0,
+            Color::GRAY,
+        );
+        commands.entity(lower).insert(LowerLeg {
+            side: side,
+            contact: false,
+        });
+
+        let mut hip = BallJoint::new(Vec2::new(0.0, LEG_DOWN).into(), Vec2::new(0.0, LEG_SIZE_HALF_Y).into());
+        hip.limits_enabled = true;
+        hip.limits = HIP_LIMITS;
+        commands
+            .spawn()
+            .insert(JointBuilderComponent::new(hip, hull, upper))
+            .insert(Motor(side * 2))
+            .insert(BipedalWalkerClean)
+            .insert(*instance);
+
+        let mut knee = BallJoint::new(
+            Vec2::new(0.0, -LEG_SIZE_HALF_Y).into(),
+            Vec2::new(0.0, LEG_SIZE_HALF_Y).into(),
+        );
+        knee.limits_enabled = true;
+        knee.limits = KNEE_LIMITS;
+        commands
+            .spawn()
+            .insert(JointBuilderComponent::new(knee, upper, lower))
+            .insert(Motor(side * 2 + 1))
+            .insert(BipedalWalkerClean)
+            .insert(*instance);
+    }
+}
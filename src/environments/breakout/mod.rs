@@ -1,31 +1,44 @@
-mod human;
+pub(crate) mod human;
 mod neat;
 
 use self::human::*;
 use self::neat::*;
+use crate::environment::*;
 use crate::helpers::{range_lerp, V2};
+use crate::models::neat::NeatML;
+use crate::space::*;
 use bevy::{ecs::component::Component, prelude::*};
 use bevy_inspector_egui::*;
 use bevy_rapier2d::prelude::*;
 use rand::Rng;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
 
-#[derive(Inspectable, Debug)]
+// Physics and rules, see load_env_config
+// Colors and the environment settings aren't in the file, they come from the default
+#[derive(Inspectable, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct BreakoutConfig {
     // environment settings
     #[inspectable(ignore)]
+    #[serde(skip)]
     pub render: bool,
     #[inspectable(ignore)]
+    #[serde(skip)]
     pub human: bool,
     pub rapier_scale: f32,
 
     // breakout settings
     pub player_size_half: Vec2,
     pub player_speed: f32,
+    #[serde(skip)]
     pub player_color: Color,
     pub board_size_half: Vec2,
     pub board_line_size_half: f32,
+    #[serde(skip)]
     pub board_color: Color,
     pub brick_grid: V2<usize>,
+    #[serde(skip)]
     pub brick_color: Color,
     pub ball_size_half: f32,
     pub ball_init_x_range: (f32, f32),
@@ -59,18 +72,16 @@ impl Default for BreakoutConfig {
     }
 }
 
-#[allow(dead_code)]
-pub struct BreakoutInstance {
-    pub index: usize,
-    pub origin: Vec2,
-}
-
 struct Brick;
-pub struct Player {
-    index: usize,
+struct Player;
+// Bricks broken, total for the episode and since the last observation, lives on the board
+struct Score {
+    total: usize,
+    step: usize,
 }
-struct Score(usize);
-struct Ball;
+struct BreakoutDone(bool);
+struct Board;
+pub(crate) struct Ball;
 struct Hit;
 struct BoardBottom;
 struct BoardOther;
@@ -79,83 +90,157 @@ struct BreakoutCleanup;
 pub struct BreakoutPlugin {
     pub render: bool,
     pub human: bool,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum BreakoutState {
-    Loading,
-    Playing,
-    Resetting,
+    pub neat: bool,
 }
 
 impl Plugin for BreakoutPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        let mut config = BreakoutConfig::default();
+        let mut config = load_env_config(app.world(), BreakoutConfig::default());
         config.human = self.human;
         config.render = self.render;
 
         app.insert_resource(config)
-            .insert_resource(Score(0))
-            .add_state(BreakoutState::Loading)
-            .add_plugin(InspectorPlugin::<BreakoutConfig>::new_insert_manually().shared())
-            .add_system_set(
-                SystemSet::on_enter(BreakoutState::Loading)
-                    .with_system(setup_environment.system())
-                    .with_system(spawn_board.system())
-                    .with_system(spawn_player.system())
-                    .with_system(spawn_ball.system()),
-            )
-            .add_system_set(
-                SystemSet::on_update(BreakoutState::Playing)
-                    .with_system(update_ball.system())
-                    .with_system(ball_collision.system())
-                    .with_system(ball_bounds_check.system()),
-            )
-            .add_system_set(
-                SystemSet::on_enter(BreakoutState::Resetting)
-                    .with_system(clean_environment.system()),
-            );
+            .add_system(update_ball.system())
+            .add_system(ball_collision.system())
+            .add_system(ball_bounds_check.system());
+        add_env_systems::<BreakoutPlugin>(app);
+
+        if self.render {
+            app.add_plugin(InspectorPlugin::<BreakoutConfig>::new_insert_manually().shared())
+                .add_startup_system(setup_camera.system());
+        }
 
         if self.human {
-            app.add_system_set(
-                SystemSet::on_update(BreakoutState::Playing)
-                    .with_system(player_movement_human.system())
-                    .with_system(other_keyboard_input.system()),
-            );
+            app.add_system(keyboard_input.system())
+                .add_system(other_keyboard_input.system());
             println!("Press A or D, or Left or Right Arrow\nR to reset\nEscape to exit");
         } else {
-            app.add_system_set(
-                SystemSet::on_update(BreakoutState::Playing)
-                    .with_system(player_movement_neat.system()),
-            );
+            if self.neat {
+                // Neat runs a population of instances, so it steps them itself
+                let config = app.world().get_resource::<EnvironmentConfig>().unwrap();
+                let model = NeatML::new("./params/breakout.toml", true)
+                    .with_checkpoints("breakout", &config.checkpoint);
+                app.insert_resource(ManualStep)
+                    .insert_resource(model)
+                    .insert_resource(NeatAgents::default())
+                    .add_system(neat_system.exclusive_system().at_end());
+            }
             println!("Press Escape to exit");
         }
     }
 }
 
-fn setup_environment(
-    mut commands: Commands,
-    mut rapier_config: ResMut<RapierConfiguration>,
-    config: Res<BreakoutConfig>,
-    mut state: ResMut<State<BreakoutState>>,
-    mut score: ResMut<Score>,
-) {
-    rapier_config.scale = config.rapier_scale;
-    rapier_config.gravity = Vec2::ZERO.into();
-    score.0 = 0;
-
-    if config.render {
-        let mut camera = OrthographicCameraBundle::new_2d();
-        camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 50.0));
-        commands.spawn_bundle(camera).insert(BreakoutCleanup);
+impl Env for BreakoutPlugin {
+    fn environment(world: &World) -> Environment {
+        let board = world.get_resource::<BreakoutConfig>().unwrap().board_size_half;
+        Environment {
+            action_space: Space::Discrete(3),
+            observation_space: Space::boxed(
+                vec![-board.x, -board.y, -board.x, -board.y],
+                vec![board.x, board.y, board.x, board.y],
+            ),
+        }
+    }
+
+    fn instance_offset(world: &World) -> Vec2 {
+        let board = world.get_resource::<BreakoutConfig>().unwrap().board_size_half;
+        Vec2::new(0.0, board.y * 2.5)
+    }
+
+    fn view_half_size(world: &World) -> Vec2 {
+        let config = world.get_resource::<BreakoutConfig>().unwrap();
+        config.board_size_half + Vec2::splat(config.board_line_size_half)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        despawn_instance::<BreakoutCleanup>(world, instance);
+
+        world.resource_scope(|world, config: Mut<BreakoutConfig>| {
+            world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+                let mut rapier_config = world.get_resource_mut::<RapierConfiguration>().unwrap();
+                rapier_config.scale = config.rapier_scale;
+                rapier_config.gravity = Vec2::ZERO.into();
+
+                run_commands(world, |commands, _| {
+                    spawn_board(commands, instance, &config);
+                    spawn_player(commands, instance, &config);
+                    spawn_ball(commands, instance, &config, &mut rng.0);
+                });
+            });
+        });
+
+        Self::observe(world, instance).observation
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        world.resource_scope(|world, config: Mut<BreakoutConfig>| {
+            let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+            let mut players = world.query_filtered::<(&Instance, &mut RigidBodyPosition), With<Player>>();
+            for (_, mut rb_pos) in players.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+                move_player(&mut rb_pos, instance, action, &config, dt);
+            }
+        });
+    }
+
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let mut observation = vec![0.0; 4];
+        let mut players = world.query_filtered::<(&Instance, &RigidBodyPosition), With<Player>>();
+        for (_, rb_pos) in players.iter(world).filter(|(i, _)| i.index == instance.index) {
+            observation[0] = rb_pos.position.translation.x - instance.origin.x; // player x
+            observation[1] = rb_pos.position.translation.y - instance.origin.y; // player y
+        }
+        let mut balls = world.query_filtered::<(&Instance, &RigidBodyPosition), With<Ball>>();
+        for (_, rb_pos) in balls.iter(world).filter(|(i, _)| i.index == instance.index) {
+            observation[2] = rb_pos.position.translation.x - instance.origin.x; // ball x
+            observation[3] = rb_pos.position.translation.y - instance.origin.y; // ball y
+        }
+
+        let mut step = Step {
+            observation: observation,
+            ..Default::default()
+        };
+        let mut boards = world.query_filtered::<(&Instance, &mut Score, &BreakoutDone), With<Board>>();
+        for (_, mut score, done) in boards.iter_mut(world).filter(|(i, ..)| i.index == instance.index) {
+            step.reward = score.step as f32;
+            step.is_done = done.0;
+            step.info.insert("score", score.total as f32);
+            score.step = 0;
+        }
+        step
     }
-    state.set(BreakoutState::Playing).unwrap();
 }
 
-fn spawn_board(mut commands: Commands, config: Res<BreakoutConfig>) {
+// Move the paddle, 0 is left, 1 is stay and 2 is right
+pub(crate) fn move_player(
+    rb_pos: &mut RigidBodyPosition,
+    instance: &Instance,
+    action: &Action,
+    config: &BreakoutConfig,
+    dt: f32,
+) {
+    let movement = config.player_speed * dt;
+    let limit = config.board_size_half.x - config.player_size_half.x - config.board_line_size_half;
+    let x = match action.discrete() {
+        0 => rb_pos.position.translation.x - movement,
+        1 => return,
+        2 => rb_pos.position.translation.x + movement,
+        _ => panic!("action invalid: {:?}", action),
+    };
+    rb_pos.next_position.translation.x =
+        x.clamp(instance.origin.x - limit, instance.origin.x + limit);
+}
+
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 50.0));
+    commands.spawn_bundle(camera);
+}
+
+fn spawn_board(commands: &mut Commands, instance: &Instance, config: &BreakoutConfig) {
     // draw board
     commands
         .spawn_bundle(RigidBodyBundle {
+            position: instance.origin.into(),
             body_type: RigidBodyType::Static,
             ..Default::default()
         })
@@ -163,6 +248,7 @@ fn spawn_board(mut commands: Commands, config: Res<BreakoutConfig>) {
             // Top
             create_board_side(
                 parent,
+                instance,
                 Vec2::new(0.0, config.board_size_half.y),
                 Vec2::new(
                     config.board_size_half.x + config.board_line_size_half,
@@ -174,6 +260,7 @@ fn spawn_board(mut commands: Commands, config: Res<BreakoutConfig>) {
             // Bottom
             create_board_side(
                 parent,
+                instance,
                 Vec2::new(0.0, -config.board_size_half.y),
                 Vec2::new(
                     config.board_size_half.x + config.board_line_size_half,
@@ -185,6 +272,7 @@ fn spawn_board(mut commands: Commands, config: Res<BreakoutConfig>) {
             // Left
             create_board_side(
                 parent,
+                instance,
                 Vec2::new(-config.board_size_half.x, 0.0),
                 Vec2::new(config.board_line_size_half, config.board_size_half.y),
                 BoardOther,
@@ -193,6 +281,7 @@ fn spawn_board(mut commands: Commands, config: Res<BreakoutConfig>) {
             // Right
             create_board_side(
                 parent,
+                instance,
                 Vec2::new(config.board_size_half.x, 0.0),
                 Vec2::new(config.board_line_size_half, config.board_size_half.y),
                 BoardOther,
@@ -221,6 +310,7 @@ fn spawn_board(mut commands: Commands, config: Res<BreakoutConfig>) {
 
                     create_brick(
                         parent,
+                        instance,
                         Vec2::new(pos_x, pos_y),
                         Vec2::new(size_x, size_y),
                         config.brick_color,
@@ -229,90 +319,86 @@ fn spawn_board(mut commands: Commands, config: Res<BreakoutConfig>) {
             }
         })
         .insert(ColliderDebugRender::default())
-        .insert(ColliderPositionSync::Discrete);
+        .insert(ColliderPositionSync::Discrete)
+        .insert(Board)
+        .insert(Score { total: 0, step: 0 })
+        .insert(BreakoutDone(false))
+        .insert(BreakoutCleanup)
+        .insert(*instance);
 }
 
 fn create_board_side(
     parent: &mut ChildBuilder,
+    instance: &Instance,
     pos: Vec2,
     size_half: Vec2,
     component: impl Component,
     color: Color,
 ) {
     parent
-        .spawn_bundle(ColliderBundle {
-            position: pos.into(),
-            collider_type: ColliderType::Solid,
-            shape: ColliderShape::cuboid(size_half.x, size_half.y),
-            material: ColliderMaterial {
-                friction: 0.0,
-                restitution: 1.0,
-                ..Default::default()
-            },
-            ..Default::default()
-        })
+        .spawn_bundle(wall_bundle(instance, pos, size_half))
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(color))
         .insert(BreakoutCleanup)
+        .insert(*instance)
         .insert(component);
 }
 
-fn create_brick(parent: &mut ChildBuilder, pos: Vec2, size_half: Vec2, color: Color) {
-    parent
-        .spawn_bundle(ColliderBundle {
-            position: pos.into(),
-            collider_type: ColliderType::Solid,
-            material: ColliderMaterial {
-                friction: 0.0,
-                restitution: 1.0,
-                ..Default::default()
-            },
-            shape: ColliderShape::cuboid(size_half.x, size_half.y),
-            flags: (ActiveEvents::CONTACT_EVENTS | ActiveEvents::INTERSECTION_EVENTS).into(),
+// Paddle, ball and wall physics below are shared with pong
+
+// Walls give back all the speed they get
+pub(crate) fn wall_bundle(instance: &Instance, pos: Vec2, size_half: Vec2) -> ColliderBundle {
+    ColliderBundle {
+        position: pos.into(),
+        collider_type: ColliderType::Solid,
+        shape: ColliderShape::cuboid(size_half.x, size_half.y),
+        material: ColliderMaterial {
+            friction: 0.0,
+            restitution: 1.0,
             ..Default::default()
-        })
-        .insert(ColliderPositionSync::Discrete)
-        .insert(ColliderDebugRender::from(color))
-        .insert(Brick)
-        .insert(BreakoutCleanup);
+        },
+        flags: ColliderFlags {
+            collision_groups: instance.collision_groups(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
 }
 
-fn spawn_player(mut commands: Commands, config: Res<BreakoutConfig>) {
-    commands
-        .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(
-                0.0,
-                -config.board_size_half.y + (config.board_size_half.y * 0.1),
-            )
-            .into(),
+// pos is relative to the instance origin
+pub(crate) fn paddle_bundle(instance: &Instance, config: &BreakoutConfig, pos: Vec2) -> (RigidBodyBundle, ColliderBundle) {
+    (
+        RigidBodyBundle {
+            position: (instance.origin + pos).into(),
             body_type: RigidBodyType::KinematicPositionBased,
             ..Default::default()
-        })
-        .insert_bundle(ColliderBundle {
+        },
+        ColliderBundle {
             collider_type: ColliderType::Solid,
             shape: ColliderShape::cuboid(config.player_size_half.x, config.player_size_half.y),
             material: ColliderMaterial {
                 restitution: 1.0,
                 ..Default::default()
             },
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
             ..Default::default()
-        })
-        .insert(RigidBodyPositionSync::Discrete)
-        //.insert(ColliderPositionSync::Discrete)
-        .insert(ColliderDebugRender::from(config.player_color))
-        .insert(Player { index: 0 })
-        .insert(BreakoutCleanup);
+        },
+    )
 }
 
-fn spawn_ball(mut commands: Commands, config: Res<BreakoutConfig>) {
-    let mut rnd = rand::thread_rng();
-    commands
-        .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(
-                0.0,
-                -config.board_size_half.y + (config.board_size_half.y * 0.2),
-            )
-            .into(),
+// pos is relative to the instance origin, update_ball keeps the speed after launch
+pub(crate) fn ball_bundle(
+    instance: &Instance,
+    config: &BreakoutConfig,
+    pos: Vec2,
+    velocity: Vec2,
+) -> (RigidBodyBundle, ColliderBundle) {
+    (
+        RigidBodyBundle {
+            position: (instance.origin + pos).into(),
             mass_properties: (RigidBodyMassPropsFlags::ROTATION_LOCKED).into(),
             activation: RigidBodyActivation::cannot_sleep(),
             ccd: RigidBodyCcd {
@@ -323,21 +409,20 @@ fn spawn_ball(mut commands: Commands, config: Res<BreakoutConfig>) {
                 linear_damping: 0.0,
                 angular_damping: 0.0,
             },
-            // Create random launch vector
             velocity: RigidBodyVelocity {
-                linvel: Vec2::new(
-                    rnd.gen_range(config.ball_init_x_range.0..config.ball_init_x_range.1),
-                    config.ball_init_y,
-                )
-                .into(),
+                linvel: velocity.into(),
                 angvel: 0.0,
             },
             ..Default::default()
-        })
-        .insert_bundle(ColliderBundle {
+        },
+        ColliderBundle {
             collider_type: ColliderType::Solid,
             shape: ColliderShape::ball(config.ball_size_half),
-            flags: (ActiveEvents::CONTACT_EVENTS).into(),
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                active_events: ActiveEvents::CONTACT_EVENTS,
+                ..Default::default()
+            },
             material: ColliderMaterial {
                 friction: 0.0, // you lose all ball control on paddle at 0
                 restitution: 1.0,
@@ -345,15 +430,87 @@ fn spawn_ball(mut commands: Commands, config: Res<BreakoutConfig>) {
                 ..Default::default()
             },
             ..Default::default()
+        },
+    )
+}
+
+fn create_brick(
+    parent: &mut ChildBuilder,
+    instance: &Instance,
+    pos: Vec2,
+    size_half: Vec2,
+    color: Color,
+) {
+    parent
+        .spawn_bundle(ColliderBundle {
+            position: pos.into(),
+            collider_type: ColliderType::Solid,
+            material: ColliderMaterial {
+                friction: 0.0,
+                restitution: 1.0,
+                ..Default::default()
+            },
+            shape: ColliderShape::cuboid(size_half.x, size_half.y),
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                active_events: ActiveEvents::CONTACT_EVENTS | ActiveEvents::INTERSECTION_EVENTS,
+                ..Default::default()
+            },
+            ..Default::default()
         })
         .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(color))
+        .insert(Brick)
+        .insert(BreakoutCleanup)
+        .insert(*instance);
+}
+
+fn spawn_player(commands: &mut Commands, instance: &Instance, config: &BreakoutConfig) {
+    let (body, collider) = paddle_bundle(
+        instance,
+        config,
+        Vec2::new(0.0, -config.board_size_half.y + (config.board_size_half.y * 0.1)),
+    );
+    commands
+        .spawn_bundle(body)
+        .insert_bundle(collider)
+        .insert(RigidBodyPositionSync::Discrete)
+        //.insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(config.player_color))
+        .insert(Player)
+        .insert(BreakoutCleanup)
+        .insert(*instance);
+}
+
+fn spawn_ball(
+    commands: &mut Commands,
+    instance: &Instance,
+    config: &BreakoutConfig,
+    rng: &mut Pcg64,
+) {
+    // Create random launch vector
+    let velocity = Vec2::new(
+        rng.gen_range(config.ball_init_x_range.0..config.ball_init_x_range.1),
+        config.ball_init_y,
+    );
+    let (body, collider) = ball_bundle(
+        instance,
+        config,
+        Vec2::new(0.0, -config.board_size_half.y + (config.board_size_half.y * 0.2)),
+        velocity,
+    );
+    commands
+        .spawn_bundle(body)
+        .insert_bundle(collider)
+        .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(config.player_color))
         .insert(Ball)
-        .insert(BreakoutCleanup);
+        .insert(BreakoutCleanup)
+        .insert(*instance);
 }
 
 // Keep the ball speed somewhat constant and  avoid getting stuck by back and forth
-fn update_ball(
+pub(crate) fn update_ball(
     mut balls: Query<&mut RigidBodyVelocity, With<Ball>>,
     params: Res<IntegrationParameters>,
     config: Res<BreakoutConfig>,
@@ -380,19 +537,25 @@ fn update_ball(
 
 // The ball can get away using the paddle to force it though a wall, this checks for that
 fn ball_bounds_check(
-    balls: Query<&RigidBodyPosition, With<Ball>>,
+    balls: Query<(&Instance, &RigidBodyPosition), With<Ball>>,
+    mut boards: Query<(&Instance, &mut BreakoutDone), With<Board>>,
     config: Res<BreakoutConfig>,
-    mut state: ResMut<State<BreakoutState>>,
 ) {
-    for rb_pos in balls.iter() {
-        if rb_pos.position.translation.x.abs() > config.board_size_half.x
-            || rb_pos.position.translation.y.abs() > config.board_size_half.y
-        {
-            state.set(BreakoutState::Resetting).unwrap()
+    for (instance, rb_pos) in balls.iter() {
+        let x = rb_pos.position.translation.x - instance.origin.x;
+        let y = rb_pos.position.translation.y - instance.origin.y;
+        if x.abs() > config.board_size_half.x || y.abs() > config.board_size_half.y {
+            set_done(&mut boards, instance);
         }
     }
 }
 
+fn set_done(boards: &mut Query<(&Instance, &mut BreakoutDone), With<Board>>, instance: &Instance) {
+    for (_, mut done) in boards.iter_mut().filter(|(i, _)| i.index == instance.index) {
+        done.0 = true;
+    }
+}
+
 // So Rapier will provide the collisions, but only the entity id
 // We can either record entity ids when we create them, or mark the entities
 // You could also query narrow phase, but using the ContactEvent is a bit clearer
@@ -400,11 +563,10 @@ fn ball_bounds_check(
 fn ball_collision(
     mut commands: Commands,
     mut contact_events: EventReader<ContactEvent>,
-    brick_hits: Query<Entity, (With<Brick>, With<Hit>)>,
-    bottom_hits: Query<Entity, (With<BoardBottom>, With<Hit>)>,
+    brick_hits: Query<(Entity, &Instance), (With<Brick>, With<Hit>)>,
+    bottom_hits: Query<&Instance, (With<BoardBottom>, With<Hit>)>,
     extra_hits: Query<Entity, (Without<Brick>, Without<BoardBottom>, With<Hit>)>,
-    mut score: ResMut<Score>,
-    mut state: ResMut<State<BreakoutState>>,
+    mut boards: Query<(&Instance, &mut Score, &mut BreakoutDone), With<Board>>,
     config: Res<BreakoutConfig>,
 ) {
     // Mark every contact event entity, will process them next frame
@@ -418,20 +580,23 @@ fn ball_collision(
         }
     }
 
-    for b in brick_hits.iter() {
+    for (b, instance) in brick_hits.iter() {
         commands.entity(b).despawn_recursive();
-        score.0 += 1;
+        for (_, mut score, mut done) in boards.iter_mut().filter(|(i, ..)| i.index == instance.index) {
+            score.total += 1;
+            score.step += 1;
 
-        // This exit condition only works assuming no bugs with hits
-        // being using it this way to debug
-        if score.0 == config.brick_grid.x * config.brick_grid.y {
-            state.set(BreakoutState::Resetting).unwrap();
-            return;
+            // This exit condition only works assuming no bugs with hits
+            // being using it this way to debug
+            if score.total == config.brick_grid.x * config.brick_grid.y {
+                done.0 = true;
+            }
         }
     }
-    for _ in bottom_hits.iter() {
-        state.set(BreakoutState::Resetting).unwrap();
-        return;
+    for instance in bottom_hits.iter() {
+        for (_, _, mut done) in boards.iter_mut().filter(|(i, ..)| i.index == instance.index) {
+            done.0 = true;
+        }
     }
     for ext in extra_hits.iter() {
         commands.entity(ext).remove::<Hit>();
@@ -439,24 +604,10 @@ fn ball_collision(
 }
 
 fn other_keyboard_input(
-    mut keyboard_input: ResMut<Input<KeyCode>>,
-    mut state: ResMut<State<BreakoutState>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut boards: Query<(&Instance, &mut BreakoutDone), With<Board>>,
 ) {
     if keyboard_input.just_pressed(KeyCode::R) {
-        state.set(BreakoutState::Resetting).unwrap();
-        // TODO: You get stuck in a loop without updating keyboard
-        // https://github.com/bevyengine/bevy/issues/1700
-        keyboard_input.update();
-    }
-}
-
-fn clean_environment(
-    mut commands: Commands,
-    cleanup: Query<Entity, With<BreakoutCleanup>>,
-    mut state: ResMut<State<BreakoutState>>,
-) {
-    for e in cleanup.iter() {
-        commands.entity(e).despawn();
+        set_done(&mut boards, &Instance::default());
     }
-    state.set(BreakoutState::Loading).unwrap();
 }
@@ -1,33 +1,83 @@
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
 
-use crate::models::neat::NeatML;
-
-use super::{BreakoutConfig, Player};
-
-pub fn player_movement_neat(
-    mut neat: ResMut<NeatML>,
-    mut players: Query<(&Player, &RigidBodyPosition, &mut RigidBodyVelocity)>,
-    balls: Query<&RigidBodyPosition, With<Ball>>,
-    params: Res<IntegrationParameters>,
-    config: Res<BreakoutConfig>
-) {
-    let ball_pos = balls.single().expect("One ball should exist!");
-
-    for (player, pos, mut rb_vel) in players.iter_mut() {
-        let observations = &[
-            pos.position.translation.x as f64,      // player x
-            pos.position.translation.y as f64,      // player y
-            ball_pos.position.translation.x as f64, // ball x
-            ball_pos.position.translation.y as f64, // ball y
-        ];
-        let output = neat.pool.activate_nth(player.index, observations).unwrap();
-
-        // Go left
-        rb_vel.linvel = match output[0] {
-            o if o < 0.33 => Vec2::new(-config.player_speed * params.dt, 0.0).into(),
-            o if o > 0.66 => Vec2::new(config.player_speed * params.dt, 0.0).into(),
-            _ => Vec2::ZERO.into(),
+use crate::{
+    environment::{despawn_instance, Env, Instance},
+    models::neat::NeatML,
+    space::Action,
+};
+
+use super::{BreakoutCleanup, BreakoutPlugin};
+
+const BRICK_FITNESS: f64 = 10.0; // each brick is worth this many seconds alive
+const MAX_STEPS: usize = 5_000; // so a paddle that never misses doesn't stall the generation
+
+// One paddle per genome, each in its own board instance
+#[derive(Default)]
+pub struct NeatAgents {
+    steps: Vec<usize>,
+    done: Vec<bool>,
+}
+
+// Drives every instance with its genome, the env systems are off (ManualStep)
+//
+// Starts a generation by resetting every instance, runs until they are all done,
+// then evolves and starts over
+pub fn neat_system(world: &mut World) {
+    let offset = BreakoutPlugin::instance_offset(world);
+    let population = world.get_resource::<NeatML>().unwrap().population;
+
+    if world.get_resource::<NeatAgents>().unwrap().done.is_empty() {
+        for index in 0..population {
+            BreakoutPlugin::reset(world, &Instance::new(index, offset));
+        }
+        let mut agents = world.get_resource_mut::<NeatAgents>().unwrap();
+        agents.steps = vec![0; population];
+        agents.done = vec![false; population];
+        return;
+    }
+
+    let dt = world.get_resource::<IntegrationParameters>().unwrap().dt as f64;
+    for index in 0..population {
+        if world.get_resource::<NeatAgents>().unwrap().done[index] {
+            continue;
         }
+        let instance = Instance::new(index, offset);
+        let step = BreakoutPlugin::observe(world, &instance);
+
+        let mut agents = world.get_resource_mut::<NeatAgents>().unwrap();
+        agents.steps[index] += 1;
+        let steps = agents.steps[index];
+
+        if step.is_done || steps >= MAX_STEPS {
+            agents.done[index] = true;
+            let bricks = step.info.get("score").cloned().unwrap_or(0.0) as f64;
+            let fitness = bricks * BRICK_FITNESS + steps as f64 * dt;
+            world
+                .get_resource_mut::<NeatML>()
+                .unwrap()
+                .record_fitness(index, fitness);
+            despawn_instance::<BreakoutCleanup>(world, &instance);
+        } else {
+            let observation: Vec<f64> = step.observation.iter().map(|v| *v as f64).collect();
+            let output = world
+                .get_resource_mut::<NeatML>()
+                .unwrap()
+                .activate(index, &observation);
+            let action = match output[0] {
+                o if o < 0.33 => 0, // left
+                o if o > 0.66 => 2, // right
+                _ => 1,
+            };
+            BreakoutPlugin::act(world, &instance, &Action::Discrete(action));
+        }
+    }
+
+    // Everyone is done, run neat though a generation, the next call will reset
+    let mut agents = world.get_resource_mut::<NeatAgents>().unwrap();
+    if agents.done.iter().all(|d| *d) {
+        agents.done.clear();
+        agents.steps.clear();
+        world.get_resource_mut::<NeatML>().unwrap().next_generation();
     }
 }
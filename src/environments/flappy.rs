@@ -1,25 +1,62 @@
-use crate::{helpers::V2, models::neat::NeatML};
+use crate::{
+    environment::*,
+    models::neat::{neat_agents_system, NeatML},
+    multi_agent::*,
+    space::*,
+};
 use bevy::{prelude::*, render::camera::Camera};
 use bevy_prototype_debug_lines::DebugLines;
 use bevy_rapier2d::prelude::*;
 use rand::Rng;
-use std::{ops::Range, time::Duration};
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ops::Range};
 
 pub struct FlappyPlugin {
-    pub config: FlappyConfig,
+    pub render: bool,
+    pub human: bool,
+    pub neat: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+// Physics and rules, see load_env_config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FlappyConfig {
-    pub render: bool,
-    pub human: bool,
+    pub tube_size_half: (f32, f32),
+    pub tube_spacing: f32,
+    pub tube_gap_size_half: f32,  // Control gap size between tubes in a set
+    pub tube_gap_offset_max: f32, // Control gap range off of y axis
+    pub tube_gap_clamp_half: f32, // Removes impossible height changes
+    pub tube_speed: f32,
+    pub tube_count: usize,
+    pub bird_size_half: (f32, f32),
+    pub action_force: f32,
+    pub bird_limit_x: Range<f32>, // dead outside these
+    pub bird_limit_y: Range<f32>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum FlappyState {
-    Loading,
-    Playing,
-    Resetting,
+impl Default for FlappyConfig {
+    fn default() -> Self {
+        Self {
+            tube_size_half: (1.0, 10.0),
+            tube_spacing: 12.0,
+            tube_gap_size_half: 2.0,
+            tube_gap_offset_max: 6.0,
+            tube_gap_clamp_half: 10.0,
+            tube_speed: 0.10,
+            tube_count: 5,
+            bird_size_half: (0.0, 0.0),
+            action_force: 250.0,
+            bird_limit_x: -1.0..4.0,
+            bird_limit_y: -8.0..8.0,
+        }
+    }
+}
+
+impl FlappyConfig {
+    fn tube_despawn_limit(&self) -> f32 {
+        -2.0 * self.tube_spacing
+    }
 }
 
 struct Bird {
@@ -31,185 +68,251 @@ struct Tube {
     bottom_lip: f32,
 }
 
-struct TubeLastGapOffset(f32);
+// Last gap offset for each instance, keeps the next tube reachable
+#[derive(Default)]
+struct TubeLastGapOffset(HashMap<usize, f32>);
 struct Population(usize);
 
 impl Plugin for FlappyPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.insert_resource(self.config)
-            .insert_resource(TubeLastGapOffset(0.0))
-            .add_state(FlappyState::Loading)
-            .add_system_set(
-                SystemSet::on_enter(FlappyState::Loading).with_system(setup_environment.system()),
-            )
-            .add_system_set(
-                SystemSet::on_update(FlappyState::Playing)
-                    .with_system(scroll_tubes.system())
-                    .with_system(catchup_bird.system()),
-            )
-            .add_system_set(
-                SystemSet::on_enter(FlappyState::Resetting).with_system(clear_environment.system()),
-            );
+        let config = load_env_config(app.world(), FlappyConfig::default());
+        app.insert_resource(config)
+            .insert_resource(TubeLastGapOffset::default())
+            .add_system(scroll_tubes.system())
+            .add_system(catchup_bird.system());
+        add_env_systems::<FlappyPlugin>(app);
+
+        if self.render {
+            app.add_startup_system(setup_camera.system());
+        }
 
-        if self.config.human {
+        if self.human {
             println!("Press Space to jump, Escape to exit");
-            app.insert_resource(Population(1)).add_system_set_to_stage(
-                CoreStage::Update,
-                SystemSet::on_update(FlappyState::Playing).with_system(update_human.system()),
-            );
-        } else {
-            let model = NeatML::new("./params/flappy.toml", Duration::new(0, 0), true);
+            app.insert_resource(Population(1))
+                .add_system(keyboard_input.system());
+        } else if self.neat {
+            let checkpoint = &app.world().get_resource::<EnvironmentConfig>().unwrap().checkpoint;
+            let model = NeatML::new("./params/flappy.toml", true)
+                .with_checkpoints("flappy", checkpoint);
             app.insert_resource(Population(model.population))
                 .insert_resource(model)
-                .add_system_set_to_stage(
-                    CoreStage::Update,
-                    SystemSet::on_update(FlappyState::Playing)
-                        .with_system(update_neat.system())
-                        .with_system(next_generation_check.system()),
-                );
+                .add_system(neat_agents_system.exclusive_system().at_start());
+            add_multi_agent_systems::<FlappyPlugin>(app);
+        } else {
+            let agents = app
+                .world()
+                .get_resource::<EnvironmentConfig>()
+                .and_then(|config| config.agents)
+                .unwrap_or(1);
+            app.insert_resource(Population(agents));
+            add_multi_agent_fns::<FlappyPlugin>(app);
         }
-
-        if self.config.human && self.config.render {}
     }
 }
 
-const RAPIER_SCALE: f32 = 50.0; // Very useful to zoom in and out to see whats going on
-                                // Also see https://rapier.rs/docs/user_guides/bevy_plugin/common_mistakes/#why-is-everything-moving-in-slow-motion
-const TUBE_SIZE_HALF_X: f32 = 1.0;
-const TUBE_SIZE_HALF_Y: f32 = 10.0;
-const TUBE_SPACING: f32 = 12.0;
-const TUBE_GAP_SIZE_HALF: f32 = 2.0; // Control gap size between tubes in a set
-const TUBE_GAP_OFFSET_MAX: f32 = 6.0; // Control gap range off of y axis
-const TUBE_GAP_CLAMP_HALF: f32 = 10.0; // Removes impossible height changes
-const TUBE_SPEED: f32 = 0.10;
-const TUBE_COUNT: usize = 5;
-const TUBE_DESPAWN_LIMIT: f32 = -2.0 * TUBE_SPACING;
-const BIRD_SIZE_HALF: V2<f32> = V2 { x: 0.0, y: 0.0 };
-const ACTION_FORCE: f32 = 250.0;
-const BIRD_LIMIT_X: Range<f32> = -1.0..4.0;
-const BIRD_LIMIT_Y: Range<f32> = -8.0..8.0;
-
-fn update_human(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut birds: Query<(&RigidBodyPosition, &mut RigidBodyVelocity), With<Bird>>,
-    params: Res<IntegrationParameters>,
-    mut state: ResMut<State<FlappyState>>,
-) {
-    for (rb_pos, mut rb_vel) in birds.iter_mut() {
-        if keyboard_input.pressed(KeyCode::Space) {
-            rb_vel.linvel = Vec2::new(0.0, ACTION_FORCE * params.dt).into();
+impl Env for FlappyPlugin {
+    fn environment(world: &World) -> Environment {
+        let config = world.get_resource::<FlappyConfig>().unwrap();
+        let lip_limit = config.tube_gap_offset_max + config.tube_gap_size_half;
+        Environment {
+            action_space: Space::Discrete(2),
+            observation_space: Space::boxed(
+                vec![config.bird_limit_y.start, -lip_limit, -lip_limit],
+                vec![config.bird_limit_y.end, lip_limit, lip_limit],
+            ),
         }
+    }
+
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, 40.0)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        world.get_resource_mut::<RapierConfiguration>().unwrap().scale = RAPIER_SCALE;
+        despawn_instance::<Bird>(world, instance);
+        despawn_instance::<Tube>(world, instance);
+
+        world.resource_scope(|world, mut gap_offset: Mut<TubeLastGapOffset>| {
+            world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+                gap_offset.0.insert(instance.index, 0.0);
+                run_commands(world, |commands, world| {
+                    let config = world.get_resource::<FlappyConfig>().unwrap();
+                    let population = world.get_resource::<Population>().unwrap();
+                    spawn_environment(
+                        commands,
+                        instance,
+                        config,
+                        population.0,
+                        &mut *gap_offset,
+                        &mut rng.0,
+                    );
+                });
+            });
+        });
+
+        Self::observe(world, instance).observation
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+        let force = world.get_resource::<FlappyConfig>().unwrap().action_force * dt;
+        let mut birds = world.query_filtered::<(&Instance, &mut RigidBodyVelocity), With<Bird>>();
+        for (_, mut rb_vel) in birds.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            match action.discrete() {
+                0 => {} // Do nothing
+                1 => rb_vel.linvel = Vec2::new(0.0, force).into(),
+                _ => panic!("action invalid: {:?}", action),
+            }
+        }
+    }
 
-        if is_bird_dead(rb_pos.position.translation.x, rb_pos.position.translation.y) {
-            state.set(FlappyState::Resetting).unwrap();
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let (tube_top_lip, tube_bottom_lip) = current_tube_lips(world, instance);
+        let config = world.get_resource::<FlappyConfig>().unwrap().clone();
+
+        // Done once every bird is dead, with neat they are despawned as they die
+        let mut is_done = true;
+        let mut bird_pos_y = 0.0;
+        let mut birds = world.query_filtered::<(&Instance, &RigidBodyPosition), With<Bird>>();
+        for (_, rb_pos) in birds.iter(world).filter(|(i, _)| i.index == instance.index) {
+            let pos = Vec2::new(rb_pos.position.translation.x, rb_pos.position.translation.y) - instance.origin;
+            if !is_bird_dead(&config, pos.x, pos.y) {
+                is_done = false;
+                bird_pos_y = pos.y;
+            }
+        }
+
+        Step {
+            observation: vec![bird_pos_y, tube_top_lip, tube_bottom_lip],
+            reward: 1.0,
+            is_done: is_done,
+            ..Default::default()
         }
     }
 }
 
-// Update Current State of the environment
-fn update_neat(
-    mut commands: Commands,
-    mut birds: Query<(Entity, &Bird, &RigidBodyPosition, &mut RigidBodyVelocity)>,
-    tubes: Query<&Tube>,
-    mut neat: ResMut<NeatML>,
-    params: Res<IntegrationParameters>,
-    time: Res<Time>,
-) {
-    // Find the next tube openings
-    let (mut tube_top_lip, mut tube_bottom_lip) = (0.0, 0.0);
-    for tube in tubes.iter().filter(|t| t.current) {
-        tube_top_lip = tube.top_lip;
-        tube_bottom_lip = tube.bottom_lip;
+// Every bird is an agent, each dies on its own and is despawned when it does
+impl MultiAgentEnv for FlappyPlugin {
+    fn possible_agents(world: &World) -> Vec<AgentId> {
+        (0..world.get_resource::<Population>().unwrap().0).collect()
     }
 
-    // For each bird, see if its dead, if not figure out its observables, then perform its action
-    for (e, bird, rb_pos, mut rb_vel) in birds.iter_mut() {
-        let bird_pos_y = rb_pos.position.translation.y;
+    fn reset_agents(world: &mut World, instance: &Instance) -> HashMap<AgentId, Vec<f32>> {
+        Self::reset(world, instance);
+        Self::observe_agents(world, instance)
+            .into_iter()
+            .map(|(agent, step)| (agent, step.observation))
+            .collect()
+    }
 
-        if is_bird_dead(rb_pos.position.translation.x, bird_pos_y) {
-            neat.record_complete_agent(bird.index, time.time_since_startup());
-            commands.entity(e).despawn_recursive();
-        } else {
-            // TODO: the original code had this translation step, don't think its needed
-            // translate those positions to be relative to the bird,
-            // let tube_top_lip = tube_top_lip - (bird_pos_y + BIRD_SIZE_HALF);
-            // let tube_bottom_lip = (bird_pos_y - BIRD_SIZE_HALF) - tube_bottom_lip;
-
-            // Preform our action
-            let output = neat
-                .pool
-                .activate_nth(
-                    bird.index,
-                    &[
-                        bird_pos_y as f64,
-                        tube_top_lip as f64,
-                        tube_bottom_lip as f64,
-                    ],
-                )
-                .unwrap();
-
-            if output[0] > 0.5 {
-                // Jump
-                rb_vel.linvel = Vec2::new(0.0, ACTION_FORCE * params.dt).into();
+    fn act_agents(world: &mut World, instance: &Instance, actions: &HashMap<AgentId, Action>) {
+        let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+        let force = world.get_resource::<FlappyConfig>().unwrap().action_force * dt;
+        let mut birds = world.query::<(&Instance, &Bird, &mut RigidBodyVelocity)>();
+        for (_, bird, mut rb_vel) in birds.iter_mut(world).filter(|(i, ..)| i.index == instance.index) {
+            match actions.get(&bird.index).map(|action| action.discrete()) {
+                None | Some(0) => {} // Do nothing
+                Some(1) => rb_vel.linvel = Vec2::new(0.0, force).into(),
+                Some(_) => panic!("action invalid: {:?}", actions[&bird.index]),
             }
         }
     }
+
+    fn observe_agents(world: &mut World, instance: &Instance) -> HashMap<AgentId, Step> {
+        let (tube_top_lip, tube_bottom_lip) = current_tube_lips(world, instance);
+        let config = world.get_resource::<FlappyConfig>().unwrap().clone();
+
+        let mut steps = HashMap::new();
+        let mut dead = Vec::new();
+        let mut birds = world.query::<(Entity, &Instance, &Bird, &RigidBodyPosition)>();
+        for (e, _, bird, rb_pos) in birds.iter(world).filter(|(_, i, ..)| i.index == instance.index) {
+            let pos = Vec2::new(rb_pos.position.translation.x, rb_pos.position.translation.y) - instance.origin;
+            let is_done = is_bird_dead(&config, pos.x, pos.y);
+            if is_done {
+                dead.push(e);
+            }
+            steps.insert(
+                bird.index,
+                Step {
+                    observation: vec![pos.y, tube_top_lip, tube_bottom_lip],
+                    reward: if is_done { 0.0 } else { 1.0 },
+                    is_done: is_done,
+                    ..Default::default()
+                },
+            );
+        }
+
+        run_commands(world, |commands, _| {
+            for e in dead {
+                commands.entity(e).despawn_recursive();
+            }
+        });
+        steps
+    }
 }
 
-fn next_generation_check(
-    mut neat: ResMut<NeatML>,
-    birds: Query<&Bird>,
-    mut state: ResMut<State<FlappyState>>,
-    time: Res<Time>,
-) {
-    // Are all the birds dead?
-    if birds.iter().count() == 0 {
-        // Yes, run neat though a generation
-        neat.next_generation(time.time_since_startup());
+const RAPIER_SCALE: f32 = 50.0; // Very useful to zoom in and out to see whats going on
+                                // Also see https://rapier.rs/docs/user_guides/bevy_plugin/common_mistakes/#why-is-everything-moving-in-slow-motion
+
+fn keyboard_input(keyboard_input: Res<Input<KeyCode>>, mut env_state: ResMut<EnvironmentState>) {
+    env_state.action = if keyboard_input.pressed(KeyCode::Space) {
+        Some(Action::Discrete(1))
+    } else {
+        None
+    };
+}
 
-        // Reset
-        state.set(FlappyState::Resetting).unwrap();
+// Find the next tube openings
+fn current_tube_lips(world: &mut World, instance: &Instance) -> (f32, f32) {
+    let (mut tube_top_lip, mut tube_bottom_lip) = (0.0, 0.0);
+    let mut tubes = world.query::<(&Instance, &Tube)>();
+    for (_, tube) in tubes
+        .iter(world)
+        .filter(|(i, t)| i.index == instance.index && t.current)
+    {
+        tube_top_lip = tube.top_lip;
+        tube_bottom_lip = tube.bottom_lip;
     }
+    (tube_top_lip, tube_bottom_lip)
 }
 
-fn is_bird_dead(bird_pos_x: f32, bird_pos_y: f32) -> bool {
+fn is_bird_dead(config: &FlappyConfig, bird_pos_x: f32, bird_pos_y: f32) -> bool {
     // if bird is in the range, its not dead
-    if BIRD_LIMIT_X.contains(&bird_pos_x) && BIRD_LIMIT_Y.contains(&bird_pos_y) {
+    if config.bird_limit_x.contains(&bird_pos_x) && config.bird_limit_y.contains(&bird_pos_y) {
         return false;
     }
     true
 }
 
-fn setup_environment(
-    mut commands: Commands,
-    mut rapier_config: ResMut<RapierConfiguration>,
-    config: ResMut<FlappyConfig>,
-    population: Res<Population>,
-    mut state: ResMut<State<FlappyState>>,
-    camera: Query<&Camera>,
-    mut gap_offset: ResMut<TubeLastGapOffset>,
-) {
-    rapier_config.scale = RAPIER_SCALE;
-
-    if config.render && camera.iter().count() == 0 {
+fn setup_camera(mut commands: Commands, camera: Query<&Camera>) {
+    if camera.iter().count() == 0 {
         let mut camera = OrthographicCameraBundle::new_2d();
         camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 50.0));
         commands.spawn_bundle(camera);
     }
+}
 
+fn spawn_environment(
+    commands: &mut Commands,
+    instance: &Instance,
+    config: &FlappyConfig,
+    population: usize,
+    gap_offset: &mut TubeLastGapOffset,
+    rng: &mut Pcg64,
+) {
     // Create the Birds
-    for i in 0..population.0 {
+    for i in 0..population {
         commands
             .spawn_bundle(RigidBodyBundle {
-                position: Vec2::new(0.0, 0.0).into(),
+                position: instance.origin.into(),
                 body_type: RigidBodyType::Dynamic,
                 ..Default::default()
             })
             .insert_bundle(ColliderBundle {
-                shape: ColliderShape::cuboid(BIRD_SIZE_HALF.x, BIRD_SIZE_HALF.y),
+                shape: ColliderShape::cuboid(config.bird_size_half.0, config.bird_size_half.1),
                 collider_type: ColliderType::Solid,
                 flags: ColliderFlags {
-                    collision_groups: InteractionGroups::new(0b0001, 0b0010),
+                    collision_groups: instance.layer_collision_groups(0),
                     ..Default::default()
                 },
                 ..Default::default()
@@ -217,64 +320,67 @@ fn setup_environment(
             .insert(ColliderPositionSync::Discrete)
             .insert(ColliderDebugRender::from(Color::RED))
             .insert(Bird { index: i })
+            .insert(*instance)
             .id();
     }
 
     // Create tubes
-    for x in 0..TUBE_COUNT {
-        spawn_tube_set(
-            &mut commands,
-            (x + 1) as f32 * TUBE_SPACING,
-            &mut gap_offset,
-        );
+    for x in 0..config.tube_count {
+        spawn_tube_set(commands, instance, config, (x + 1) as f32 * config.tube_spacing, gap_offset, rng);
     }
-
-    state.set(FlappyState::Playing).unwrap();
 }
 
-fn spawn_tube_set(commands: &mut Commands, pos_x: f32, last_gap_offset: &mut TubeLastGapOffset) {
+fn spawn_tube_set(
+    commands: &mut Commands,
+    instance: &Instance,
+    config: &FlappyConfig,
+    pos_x: f32,
+    last_gap_offsets: &mut TubeLastGapOffset,
+    rng: &mut Pcg64,
+) {
     // figure out where the tubes should be
-    let mut rng = rand::thread_rng();
+    let last_gap_offset = last_gap_offsets.0.entry(instance.index).or_insert(0.0);
     let gap_offset = rng
-        .gen_range(-TUBE_GAP_OFFSET_MAX..TUBE_GAP_OFFSET_MAX)
+        .gen_range(-config.tube_gap_offset_max..config.tube_gap_offset_max)
         .clamp(
-            last_gap_offset.0 - TUBE_GAP_CLAMP_HALF,
-            last_gap_offset.0 + TUBE_GAP_CLAMP_HALF,
+            *last_gap_offset - config.tube_gap_clamp_half,
+            *last_gap_offset + config.tube_gap_clamp_half,
         ); // Remove impossible height changes
-    last_gap_offset.0 = gap_offset;
+    *last_gap_offset = gap_offset;
 
-    let spacing = TUBE_SIZE_HALF_Y + TUBE_GAP_SIZE_HALF;
+    let spacing = config.tube_size_half.1 + config.tube_gap_size_half;
     let top_pos = Vec2::new(0.0, spacing + gap_offset);
     let bottom_pos = Vec2::new(0.0, -spacing + gap_offset);
 
     commands
         .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(pos_x, 0.0).into(),
+            position: (instance.origin + Vec2::new(pos_x, 0.0)).into(),
             body_type: RigidBodyType::Static,
             ..Default::default()
         })
         .with_children(|mut parent| {
-            create_child_tubes(&mut parent, top_pos);
-            create_child_tubes(&mut parent, bottom_pos);
+            create_child_tubes(&mut parent, instance, config, top_pos);
+            create_child_tubes(&mut parent, instance, config, bottom_pos);
         })
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(Color::GREEN))
         .insert(Tube {
-            top_lip: TUBE_GAP_SIZE_HALF + gap_offset,
-            bottom_lip: -TUBE_GAP_SIZE_HALF + gap_offset,
+            top_lip: config.tube_gap_size_half + gap_offset,
+            bottom_lip: -config.tube_gap_size_half + gap_offset,
             current: false,
         })
+        .insert(*instance)
         .id();
 }
 
-fn create_child_tubes(parent: &mut ChildBuilder, pos: Vec2) {
+fn create_child_tubes(parent: &mut ChildBuilder, instance: &Instance, config: &FlappyConfig, pos: Vec2) {
     parent
         .spawn_bundle(ColliderBundle {
             position: pos.into(),
             collider_type: ColliderType::Solid,
-            shape: ColliderShape::cuboid(TUBE_SIZE_HALF_X, TUBE_SIZE_HALF_Y),
+            shape: ColliderShape::cuboid(config.tube_size_half.0, config.tube_size_half.1),
             flags: ColliderFlags {
-                collision_groups: InteractionGroups::new(0b0010, 0b0001),
+                collision_groups: instance.layer_collision_groups(1),
                 ..Default::default()
             },
             ..Default::default()
@@ -285,33 +391,40 @@ fn create_child_tubes(parent: &mut ChildBuilder, pos: Vec2) {
 
 fn scroll_tubes(
     mut commands: Commands,
-    mut tubes: Query<(Entity, &mut RigidBodyPosition, &mut Tube)>,
+    mut tubes: Query<(Entity, &Instance, &mut RigidBodyPosition, &mut Tube)>,
     mut lines: ResMut<DebugLines>,
     mut gap_offset: ResMut<TubeLastGapOffset>,
+    mut rng: ResMut<EnvRng>,
+    config: Res<FlappyConfig>,
 ) {
-    for (e, mut rb_pos, mut tube) in tubes.iter_mut() {
-        rb_pos.position.translation.x -= TUBE_SPEED;
+    for (e, instance, mut rb_pos, mut tube) in tubes.iter_mut() {
+        rb_pos.position.translation.x -= config.tube_speed;
 
-        let x = rb_pos.position.translation.x;
+        let x = rb_pos.position.translation.x - instance.origin.x;
 
         // despawn when off screen and spawn new tube
-        if x < TUBE_DESPAWN_LIMIT {
+        if x < config.tube_despawn_limit() {
             commands.entity(e).despawn_recursive();
 
             spawn_tube_set(
                 &mut commands,
-                TUBE_COUNT as f32 * TUBE_SPACING + TUBE_DESPAWN_LIMIT,
+                instance,
+                &config,
+                config.tube_count as f32 * config.tube_spacing + config.tube_despawn_limit(),
                 &mut gap_offset,
+                &mut rng.0,
             );
         }
 
         // Update Tube Status
-        if x > 0.0 && x < TUBE_SPACING {
+        if x > 0.0 && x < config.tube_spacing {
             tube.current = true;
 
             // Draw Debug Line
-            let start = Vec3::new(0.0, tube.top_lip * RAPIER_SCALE, 0.0);
-            let end = Vec3::new(0.0, tube.bottom_lip * RAPIER_SCALE, 0.0);
+            let origin = instance.origin.extend(0.0);
+            let start = origin + Vec3::new(0.0, tube.top_lip, 0.0);
+            let end = origin + Vec3::new(0.0, tube.bottom_lip, 0.0);
+            let (start, end) = (start * RAPIER_SCALE, end * RAPIER_SCALE);
             lines.line_colored(start, end, 0.0, Color::BLUE);
         } else {
             tube.current = false;
@@ -322,24 +435,9 @@ fn scroll_tubes(
 // The bird can get stuck behind tubes and falls behind and skid off top of tubes
 // I could just trigger game over when a touch occurs but I like the effect
 // This is a system to move bird back to x=0, so it can be used more than once or twice
-fn catchup_bird(mut bird: Query<&mut RigidBodyPosition, With<Bird>>) {
-    for mut rb_pos in bird.iter_mut() {
+fn catchup_bird(mut bird: Query<(&Instance, &mut RigidBodyPosition), With<Bird>>) {
+    for (instance, mut rb_pos) in bird.iter_mut() {
         // poor mans lerp
-        rb_pos.position.translation.x -= rb_pos.position.translation.x / 60.0;
-    }
-}
-
-fn clear_environment(
-    mut commands: Commands,
-    query_set: QuerySet<(Query<Entity, With<Bird>>, Query<Entity, With<Tube>>)>,
-    mut state: ResMut<State<FlappyState>>,
-) {
-    for e in query_set.q0().iter() {
-        commands.entity(e).despawn_recursive();
+        rb_pos.position.translation.x -= (rb_pos.position.translation.x - instance.origin.x) / 60.0;
     }
-    for e in query_set.q1().iter() {
-        commands.entity(e).despawn_recursive();
-    }
-
-    state.set(FlappyState::Loading).unwrap();
 }
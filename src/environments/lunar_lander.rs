@@ -0,0 +1,473 @@
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+use crate::{environment::*, space::*};
+
+// Based on gym's LunarLander, land between the flags on the pad
+//
+// Sizes are gym's, in meters, with the same 30 pixels per meter. Engines change the
+// lander's velocity directly, so they don't depend on its mass.
+pub struct LunarLanderPlugin {
+    pub render: bool,
+    pub human: bool,
+    pub continuous: bool,
+}
+
+// Same as gym's LunarLanderContinuous, main and side throttle each in -1..1
+pub struct LunarLanderContinuous;
+
+// Physics and rules, see load_env_config, engines are in velocity per second
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LunarLanderConfig {
+    pub gravity: f32,
+    pub max_steps: usize,
+    pub initial_vel_limit: f32,
+    pub main_engine_accel: f32, // enough to beat gravity at full throttle
+    pub side_engine_accel: f32,
+    pub side_engine_spin: f32, // side engines sit above the center, so they also turn the lander
+    pub main_engine_fuel: f32,
+    pub side_engine_fuel: f32,
+    pub rest_speed: f32,
+}
+
+impl Default for LunarLanderConfig {
+    fn default() -> Self {
+        Self {
+            gravity: -10.0,
+            max_steps: 1000,
+            initial_vel_limit: 2.0,
+            main_engine_accel: 20.0,
+            side_engine_accel: 2.0,
+            side_engine_spin: 1.5,
+            main_engine_fuel: 0.3,
+            side_engine_fuel: 0.03,
+            rest_speed: 0.05,
+        }
+    }
+}
+
+// Makers
+struct Lander {
+    steps: usize,
+    crashed: bool,   // body touched the ground
+    main_power: f32, // last action, for fuel use
+    side_power: f32,
+    prev_shaping: Option<f32>, // reward is the change in shaping
+}
+struct Leg {
+    side: f32, // -1 left, 1 right
+    contact: bool,
+}
+struct Ground;
+struct Flag;
+struct LunarLanderClean;
+
+impl Plugin for LunarLanderPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let config = load_env_config(app.world(), LunarLanderConfig::default());
+        app.insert_resource(config);
+        if self.continuous {
+            add_env_systems::<LunarLanderContinuous>(app);
+        } else {
+            add_env_systems::<LunarLanderPlugin>(app);
+        }
+        app.add_system(lander_contacts.system());
+
+        if self.render {
+            app.add_startup_system(setup_camera.system());
+        }
+        if self.human && self.render {
+            app.add_system(keyboard_input.system());
+            println!("Keys: W for the main engine, A and D to steer");
+        }
+    }
+}
+
+impl Env for LunarLanderPlugin {
+    fn environment(_: &World) -> Environment {
+        // x, y, x velocity, y velocity, angle, angular velocity, left leg and right leg contact
+        // 0 nothing, 1 steer left, 2 main engine, 3 steer right
+        Environment {
+            action_space: Space::Discrete(4),
+            observation_space: Space::unbounded(8),
+        }
+    }
+
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, VIEWPORT_H_HALF * 2.5)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        let gravity = world.get_resource::<LunarLanderConfig>().unwrap().gravity;
+        let mut rapier_config = world.get_resource_mut::<RapierConfiguration>().unwrap();
+        rapier_config.scale = RAPIER_SCALE;
+        rapier_config.gravity = Vec2::new(0.0, gravity).into();
+
+        despawn_instance::<LunarLanderClean>(world, instance);
+        world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+            run_commands(world, |commands, world| {
+                let config = world.get_resource::<LunarLanderConfig>().unwrap();
+                spawn_terrain(commands, instance, &mut rng.0);
+                spawn_lander(commands, instance, config, &mut rng.0);
+            });
+        });
+        Self::observe(world, instance).observation
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let (main, side) = match action.discrete() {
+            0 => (0.0, 0.0),
+            1 => (0.0, -1.0),
+            2 => (1.0, 0.0),
+            3 => (0.0, 1.0),
+            _ => panic!("action invalid: {:?}", action),
+        };
+        fire_engines(world, instance, main, side);
+    }
+
+    // Same reward as gym, the change in shaping (closer to the pad, slower, level and legs
+    // down is better) less fuel, -100 for crashing or flying off and +100 for coming to rest
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let config = world.get_resource::<LunarLanderConfig>().unwrap().clone();
+        let mut contacts = [0.0, 0.0];
+        let mut legs = world.query::<(&Instance, &Leg)>();
+        for (_, leg) in legs.iter(world).filter(|(i, _)| i.index == instance.index) {
+            if leg.contact {
+                contacts[if leg.side < 0.0 { 0 } else { 1 }] = 1.0;
+            }
+        }
+
+        let mut step = Step::default();
+        let mut landers = world.query::<(&Instance, &mut Lander, &RigidBodyPosition, &RigidBodyVelocity)>();
+        for (_, mut lander, rb_pos, rb_vel) in landers.iter_mut(world).filter(|(i, ..)| i.index == instance.index) {
+            let pos = rb_pos.position.translation;
+            let x = (pos.x - instance.origin.x) / VIEWPORT_W_HALF;
+            let y = (pos.y - instance.origin.y - HELIPAD_Y - LANDER_REST_HEIGHT) / VIEWPORT_H_HALF;
+            let vel_x = rb_vel.linvel.x / VIEWPORT_W_HALF;
+            let vel_y = rb_vel.linvel.y / VIEWPORT_H_HALF;
+            let angle = rb_pos.position.rotation.angle();
+
+            step.observation = vec![x, y, vel_x, vel_y, angle, rb_vel.angvel, contacts[0], contacts[1]];
+
+            // Reset's first look, before any shaping, isn't a step
+            if lander.prev_shaping.is_some() {
+                lander.steps += 1;
+            }
+
+            let shaping = -100.0 * (x * x + y * y).sqrt()
+                - 100.0 * (vel_x * vel_x + vel_y * vel_y).sqrt()
+                - 100.0 * angle.abs()
+                + 10.0 * contacts[0]
+                + 10.0 * contacts[1];
+            step.reward = lander.prev_shaping.map_or(0.0, |prev| shaping - prev)
+                - lander.main_power * config.main_engine_fuel
+                - lander.side_power * config.side_engine_fuel;
+            lander.prev_shaping = Some(shaping);
+
+            let at_rest = contacts[0] > 0.0
+                && contacts[1] > 0.0
+                && rb_vel.linvel.norm() < config.rest_speed
+                && rb_vel.angvel.abs() < config.rest_speed;
+            if lander.crashed || x.abs() >= 1.0 {
+                step.reward = -100.0;
+                step.is_done = true;
+            } else if at_rest {
+                step.reward = 100.0;
+                step.is_done = true;
+            } else if lander.steps >= config.max_steps {
+                step.is_done = true;
+                step.info.insert("truncated", 1.0);
+            }
+        }
+        step
+    }
+}
+
+impl Env for LunarLanderContinuous {
+    fn environment(world: &World) -> Environment {
+        Environment {
+            action_space: Space::boxed(vec![-1.0, -1.0], vec![1.0, 1.0]),
+            ..LunarLanderPlugin::environment(world)
+        }
+    }
+
+    fn instance_offset(world: &World) -> Vec2 {
+        LunarLanderPlugin::instance_offset(world)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        LunarLanderPlugin::reset(world, instance)
+    }
+
+    // Like gym, the main engine is off below 0 then 50% to 100%, side engines
+    // are off between -0.5 and 0.5
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let (main, side) = match action {
+            Action::Box(values) => (values[0].clamp(-1.0, 1.0), values[1].clamp(-1.0, 1.0)),
+            _ => panic!("action invalid: {:?}", action),
+        };
+        let main = if main > 0.0 { (main + 1.0) * 0.5 } else { 0.0 };
+        let side = if side.abs() > 0.5 { side } else { 0.0 };
+        fire_engines(world, instance, main, side);
+    }
+
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        LunarLanderPlugin::observe(world, instance)
+    }
+}
+
+const RAPIER_SCALE: f32 = 30.0;
+const VIEWPORT_W_HALF: f32 = 10.0;
+const VIEWPORT_H_HALF: f32 = 6.67;
+
+const TERRAIN_CHUNKS: usize = 11;
+const HELIPAD_Y: f32 = -VIEWPORT_H_HALF * 0.5;
+
+const LEG_AWAY: f32 = 0.67;
+const LEG_DOWN: f32 = 0.6;
+const LEG_ANGLE: f32 = 0.25;
+const LEG_SIZE_HALF_X: f32 = 0.07;
+const LEG_SIZE_HALF_Y: f32 = 0.27;
+const LANDER_REST_HEIGHT: f32 = LEG_DOWN + LEG_SIZE_HALF_Y; // center above the ground on its legs
+
+// main in 0..1, side in -1..1 where negative steers left
+fn fire_engines(world: &mut World, instance: &Instance, main: f32, side: f32) {
+    let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+    let config = world.get_resource::<LunarLanderConfig>().unwrap().clone();
+    let mut landers = world.query::<(&Instance, &mut Lander, &RigidBodyPosition, &mut RigidBodyVelocity)>();
+    for (_, mut lander, rb_pos, mut rb_vel) in landers.iter_mut(world).filter(|(i, ..)| i.index == instance.index) {
+        let angle = rb_pos.position.rotation.angle();
+        let up = Vec2::new(-angle.sin(), angle.cos());
+        let right = Vec2::new(angle.cos(), angle.sin());
+
+        if main > 0.0 || side != 0.0 {
+            let dv = up * main * config.main_engine_accel * dt + right * side * config.side_engine_accel * dt;
+            rb_vel.linvel += Vector::from(dv);
+            rb_vel.angvel -= side * config.side_engine_spin * dt;
+        }
+        lander.main_power = main;
+        lander.side_power = side.abs();
+    }
+}
+
+// Mark leg contacts and crashes, only touching the ground counts
+// This causes an additional 1-frame-lag, same as breakout
+fn lander_contacts(
+    mut contact_events: EventReader<ContactEvent>,
+    ground: Query<&Ground>,
+    mut legs: Query<&mut Leg>,
+    mut landers: Query<&mut Lander>,
+) {
+    for contact_event in contact_events.iter() {
+        let (h1, h2, started) = match contact_event {
+            ContactEvent::Started(h1, h2) => (h1, h2, true),
+            ContactEvent::Stopped(h1, h2) => (h1, h2, false),
+        };
+        let other = if ground.get(h1.entity()).is_ok() {
+            h2.entity()
+        } else if ground.get(h2.entity()).is_ok() {
+            h1.entity()
+        } else {
+            continue;
+        };
+        if let Ok(mut leg) = legs.get_mut(other) {
+            leg.contact = started;
+        }
+        if let Ok(mut lander) = landers.get_mut(other) {
+            lander.crashed |= started;
+        }
+    }
+}
+
+fn keyboard_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    environment: Res<Environment>,
+    mut env_state: ResMut<EnvironmentState>,
+) {
+    let main = keyboard_input.pressed(KeyCode::W);
+    let left = keyboard_input.pressed(KeyCode::A);
+    let right = keyboard_input.pressed(KeyCode::D);
+
+    env_state.action = if matches!(environment.action_space, Space::Box { .. }) {
+        let side = if left { -1.0 } else if right { 1.0 } else { 0.0 };
+        Some(Action::Box(vec![if main { 1.0 } else { -1.0 }, side]))
+    } else if main {
+        Some(Action::Discrete(2))
+    } else if left {
+        Some(Action::Discrete(1))
+    } else if right {
+        Some(Action::Discrete(3))
+    } else {
+        Some(Action::Discrete(0))
+    };
+}
+
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 100.0));
+    commands.spawn_bundle(camera);
+}
+
+// Random hills with a flat pad in the middle, smoothed like gym's
+fn spawn_terrain(commands: &mut Commands, instance: &Instance, rng: &mut Pcg64) {
+    let mut heights: Vec<f32> = (0..=TERRAIN_CHUNKS)
+        .map(|_| rng.gen_range(-VIEWPORT_H_HALF..0.0))
+        .collect();
+    let middle = TERRAIN_CHUNKS / 2;
+    for height in heights[middle - 2..=middle + 2].iter_mut() {
+        *height = HELIPAD_Y;
+    }
+
+    let chunk_width = VIEWPORT_W_HALF * 2.0 / (TERRAIN_CHUNKS - 1) as f32;
+    let vertices: Vec<Point<Real>> = (0..TERRAIN_CHUNKS)
+        .map(|i| {
+            let before = heights[if i == 0 { 0 } else { i - 1 }];
+            let y = (before + heights[i] + heights[i + 1]) / 3.0;
+            Vec2::new(-VIEWPORT_W_HALF + i as f32 * chunk_width, y).into()
+        })
+        .collect();
+
+    commands
+        .spawn_bundle(RigidBodyBundle {
+            position: instance.origin.into(),
+            body_type: RigidBodyType::Static,
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            shape: ColliderShape::polyline(vertices, None),
+            material: ColliderMaterial {
+                friction: 0.1,
+                ..Default::default()
+            },
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(Color::BLACK))
+        .insert(Ground)
+        .insert(LunarLanderClean)
+        .insert(*instance);
+
+    // Flags at each end of the pad
+    for side in [-1.0, 1.0].iter() {
+        commands
+            .spawn_bundle(RigidBodyBundle {
+                position: (instance.origin + Vec2::new(side * chunk_width, HELIPAD_Y + 0.5)).into(),
+                body_type: RigidBodyType::Static,
+                ..Default::default()
+            })
+            .insert_bundle(ColliderBundle {
+                shape: ColliderShape::cuboid(0.03, 0.5),
+                collider_type: ColliderType::Sensor,
+                flags: ColliderFlags {
+                    collision_groups: instance.collision_groups(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .insert(ColliderPositionSync::Discrete)
+            .insert(ColliderDebugRender::from(Color::YELLOW))
+            .insert(Flag)
+            .insert(LunarLanderClean)
+            .insert(*instance);
+    }
+}
+
+// Starts at the top with a random push, legs are held on by fixed joints
+fn spawn_lander(commands: &mut Commands, instance: &Instance, config: &LunarLanderConfig, rng: &mut Pcg64) {
+    let start = instance.origin + Vec2::new(0.0, VIEWPORT_H_HALF - 0.5);
+    // gym's lander polygon, in pixels
+    let hull: Vec<Point<Real>> = [(-14.0, 17.0), (-17.0, 0.0), (-17.0, -10.0), (17.0, -10.0), (17.0, 0.0), (14.0, 17.0)]
+        .iter()
+        .map(|(x, y)| (Vec2::new(*x, *y) / RAPIER_SCALE).into())
+        .collect();
+
+    let lander = commands
+        .spawn_bundle(RigidBodyBundle {
+            position: start.into(),
+            velocity: RigidBodyVelocity {
+                linvel: Vec2::new(
+                    rng.gen_range(-config.initial_vel_limit..config.initial_vel_limit),
+                    rng.gen_range(-config.initial_vel_limit..config.initial_vel_limit),
+                )
+                .into(),
+                angvel: 0.0,
+            },
+            activation: RigidBodyActivation::cannot_sleep(),
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            shape: ColliderShape::convex_hull(&hull).unwrap(),
+            material: ColliderMaterial {
+                density: 5.0,
+                friction: 0.1,
+                ..Default::default()
+            },
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                active_events: ActiveEvents::CONTACT_EVENTS,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(Color::PURPLE))
+        .insert(Lander {
+            steps: 0,
+            crashed: false,
+            main_power: 0.0,
+            side_power: 0.0,
+            prev_shaping: None,
+        })
+        .insert(LunarLanderClean)
+        .insert(*instance)
+        .id();
+
+    for side in [-1.0, 1.0].iter() {
+        // Leg center relative to the lander, angled out so the feet are wider than the body
+        let mount = Vec2::new(side * LEG_AWAY, -LEG_DOWN);
+        let angle = side * LEG_ANGLE;
+        let leg = commands
+            .spawn_bundle(RigidBodyBundle {
+                position: Isometry::new((start + mount).into(), angle).into(),
+                activation: RigidBodyActivation::cannot_sleep(),
+                ..Default::default()
+            })
+            .insert_bundle(ColliderBundle {
+                shape: ColliderShape::cuboid(LEG_SIZE_HALF_X, LEG_SIZE_HALF_Y),
+                material: ColliderMaterial {
+                    friction: 0.5,
+                    ..Default::default()
+                },
+                flags: ColliderFlags {
+                    collision_groups: instance.collision_groups(),
+                    active_events: ActiveEvents::CONTACT_EVENTS,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .insert(ColliderPositionSync::Discrete)
+            .insert(ColliderDebugRender::from(Color::GRAY))
+            .insert(Leg {
+                side: *side,
+                contact: false,
+            })
+            .insert(LunarLanderClean)
+            .insert(*instance)
+            .id();
+
+        let joint = FixedJoint::new(Isometry::new(mount.into(), angle), Isometry::identity());
+        commands
+            .spawn()
+            .insert(JointBuilderComponent::new(joint, lander, leg))
+            .insert(LunarLanderClean)
+            .insert(*instance);
+    }
+}
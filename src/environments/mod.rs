@@ -1,83 +1,386 @@
 pub mod acrobot;
+pub mod bipedal_walker;
 pub mod cartpole;
+pub mod lunar_lander;
 pub mod mountaincar;
 pub mod pendulum;
+pub mod pong;
+pub mod snake;
 pub mod flappy;
 pub mod breakout;
+pub mod space_invaders;
 
-use std::{fmt, str::FromStr};
+use std::{fmt, path::Path, str::FromStr};
 
-use bevy::prelude::AppBuilder;
+use bevy::prelude::{AppBuilder, Plugin};
+use serde::Serialize;
 
-use self::{acrobot::AcrobotPlugin, breakout::BreakoutPlugin, cartpole::CartPolePlugin, flappy::{FlappyConfig, FlappyPlugin}, mountaincar::MountainCarPlugin, pendulum::PendulumPlugin};
+use crate::{environment::EnvironmentConfig, models::ModelType};
 
+use self::{acrobot::{AcrobotConfig, AcrobotPlugin}, bipedal_walker::{BipedalWalkerConfig, BipedalWalkerPlugin}, breakout::{BreakoutConfig, BreakoutPlugin}, cartpole::{CartPoleConfig, CartPolePlugin}, lunar_lander::{LunarLanderConfig, LunarLanderPlugin}, flappy::{FlappyConfig, FlappyPlugin}, mountaincar::{MountainCarConfig, MountainCarPlugin}, pendulum::{PendulumConfig, PendulumPlugin}, pong::{pong_config, PongPlugin}, snake::{SnakeConfig, SnakeObservation, SnakePlugin}, space_invaders::{SpaceInvadersConfig, SpaceInvadersPlugin}};
 
-#[derive(Copy,Clone,Debug,Eq, PartialEq, Hash)]
-pub enum EnvironmentType {
-    Acrobot,
-    CartPole,
-    MountainCar,
-    Pendulum,
-    Flappy,
-    Breakout
+// Registered environment, everything else enumerates REGISTRY
+pub struct EnvironmentEntry {
+    // Versioned like gym, CartPole-v1, bump it when observations, rewards or rules change
+    pub id: &'static str,
+    pub name: &'static str,
+    pub category: EnvironmentCategory,
+    // Its own params/env/<config_name>.toml, no two entries share one
+    pub config_name: &'static str,
+    // Config the plugin starts from before params/env/<config_name>.toml, see load_env_config
+    pub default_config: fn() -> toml::Value,
+    // Builds the plugin with its default config
+    pub plugin: fn(&EnvironmentConfig) -> Box<dyn Plugin>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum EnvironmentMode {
-    RenderHuman,
-    Render,
-    Simulation,
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EnvironmentCategory {
+    Game,
+    Classical,
+    Box2d,
+}
+
+impl EnvironmentCategory {
+    pub const ALL: [EnvironmentCategory; 3] = [
+        EnvironmentCategory::Game,
+        EnvironmentCategory::Classical,
+        EnvironmentCategory::Box2d,
+    ];
+}
+
+impl fmt::Display for EnvironmentCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EnvironmentCategory::Game => write!(f, "Games"),
+            EnvironmentCategory::Classical => write!(f, "Classical"),
+            EnvironmentCategory::Box2d => write!(f, "Box2D"),
+        }
+    }
+}
+
+impl EnvironmentEntry {
+    // Id without its version, CartPole
+    pub fn base_id(&self) -> &'static str {
+        self.split_id().0
+    }
+
+    pub fn version(&self) -> u32 {
+        self.split_id().1
+    }
+
+    fn split_id(&self) -> (&'static str, u32) {
+        match self.id.rfind("-v") {
+            Some(i) => (&self.id[..i], self.id[i + 2..].parse().unwrap_or(0)),
+            None => (self.id, 0),
+        }
+    }
+}
+
+fn to_toml<T: Serialize>(config: T) -> toml::Value {
+    toml::Value::try_from(config).unwrap()
+}
+
+// Neat drives the environment itself, see each plugin
+fn neat(config: &EnvironmentConfig) -> bool {
+    !config.human && matches!(config.model, ModelType::Neat)
 }
 
+pub static REGISTRY: &[EnvironmentEntry] = &[
+    EnvironmentEntry {
+        id: "Acrobot-v1",
+        name: "Acrobot",
+        category: EnvironmentCategory::Classical,
+        config_name: "acrobot",
+        default_config: || to_toml(AcrobotConfig::default()),
+        plugin: |config| {
+            Box::new(AcrobotPlugin {
+                render: config.render,
+                human: config.human,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "CartPole-v1",
+        name: "Cart Pole",
+        category: EnvironmentCategory::Classical,
+        config_name: "cartpole",
+        default_config: || to_toml(CartPoleConfig::default()),
+        plugin: |config| {
+            Box::new(CartPolePlugin {
+                human: config.human,
+                render: config.render,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "MountainCar-v1",
+        name: "Mountain Car",
+        category: EnvironmentCategory::Classical,
+        config_name: "mountain_car",
+        default_config: || to_toml(MountainCarConfig::default()),
+        plugin: |config| {
+            Box::new(MountainCarPlugin {
+                render: config.render,
+                human: config.human,
+                continuous: false,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "MountainCarContinuous-v1",
+        name: "Mountain Car Continuous",
+        category: EnvironmentCategory::Classical,
+        config_name: "mountain_car_continuous",
+        default_config: || to_toml(MountainCarConfig::default()),
+        plugin: |config| {
+            Box::new(MountainCarPlugin {
+                render: config.render,
+                human: config.human,
+                continuous: true,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "Pendulum-v1",
+        name: "Pendulum",
+        category: EnvironmentCategory::Classical,
+        config_name: "pendulum",
+        default_config: || to_toml(PendulumConfig::default()),
+        plugin: |config| {
+            Box::new(PendulumPlugin {
+                render: config.render,
+                human: config.human,
+                continuous: false,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "PendulumContinuous-v1",
+        name: "Pendulum Continuous",
+        category: EnvironmentCategory::Classical,
+        config_name: "pendulum_continuous",
+        default_config: || to_toml(PendulumConfig::default()),
+        plugin: |config| {
+            Box::new(PendulumPlugin {
+                render: config.render,
+                human: config.human,
+                continuous: true,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "BipedalWalker-v1",
+        name: "Bipedal Walker",
+        category: EnvironmentCategory::Box2d,
+        config_name: "bipedal_walker",
+        default_config: || to_toml(BipedalWalkerConfig::default()),
+        plugin: |config| {
+            Box::new(BipedalWalkerPlugin {
+                render: config.render,
+                human: config.human,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "LunarLander-v1",
+        name: "Lunar Lander",
+        category: EnvironmentCategory::Box2d,
+        config_name: "lunar_lander",
+        default_config: || to_toml(LunarLanderConfig::default()),
+        plugin: |config| {
+            Box::new(LunarLanderPlugin {
+                render: config.render,
+                human: config.human,
+                continuous: false,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "LunarLanderContinuous-v1",
+        name: "Lunar Lander Continuous",
+        category: EnvironmentCategory::Box2d,
+        config_name: "lunar_lander_continuous",
+        default_config: || to_toml(LunarLanderConfig::default()),
+        plugin: |config| {
+            Box::new(LunarLanderPlugin {
+                render: config.render,
+                human: config.human,
+                continuous: true,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "Flappy-v1",
+        name: "Flappy",
+        category: EnvironmentCategory::Game,
+        config_name: "flappy",
+        default_config: || to_toml(FlappyConfig::default()),
+        plugin: |config| {
+            Box::new(FlappyPlugin {
+                render: config.render,
+                human: config.human,
+                neat: neat(config),
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "Breakout-v1",
+        name: "Breakout",
+        category: EnvironmentCategory::Game,
+        config_name: "breakout",
+        default_config: || to_toml(BreakoutConfig::default()),
+        plugin: |config| {
+            Box::new(BreakoutPlugin {
+                render: config.render,
+                human: config.human,
+                neat: neat(config),
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "SpaceInvaders-v1",
+        name: "Space Invaders",
+        category: EnvironmentCategory::Game,
+        config_name: "space_invaders",
+        default_config: || to_toml(SpaceInvadersConfig::default()),
+        plugin: |config| {
+            Box::new(SpaceInvadersPlugin {
+                render: config.render,
+                human: config.human,
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "Snake-v1",
+        name: "Snake",
+        category: EnvironmentCategory::Game,
+        config_name: "snake",
+        default_config: || to_toml(SnakeConfig::default()),
+        plugin: |config| {
+            Box::new(SnakePlugin {
+                render: config.render,
+                human: config.human,
+                config: SnakeConfig::default(),
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "SnakeGrid-v1",
+        name: "Snake Grid",
+        category: EnvironmentCategory::Game,
+        config_name: "snake_grid",
+        default_config: || {
+            to_toml(SnakeConfig {
+                observation: SnakeObservation::Grid,
+                ..Default::default()
+            })
+        },
+        plugin: |config| {
+            Box::new(SnakePlugin {
+                render: config.render,
+                human: config.human,
+                config: SnakeConfig {
+                    observation: SnakeObservation::Grid,
+                    ..Default::default()
+                },
+            })
+        },
+    },
+    EnvironmentEntry {
+        id: "Pong-v1",
+        name: "Pong",
+        category: EnvironmentCategory::Game,
+        config_name: "pong",
+        default_config: || to_toml(pong_config()),
+        plugin: |config| {
+            Box::new(PongPlugin {
+                render: config.render,
+                human: config.human,
+            })
+        },
+    },
+];
 
+// A registered environment, by id
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct EnvironmentType(&'static str);
+
+#[allow(dead_code)]
+impl EnvironmentType {
+    pub fn all() -> impl Iterator<Item = EnvironmentType> {
+        REGISTRY.iter().map(|entry| EnvironmentType(entry.id))
+    }
+
+    pub fn id(&self) -> &'static str {
+        self.0
+    }
+
+    pub fn entry(&self) -> &'static EnvironmentEntry {
+        REGISTRY.iter().find(|entry| entry.id == self.0).unwrap()
+    }
+
+    // What --environment accepts, each id with and without its version
+    pub fn possible_values() -> Vec<&'static str> {
+        let mut values: Vec<&'static str> = REGISTRY.iter().map(|entry| entry.id).collect();
+        for entry in REGISTRY {
+            if !values.contains(&entry.base_id()) {
+                values.push(entry.base_id());
+            }
+        }
+        values
+    }
+}
+
+// Ids are case insensitive, without a version the latest is picked, so cartpole is CartPole-v1
 impl FromStr for EnvironmentType {
-    type Err = &'static str;
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "acrobot" => Ok(EnvironmentType::Acrobot),
-            "cartpole" => Ok(EnvironmentType::CartPole),
-            "mountaincar" => Ok(EnvironmentType::MountainCar),
-            "pendulum" => Ok(EnvironmentType::Pendulum),
-             "flappy" => Ok(EnvironmentType::Flappy),
-            "breakout" => Ok(EnvironmentType::Breakout),
-            _ => Err("No environment match!"),
-        }
+        let s = s.to_lowercase();
+        REGISTRY
+            .iter()
+            .filter(|entry| entry.id.to_lowercase() == s || entry.base_id().to_lowercase() == s)
+            .max_by_key(|entry| entry.version())
+            .map(|entry| EnvironmentType(entry.id))
+            .ok_or_else(|| {
+                let ids: Vec<&str> = REGISTRY.iter().map(|entry| entry.id).collect();
+                format!("No environment match, one of {}", ids.join(", "))
+            })
     }
 }
 
 impl fmt::Display for EnvironmentType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            EnvironmentType::Acrobot => write!(f, "Acrobot"),
-            EnvironmentType::CartPole => write!(f, "Cart Pole"),
-            EnvironmentType::MountainCar => write!(f, "Mountain Car"),
-            EnvironmentType::Pendulum => write!(f, "Pendulum"),
-            EnvironmentType::Flappy => write!(f, "Flappy"),
-            EnvironmentType::Breakout => write!(f, "Breakout"),
+        write!(f, "{}", self.entry().name)
+    }
+}
+
+pub fn load_environment(app: &mut AppBuilder, env: EnvironmentType, mut config: EnvironmentConfig) {
+    if config.env_config.is_none() {
+        let path = Path::new("params").join("env").join(format!("{}.toml", env.entry().config_name));
+        if path.exists() {
+            config.env_config = Some(path);
         }
     }
+    let plugin = (env.entry().plugin)(&config);
+    app.insert_resource(config);
+    plugin.build(app);
 }
 
-pub fn load_environment(app: &mut AppBuilder, env: EnvironmentType, render: bool, human: bool) {
-    match env {
-        EnvironmentType::Acrobot => app.add_plugin(AcrobotPlugin { render: render }),
-        EnvironmentType::CartPole => app.add_plugin(CartPolePlugin {
-            human: human,
-            render: render,
-        }),
-        EnvironmentType::MountainCar => app.add_plugin(MountainCarPlugin { render: render }),
-        EnvironmentType::Pendulum => app.add_plugin(PendulumPlugin { render: render }),
-        EnvironmentType::Flappy => app.add_plugin(FlappyPlugin {
-            config: FlappyConfig {
-                render: render,
-                human: human,
-            },
-        }),
-        EnvironmentType::Breakout => app.add_plugin(BreakoutPlugin {
-            render: render,
-            human: human,
-        }),
-    };
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_ids_parse_and_have_configs() {
+        for entry in REGISTRY {
+            assert_eq!(REGISTRY.iter().filter(|other| other.id == entry.id).count(), 1);
+            assert_eq!(REGISTRY.iter().filter(|other| other.config_name == entry.config_name).count(), 1);
+            assert_eq!(entry.id.to_lowercase().parse::<EnvironmentType>().unwrap().id(), entry.id);
+            assert!(EnvironmentType::possible_values().contains(&entry.base_id()));
+            assert!((entry.default_config)().is_table(), "{}", entry.id);
+        }
+        assert_eq!("cartpole".parse::<EnvironmentType>().unwrap().id(), "CartPole-v1");
+        assert!("cartpole-v9".parse::<EnvironmentType>().is_err());
+    }
+}
@@ -1,27 +1,193 @@
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
-use crate::environment::*;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+use crate::{environment::*, space::*};
 
 pub struct MountainCarPlugin {
     pub render: bool,
+    pub human: bool,
+    pub continuous: bool,
+}
+
+// Same as gym's MountainCarContinuous, throttle in -1..1 scaled by action_torque
+pub struct MountainCarContinuous;
+
+// Physics and rules, see load_env_config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountainCarConfig {
+    pub ground_width_half: f32, // assuming at origin
+    pub ground_height: f32,     // ground is sin(x) * ground_height
+    pub action_torque: f32,
+    pub max_steps: usize,
+    pub start_x: f32, // bottom of the valley
+    pub start_x_range: f32,
+    pub goal_x: f32, // top of the right hill
+    pub chassis_size_half: (f32, f32),
+    pub wheel_radius: f32,
+}
+
+impl Default for MountainCarConfig {
+    fn default() -> Self {
+        Self {
+            ground_width_half: 10.0,
+            ground_height: 2.0,
+            action_torque: 5.0,
+            max_steps: 500,
+            start_x: -FRAC_PI_2,
+            start_x_range: 0.3,
+            goal_x: FRAC_PI_2,
+            chassis_size_half: (0.5, 0.15),
+            wheel_radius: 0.2,
+        }
+    }
 }
 // Makers
-struct Cart;
+struct Cart {
+    steps: usize, // steps taken this episode
+}
+struct Wheel;
 struct Ground;
+struct Flag;
+struct MountainCarClean;
 
 impl Plugin for MountainCarPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        insert_env_resources(app, 2, 4);
-
-        app.add_startup_system(setup_physics.system());
+        let config = load_env_config(app.world(), MountainCarConfig::default());
+        app.insert_resource(config);
+        if self.continuous {
+            add_env_systems::<MountainCarContinuous>(app);
+        } else {
+            add_env_systems::<MountainCarPlugin>(app);
+        }
 
         if self.render {
-            app.add_startup_system(setup_graphics.system())
-                .add_system(keyboard_input.system());
+            app.add_startup_system(setup_graphics.system());
+        }
+        if self.human && self.render {
+            app.add_system(keyboard_input.system());
+            println!("Keys: A and D");
         }
     }
 }
 
+impl Env for MountainCarPlugin {
+    fn environment(world: &World) -> Environment {
+        let ground_width_half = world.get_resource::<MountainCarConfig>().unwrap().ground_width_half;
+        Environment {
+            action_space: Space::Discrete(2),
+            observation_space: Space::boxed(
+                vec![-ground_width_half, f32::NEG_INFINITY],
+                vec![ground_width_half, f32::INFINITY],
+            ),
+        }
+    }
+
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, 15.0)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        // Scaling up, see https://rapier.rs/docs/user_guides/bevy_plugin/common_mistakes/#why-is-everything-moving-in-slow-motion
+        world.get_resource_mut::<RapierConfiguration>().unwrap().scale = 50.0;
+        despawn_instance::<MountainCarClean>(world, instance);
+        world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+            run_commands(world, |commands, world| {
+                let config = world.get_resource::<MountainCarConfig>().unwrap();
+                setup_physics(commands, instance, config, &mut rng.0)
+            });
+        });
+        observe_cart(world, instance).observation
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let throttle = match action.discrete() {
+            0 => -1.0,
+            1 => 1.0,
+            _ => panic!("action invalid: {:?}", action),
+        };
+        drive(world, instance, throttle);
+    }
+
+    // Counts the step, done at the flag, truncated after max_steps
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let mut carts = world.query::<(&Instance, &mut Cart)>();
+        for (_, mut cart) in carts.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            cart.steps += 1;
+        }
+        observe_cart(world, instance)
+    }
+}
+
+impl Env for MountainCarContinuous {
+    fn environment(world: &World) -> Environment {
+        Environment {
+            action_space: Space::boxed(vec![-1.0], vec![1.0]),
+            ..MountainCarPlugin::environment(world)
+        }
+    }
+
+    fn instance_offset(world: &World) -> Vec2 {
+        MountainCarPlugin::instance_offset(world)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        MountainCarPlugin::reset(world, instance)
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let throttle = match action {
+            Action::Box(values) => values[0].clamp(-1.0, 1.0),
+            _ => panic!("action invalid: {:?}", action),
+        };
+        drive(world, instance, throttle);
+    }
+
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        MountainCarPlugin::observe(world, instance)
+    }
+}
+
+
+// Turn the wheels, negative throttle rolls left
+fn drive(world: &mut World, instance: &Instance, throttle: f32) {
+    let action_torque = world.get_resource::<MountainCarConfig>().unwrap().action_torque;
+    let mut wheels = world.query_filtered::<(&Instance, &mut RigidBodyForces), With<Wheel>>();
+    for (_, mut rb_f) in wheels.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+        // clockwise rolls right
+        rb_f.torque = -throttle * action_torque;
+    }
+}
+
+// The step as it stands, without counting it
+fn observe_cart(world: &mut World, instance: &Instance) -> Step {
+    let config = world.get_resource::<MountainCarConfig>().unwrap().clone();
+    let mut cart_pos_x = 0.0;
+    let mut cart_vel = 0.0;
+    let mut steps = 0;
+    let mut carts = world.query::<(&Instance, &Cart, &RigidBodyPosition, &RigidBodyVelocity)>();
+    for (_, cart, rb_pos, rb_vel) in carts.iter(world).filter(|(i, ..)| i.index == instance.index) {
+        cart_pos_x = rb_pos.position.translation.x - instance.origin.x;
+        cart_vel = rb_vel.linvel[0];
+        steps = cart.steps;
+    }
+
+    let mut step = Step {
+        observation: vec![cart_pos_x, cart_vel],
+        reward: -1.0,
+        is_done: cart_pos_x >= config.goal_x,
+        ..Default::default()
+    };
+    if !step.is_done && steps >= config.max_steps {
+        step.is_done = true;
+        step.info.insert("truncated", 1.0);
+    }
+    step
+}
+
 fn setup_graphics(mut commands: Commands) {
     let mut camera = OrthographicCameraBundle::new_2d();
     camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 100.0));
@@ -29,40 +195,31 @@ fn setup_graphics(mut commands: Commands) {
 }
 
 fn keyboard_input(
-    mut rigid_bodies: Query<&mut RigidBodyForces, With<Cart>>,
     keyboard_input: Res<Input<KeyCode>>,
+    environment: Res<Environment>,
+    mut env_state: ResMut<EnvironmentState>,
 ) {
-    for mut rb_forces in rigid_bodies.iter_mut() {
-        if keyboard_input.pressed(KeyCode::A) {
-            rb_forces.force = Vec2::new(-10.0, 0.0).into();
-        }
-        if keyboard_input.pressed(KeyCode::D) {
-            rb_forces.force = Vec2::new(10.0, 0.0).into();
-        }
-    }
+    let continuous = matches!(environment.action_space, Space::Box { .. });
+    env_state.action = if keyboard_input.pressed(KeyCode::A) {
+        Some(if continuous { Action::Box(vec![-1.0]) } else { Action::Discrete(0) })
+    } else if keyboard_input.pressed(KeyCode::D) {
+        Some(if continuous { Action::Box(vec![1.0]) } else { Action::Discrete(1) })
+    } else {
+        None
+    };
 }
 
-fn setup_physics(
-    mut commands: Commands,
-    mut rapier_config: ResMut<RapierConfiguration>,
-    mut _meshes: ResMut<Assets<Mesh>>,
-    mut _materials: ResMut<Assets<StandardMaterial>>,
-) {
-    // Scaling up, see https://rapier.rs/docs/user_guides/bevy_plugin/common_mistakes/#why-is-everything-moving-in-slow-motion
-    rapier_config.scale = 50.0;
-
-
+fn setup_physics(commands: &mut Commands, instance: &Instance, config: &MountainCarConfig, rng: &mut Pcg64) {
     // To create the ground from list of points following sin curve
-    let ground_width = 20.0f32; // assuming at origin
     let ground_resolution = 0.2; // Smoothness, space between vertices
 
     // uses to change shape of sin curve, changing x will effect resolution
-    let ground_scale = Vec3::new(1.0, 2.0, 1.0); 
+    let ground_scale = Vec3::new(1.0, config.ground_height, 1.0);
 
-    let half_segments = (( ground_width * 0.5) / ground_resolution) as i8;
+    let half_segments = (config.ground_width_half / ground_resolution) as i32;
     let mut vertices: Vec<Point<Real>> = Vec::new();
     for i in -half_segments..=half_segments {
-        let i = f32::from(i) * ground_resolution;
+        let i = i as f32 * ground_resolution;
         let x = i * ground_scale.x;
         let y = x.sin() * ground_scale.y;
         vertices.push(Vec2::new(x, y).into());
@@ -70,39 +227,109 @@ fn setup_physics(
 
     commands
         .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(0.0, 0.0).into(),
+            position: instance.origin.into(),
             body_type: RigidBodyType::Static,
             ..Default::default()
         })
         .insert_bundle(ColliderBundle {
             shape: ColliderShape::polyline(vertices, None),
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
             ..Default::default()
         })
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(Color::BLACK))
         .insert(Ground)
+        .insert(MountainCarClean)
+        .insert(*instance)
         .id();
 
 
-    let cart_size = Vec2::new(0.2, 1.0);
+    // Goal flag on the right hill
+    let flag_base = Vec2::new(config.goal_x, config.goal_x.sin() * config.ground_height);
     commands
         .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(0.0, 10.0).into(),
+            position: (instance.origin + flag_base + Vec2::new(0.0, 0.5)).into(),
+            body_type: RigidBodyType::Static,
             ..Default::default()
         })
         .insert_bundle(ColliderBundle {
-            shape: ColliderShape::cuboid(cart_size.x, cart_size.y),
-            material: ColliderMaterial {
-                restitution: 0.7,
+            shape: ColliderShape::cuboid(0.03, 0.5),
+            collider_type: ColliderType::Sensor,
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(Color::GREEN))
+        .insert(Flag)
+        .insert(MountainCarClean)
+        .insert(*instance);
+
+    // Car, a chassis with a wheel joined at each end, starts at rest somewhere in the valley
+    let start_x = config.start_x + rng.gen_range(-config.start_x_range..config.start_x_range);
+    let start = instance.origin
+        + Vec2::new(
+            start_x,
+            start_x.sin() * config.ground_height + config.wheel_radius * 2.0 + config.chassis_size_half.1,
+        );
+    let chassis = commands
+        .spawn_bundle(RigidBodyBundle {
+            position: start.into(),
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            shape: ColliderShape::cuboid(config.chassis_size_half.0, config.chassis_size_half.1),
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
                 ..Default::default()
             },
             ..Default::default()
         })
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(Color::GRAY))
-        .insert(Cart)
+        .insert(Cart { steps: 0 })
+        .insert(MountainCarClean)
+        .insert(*instance)
         .id();
 
-    // TODO: Figure out rollings the cart
-}
+    for side in [-1.0, 1.0].iter() {
+        // Wheel center relative to the chassis
+        let axle = Vec2::new(side * config.chassis_size_half.0 * 0.8, -config.chassis_size_half.1 - config.wheel_radius);
+        let wheel = commands
+            .spawn_bundle(RigidBodyBundle {
+                position: (start + axle).into(),
+                ..Default::default()
+            })
+            .insert_bundle(ColliderBundle {
+                shape: ColliderShape::ball(config.wheel_radius),
+                material: ColliderMaterial {
+                    friction: 1.0,
+                    ..Default::default()
+                },
+                flags: ColliderFlags {
+                    collision_groups: instance.collision_groups(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .insert(ColliderPositionSync::Discrete)
+            .insert(ColliderDebugRender::from(Color::BLACK))
+            .insert(Wheel)
+            .insert(MountainCarClean)
+            .insert(*instance)
+            .id();
 
+        // Wheels spin freely around their center
+        let joint = BallJoint::new(axle.into(), Vec2::ZERO.into());
+        commands
+            .spawn()
+            .insert(JointBuilderComponent::new(joint, chassis, wheel))
+            .insert(MountainCarClean)
+            .insert(*instance);
+    }
+}
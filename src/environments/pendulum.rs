@@ -1,135 +1,265 @@
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
 
-use crate::environment::*;
+use crate::{environment::*, space::*};
 
+// Classic swing-up, starts at a random angle and has to be brought upright and held there
 #[derive(Debug)]
 pub struct PendulumPlugin {
     pub render: bool,
+    pub human: bool,
+    pub continuous: bool,
+}
+
+// Same pendulum with a continuous action, torque in -1..1 scaled by action_torque
+pub struct PendulumContinuous;
 
+// Physics and rules, see load_env_config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PendulumConfig {
+    pub action_torque: f32,
+    pub max_steps: usize,
+    pub init_angvel_limit: f32,
+    pub link_size_half: (f32, f32),
 }
 
-enum PendulumState {
-    Playing,
-    Reset,
+impl Default for PendulumConfig {
+    fn default() -> Self {
+        Self {
+            action_torque: 3000.0,
+            max_steps: 200,
+            init_angvel_limit: 1.0,
+            link_size_half: (1.0, 10.0),
+        }
+    }
 }
 
 impl Plugin for PendulumPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        insert_env_resources(app, 2, 2);
-
-        app.add_system_set( SystemSet::on_in_stack_update(s))
-            .add_startup_system(setup_environment.system())
-            .add_system_to_stage(CoreStage::PreUpdate, update_state.system())
-            .add_system_to_stage(CoreStage::PostUpdate, take_action.system());
+        let config = load_env_config(app.world(), PendulumConfig::default());
+        app.insert_resource(config);
+        if self.continuous {
+            add_env_systems::<PendulumContinuous>(app);
+        } else {
+            add_env_systems::<PendulumPlugin>(app);
+        }
 
         if self.render {
-            app.add_system_to_stage(CoreStage::Update, keyboard_input.system());
+            app.add_startup_system(setup_camera.system());
+        }
+        if self.human && self.render {
+            app.add_system(keyboard_input.system());
+            println!("Keys: A and D");
         }
     }
 }
 
-const RAPIER_SCALE: f32 = 50.0;
-const ACTION_FORCE: f32 = 3000.0;
-const LINK_SIZE_HALF_X: f32 = 1.0;
-const LINK_SIZE_HALF_Y: f32 = 10.0;
+impl Env for PendulumPlugin {
+    fn environment(_: &World) -> Environment {
+        // cos θ, sin θ and θ̇, θ is 0 upright
+        Environment {
+            action_space: Space::Discrete(2),
+            observation_space: Space::boxed(
+                vec![-1.0, -1.0, f32::NEG_INFINITY],
+                vec![1.0, 1.0, f32::INFINITY],
+            ),
+        }
+    }
+
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, 45.0)
+    }
 
-fn keyboard_input(keyboard_input: Res<Input<KeyCode>>, mut env_state: ResMut<EnvironmentState>) {
-    if keyboard_input.pressed(KeyCode::A) {
-        env_state.action = Some(0);
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        world.get_resource_mut::<RapierConfiguration>().unwrap().scale = RAPIER_SCALE;
+        despawn_instance::<PendulumClean>(world, instance);
+        world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+            run_commands(world, |commands, world| {
+                let config = world.get_resource::<PendulumConfig>().unwrap();
+                spawn_environment(commands, instance, config, &mut rng.0)
+            });
+        });
+        observe_link(world, instance).observation
     }
-    if keyboard_input.pressed(KeyCode::D) {
-        env_state.action = Some(1);
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let throttle = match action.discrete() {
+            0 => -1.0,
+            1 => 1.0,
+            _ => panic!("action invalid: {:?}", action),
+        };
+        apply_torque(world, instance, throttle);
+    }
+
+    // Counts the step, an action only lasts for the step it was taken in
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let mut links = world.query::<(&Instance, &mut Link)>();
+        for (_, mut link) in links.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            link.steps += 1;
+        }
+        let step = observe_link(world, instance);
+        apply_torque(world, instance, 0.0);
+        step
     }
 }
 
-// Makers
-struct Link;
+impl Env for PendulumContinuous {
+    fn environment(world: &World) -> Environment {
+        Environment {
+            action_space: Space::boxed(vec![-1.0], vec![1.0]),
+            ..PendulumPlugin::environment(world)
+        }
+    }
 
-fn setup_environment(
-    mut commands: Commands,
-    mut rapier_config: ResMut<RapierConfiguration>,
-    config: Res<EnvironmentConfig>,
-) {
-    rapier_config.scale = RAPIER_SCALE;
+    fn instance_offset(world: &World) -> Vec2 {
+        PendulumPlugin::instance_offset(world)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        PendulumPlugin::reset(world, instance)
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let throttle = match action {
+            Action::Box(values) => values[0].clamp(-1.0, 1.0),
+            _ => panic!("action invalid: {:?}", action),
+        };
+        apply_torque(world, instance, throttle);
+    }
+
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        PendulumPlugin::observe(world, instance)
+    }
+}
+
+const RAPIER_SCALE: f32 = 50.0;
+const GYM_MAX_TORQUE: f32 = 2.0; // throttle is scaled by this for the action cost, as in gym
 
-    if config.render {
-        let mut camera = OrthographicCameraBundle::new_2d();
-        camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 100.0));
-        commands.spawn_bundle(camera);
+// Wrap into -π..π
+fn normalize_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+fn apply_torque(world: &mut World, instance: &Instance, throttle: f32) {
+    let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+    let action_torque = world.get_resource::<PendulumConfig>().unwrap().action_torque;
+    let mut links = world.query::<(&Instance, &mut Link, &mut RigidBodyForces)>();
+    for (_, mut link, mut rb_f) in links.iter_mut(world).filter(|(i, ..)| i.index == instance.index) {
+        rb_f.torque = throttle * action_torque * dt;
+        link.throttle = throttle;
+    }
+}
+
+// Reward is gym's -(θ² + 0.1θ̇² + 0.001u²), truncated after max_steps, without counting the step
+fn observe_link(world: &mut World, instance: &Instance) -> Step {
+    let max_steps = world.get_resource::<PendulumConfig>().unwrap().max_steps;
+    let mut step = Step::default();
+    let mut links = world.query::<(&Instance, &Link, &RigidBodyPosition, &RigidBodyVelocity)>();
+    for (_, link, rb_pos, rb_vel) in links.iter(world).filter(|(i, ..)| i.index == instance.index) {
+        // Spawned hanging down, so upright is half a turn away
+        let theta = normalize_angle(rb_pos.position.rotation.angle() + PI);
+        let theta_vel = rb_vel.angvel;
+        let u = link.throttle * GYM_MAX_TORQUE;
+
+        step.observation = vec![theta.cos(), theta.sin(), theta_vel];
+        step.reward = -(theta * theta + 0.1 * theta_vel * theta_vel + 0.001 * u * u);
+        if link.steps >= max_steps {
+            // Never terminal, only the step limit ends it
+            step.is_done = true;
+            step.info.insert("truncated", 1.0);
+        }
     }
+    step
+}
+
+fn keyboard_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    environment: Res<Environment>,
+    mut env_state: ResMut<EnvironmentState>,
+) {
+    let continuous = matches!(environment.action_space, Space::Box { .. });
+    env_state.action = if keyboard_input.pressed(KeyCode::A) {
+        Some(if continuous { Action::Box(vec![-1.0]) } else { Action::Discrete(0) })
+    } else if keyboard_input.pressed(KeyCode::D) {
+        Some(if continuous { Action::Box(vec![1.0]) } else { Action::Discrete(1) })
+    } else {
+        None
+    };
+}
+
+// Makers
+struct Link {
+    throttle: f32, // this step's action, for the reward, 0 when there was none
+    steps: usize,
+}
+struct PendulumClean;
+
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 100.0));
+    commands.spawn_bundle(camera);
+}
 
+fn spawn_environment(commands: &mut Commands, instance: &Instance, config: &PendulumConfig, rng: &mut Pcg64) {
     let anchor = commands
         .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(0.0, 0.0).into(),
+            position: instance.origin.into(),
             body_type: RigidBodyType::Static,
             ..Default::default()
         })
         .insert_bundle(ColliderBundle {
-            shape: ColliderShape::ball(LINK_SIZE_HALF_X),
+            shape: ColliderShape::ball(config.link_size_half.0),
             collider_type: ColliderType::Sensor,
             ..Default::default()
         })
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(Color::YELLOW))
+        .insert(PendulumClean)
+        .insert(*instance)
         .id();
 
+    // Random angle and spin, the link center swings around the anchor
+    let angle = rng.gen_range(-PI..PI);
+    let angvel = rng.gen_range(-config.init_angvel_limit..config.init_angvel_limit);
+    let center = Vec2::new(angle.sin(), -angle.cos()) * config.link_size_half.1;
     let link = commands
         .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(0.0, -LINK_SIZE_HALF_X).into(),
+            position: Isometry::new((instance.origin + center).into(), angle).into(),
+            velocity: RigidBodyVelocity {
+                linvel: Vec2::new(-angvel * center.y, angvel * center.x).into(),
+                angvel: angvel,
+            },
             body_type: RigidBodyType::Dynamic,
             ..Default::default()
         })
         .insert_bundle(ColliderBundle {
-            shape: ColliderShape::cuboid(LINK_SIZE_HALF_X, LINK_SIZE_HALF_Y),
+            shape: ColliderShape::cuboid(config.link_size_half.0, config.link_size_half.1),
             //collider_type: ColliderType::Sensor,
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
             ..Default::default()
         })
         .insert(ColliderPositionSync::Discrete)
         .insert(ColliderDebugRender::from(Color::GRAY))
-        .insert(Link)
+        .insert(Link {
+            throttle: 0.0,
+            steps: 0,
+        })
+        .insert(PendulumClean)
+        .insert(*instance)
         .id();
 
-    let joint = BallJoint::new(Vec2::ZERO.into(), Vec2::new(0.0, LINK_SIZE_HALF_Y).into());
+    let joint = BallJoint::new(Vec2::ZERO.into(), Vec2::new(0.0, config.link_size_half.1).into());
     commands
         .spawn()
-        .insert(JointBuilderComponent::new(joint, anchor, link));
-}
-
-
-fn take_action(
-    env_state: Res<EnvironmentState>,
-    mut cart: Query<&mut RigidBodyForces, With<Link>>,
-    params: Res<IntegrationParameters>,
-) {
-    if let Some(action) = env_state.action {
-        for mut rb_f in cart.iter_mut() {
-            match action {
-                0 => rb_f.force = Vec2::new(-ACTION_FORCE * params.dt, 0.0).into(),
-                1 => rb_f.force = Vec2::new(ACTION_FORCE * params.dt, 0.0).into(),
-                _ => panic!("action invalid: {}", action),
-            }
-        }
-    }
+        .insert(JointBuilderComponent::new(joint, anchor, link))
+        .insert(PendulumClean)
+        .insert(*instance);
 }
-
-// Update Current State of the environment
-fn update_state(
-    mut state: ResMut<EnvironmentState>,
-    link: Query<(&RigidBodyPosition, &RigidBodyVelocity), With<Link>>,
-) {
-    // Find our observables
-    let mut cart_pos_x = 0.0;
-    let mut cart_vel = 0.0;
-    for (rb_pos, rb_vel) in link.iter() {
-        cart_pos_x = rb_pos.position.translation.x;
-        cart_vel = rb_vel.linvel[0];
-    }
-
-    // Update state using that info
-    state.observation = vec![cart_pos_x, cart_vel];
-    state.reward = 1.0;
-    state.is_done = None;
-}
-
-
@@ -0,0 +1,395 @@
+// Pong
+//
+// Two paddles and a ball on breakout's physics. Player 0 at the bottom is the
+// environment's agent, player 1 at the top is an Opponent, see OpponentType.
+// Each player observes and acts from its own side, the top player's view is turned
+// around, so one policy can play either side. As a multi-agent environment both
+// players are agents.
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use std::collections::HashMap;
+
+use super::breakout::{
+    ball_bundle, human::keyboard_input, move_player, paddle_bundle, update_ball, wall_bundle, Ball,
+    BreakoutConfig,
+};
+use crate::{environment::*, multi_agent::*, space::*};
+
+pub struct PongPlugin {
+    pub render: bool,
+    pub human: bool,
+}
+
+impl Plugin for PongPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let opponent = app
+            .world()
+            .get_resource::<EnvironmentConfig>()
+            .map_or(OpponentType::Scripted, |config| config.opponent);
+        let mut config = load_env_config(app.world(), pong_config());
+        config.human = self.human;
+        config.render = self.render;
+
+        app.insert_resource(config)
+            .insert_resource(opponent)
+            .add_system(update_ball.system())
+            .add_system(ball_out.system());
+        add_env_systems::<PongPlugin>(app);
+        add_multi_agent_fns::<PongPlugin>(app);
+
+        if self.render {
+            app.add_startup_system(setup_camera.system());
+        }
+        if self.human && self.render {
+            app.add_system(keyboard_input.system())
+                .add_system(reset_input.system());
+            println!("Press A or D, or Left or Right Arrow\nR to reset");
+        }
+        if opponent == OpponentType::Human && self.render {
+            app.add_system(opponent_keyboard_input.system());
+            println!("Top player press J or L");
+        }
+    }
+}
+
+const WINNING_SCORE: usize = 5;
+// own paddle x, other paddle x, ball position and velocity
+const OBSERVATION_SIZE: usize = 6;
+
+// Makers
+struct Paddle {
+    player: usize,
+}
+struct Wall;
+struct PongCleanup;
+
+// Per instance score, player 0 first
+#[derive(Default)]
+struct Match {
+    scores: [usize; 2],
+    rewards: [f32; 2], // since the last observation
+    done: bool,
+}
+
+// Breakout's paddle and ball with the board to ourselves, params/env/pong.toml overrides it
+pub fn pong_config() -> BreakoutConfig {
+    BreakoutConfig {
+        player_color: Color::BLUE,
+        ball_init_y: 4.0,
+        ..Default::default()
+    }
+}
+
+impl Env for PongPlugin {
+    fn environment(world: &World) -> Environment {
+        let board = world.get_resource::<BreakoutConfig>().unwrap().board_size_half;
+        Environment {
+            action_space: Space::Discrete(3),
+            observation_space: Space::boxed(
+                vec![-board.x, -board.x, -board.x, -board.y, f32::NEG_INFINITY, f32::NEG_INFINITY],
+                vec![board.x, board.x, board.x, board.y, f32::INFINITY, f32::INFINITY],
+            ),
+        }
+    }
+
+    fn instance_offset(world: &World) -> Vec2 {
+        let board = world.get_resource::<BreakoutConfig>().unwrap().board_size_half;
+        Vec2::new(0.0, board.y * 2.5)
+    }
+
+    fn view_half_size(world: &World) -> Vec2 {
+        let config = world.get_resource::<BreakoutConfig>().unwrap();
+        config.board_size_half + Vec2::splat(config.board_line_size_half)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        despawn_instance::<PongCleanup>(world, instance);
+
+        world.resource_scope(|world, config: Mut<BreakoutConfig>| {
+            world.resource_scope(|world, mut rng: Mut<EnvRng>| {
+                let mut rapier_config = world.get_resource_mut::<RapierConfiguration>().unwrap();
+                rapier_config.scale = config.rapier_scale;
+                rapier_config.gravity = Vec2::ZERO.into();
+
+                run_commands(world, |commands, _| {
+                    commands
+                        .spawn()
+                        .insert(Match::default())
+                        .insert(Opponent::default())
+                        .insert(PongCleanup)
+                        .insert(*instance);
+                    spawn_walls(commands, instance, &config);
+                    spawn_paddle(commands, instance, &config, 0);
+                    spawn_paddle(commands, instance, &config, 1);
+                    let serve_to = rng.0.gen_range(0..2);
+                    spawn_ball(commands, instance, &config, &mut rng.0, serve_to);
+                });
+            });
+        });
+
+        Self::observe(world, instance).observation
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let opponent_action = match *world.get_resource::<OpponentType>().unwrap() {
+            OpponentType::Scripted => {
+                let dead_zone = world.get_resource::<BreakoutConfig>().unwrap().player_size_half.x * 0.5;
+                scripted(&observe_player(world, instance, 1), dead_zone)
+            }
+            _ => world
+                .query::<(&Instance, &Opponent)>()
+                .iter(world)
+                .find(|(i, _)| i.index == instance.index)
+                .and_then(|(_, opponent)| opponent.action.clone())
+                .unwrap_or(Action::Discrete(1)),
+        };
+        move_paddles(world, instance, &[action.clone(), opponent_action]);
+    }
+
+    // Steps are for player 0, player 1's observation goes to its Opponent
+    // and its reward to info
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let [mut step, opponent_step] = observe_players(world, instance);
+        step.info.insert("opponent_reward", opponent_step.reward);
+
+        let mut opponents = world.query::<(&Instance, &mut Opponent)>();
+        for (_, mut opponent) in opponents.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+            opponent.observation = opponent_step.observation.clone();
+        }
+        step
+    }
+}
+
+// Both players are agents, the Opponent and OpponentType are left alone
+impl MultiAgentEnv for PongPlugin {
+    fn possible_agents(_: &World) -> Vec<AgentId> {
+        vec![0, 1]
+    }
+
+    fn reset_agents(world: &mut World, instance: &Instance) -> HashMap<AgentId, Vec<f32>> {
+        Self::reset(world, instance);
+        (0..2)
+            .map(|player| (player, observe_player(world, instance, player)))
+            .collect()
+    }
+
+    // A player without an action stays put
+    fn act_agents(world: &mut World, instance: &Instance, actions: &HashMap<AgentId, Action>) {
+        let action = |player| actions.get(&player).cloned().unwrap_or(Action::Discrete(1));
+        move_paddles(world, instance, &[action(0), action(1)]);
+    }
+
+    fn observe_agents(world: &mut World, instance: &Instance) -> HashMap<AgentId, Step> {
+        let [bottom, top] = observe_players(world, instance);
+        vec![(0, bottom), (1, top)].into_iter().collect()
+    }
+}
+
+// Each player's step from its own side, the match's rewards are handed out
+fn observe_players(world: &mut World, instance: &Instance) -> [Step; 2] {
+    let mut steps = [
+        Step {
+            observation: observe_player(world, instance, 0),
+            ..Default::default()
+        },
+        Step {
+            observation: observe_player(world, instance, 1),
+            ..Default::default()
+        },
+    ];
+
+    let mut matches = world.query::<(&Instance, &mut Match)>();
+    for (_, mut game) in matches.iter_mut(world).filter(|(i, _)| i.index == instance.index) {
+        for (player, step) in steps.iter_mut().enumerate() {
+            step.reward = game.rewards[player];
+            step.is_done = game.done;
+            step.info.insert("score", game.scores[player] as f32);
+            step.info.insert("opponent_score", game.scores[1 - player] as f32);
+        }
+        game.rewards = [0.0; 2];
+    }
+    steps
+}
+
+// Each player's action is from its own side
+fn move_paddles(world: &mut World, instance: &Instance, actions: &[Action; 2]) {
+    // The top player is turned around, its left is our right
+    let top_action = Action::Discrete(2 - actions[1].discrete());
+    world.resource_scope(|world, config: Mut<BreakoutConfig>| {
+        let dt = world.get_resource::<IntegrationParameters>().unwrap().dt;
+        let mut paddles = world.query::<(&Instance, &Paddle, &mut RigidBodyPosition)>();
+        for (_, paddle, mut rb_pos) in paddles.iter_mut(world).filter(|(i, ..)| i.index == instance.index) {
+            let action = if paddle.player == 0 { &actions[0] } else { &top_action };
+            move_player(&mut rb_pos, instance, action, &config, dt);
+        }
+    });
+}
+
+// From the player's side, own paddle x, other paddle x, ball position and velocity
+fn observe_player(world: &mut World, instance: &Instance, player: usize) -> Vec<f32> {
+    // Turn the board around for the top player
+    let side = if player == 0 { 1.0 } else { -1.0 };
+    let mut observation = vec![0.0; OBSERVATION_SIZE];
+
+    let mut paddles = world.query::<(&Instance, &Paddle, &RigidBodyPosition)>();
+    for (_, paddle, rb_pos) in paddles.iter(world).filter(|(i, ..)| i.index == instance.index) {
+        let x = (rb_pos.position.translation.x - instance.origin.x) * side;
+        if paddle.player == player {
+            observation[0] = x;
+        } else {
+            observation[1] = x;
+        }
+    }
+    let mut balls = world.query_filtered::<(&Instance, &RigidBodyPosition, &RigidBodyVelocity), With<Ball>>();
+    for (_, rb_pos, rb_vel) in balls.iter(world).filter(|(i, ..)| i.index == instance.index) {
+        observation[2] = (rb_pos.position.translation.x - instance.origin.x) * side;
+        observation[3] = (rb_pos.position.translation.y - instance.origin.y) * side;
+        observation[4] = rb_vel.linvel.x * side;
+        observation[5] = rb_vel.linvel.y * side;
+    }
+    observation
+}
+
+// Follows the ball, from the player's side
+fn scripted(observation: &[f32], dead_zone: f32) -> Action {
+    let (paddle_x, ball_x) = (observation[0], observation[2]);
+    if ball_x < paddle_x - dead_zone {
+        Action::Discrete(0)
+    } else if ball_x > paddle_x + dead_zone {
+        Action::Discrete(2)
+    } else {
+        Action::Discrete(1)
+    }
+}
+
+// A ball past a paddle is a point for the other player, who then serves to the loser.
+// Also catches the ball being forced through a wall by a paddle, that is served again.
+fn ball_out(
+    mut commands: Commands,
+    balls: Query<(Entity, &Instance, &RigidBodyPosition), With<Ball>>,
+    mut matches: Query<(&Instance, &mut Match)>,
+    config: Res<BreakoutConfig>,
+    mut rng: ResMut<EnvRng>,
+) {
+    let board = config.board_size_half;
+    for (ball, instance, rb_pos) in balls.iter() {
+        let x = rb_pos.position.translation.x - instance.origin.x;
+        let y = rb_pos.position.translation.y - instance.origin.y;
+        let scorer = if y < -board.y {
+            Some(1)
+        } else if y > board.y {
+            Some(0)
+        } else if x.abs() > board.x {
+            None
+        } else {
+            continue;
+        };
+
+        if let Some(scorer) = scorer {
+            for (_, mut game) in matches.iter_mut().filter(|(i, _)| i.index == instance.index) {
+                game.scores[scorer] += 1;
+                game.rewards[scorer] += 1.0;
+                game.rewards[1 - scorer] -= 1.0;
+                if game.scores[scorer] >= WINNING_SCORE {
+                    game.done = true;
+                }
+            }
+        }
+        commands.entity(ball).despawn_recursive();
+        let serve_to = match scorer {
+            Some(scorer) => 1 - scorer,
+            None => rng.0.gen_range(0..2),
+        };
+        spawn_ball(&mut commands, instance, &config, &mut rng.0, serve_to);
+    }
+}
+
+fn reset_input(keyboard_input: Res<Input<KeyCode>>, mut matches: Query<(&Instance, &mut Match)>) {
+    if keyboard_input.just_pressed(KeyCode::R) {
+        for (_, mut game) in matches.iter_mut().filter(|(i, _)| i.index == 0) {
+            game.done = true;
+        }
+    }
+}
+
+// Actions are from the top player's side, so J is its right
+fn opponent_keyboard_input(keyboard_input: Res<Input<KeyCode>>, mut opponents: Query<(&Instance, &mut Opponent)>) {
+    let action = if keyboard_input.pressed(KeyCode::J) {
+        Action::Discrete(2)
+    } else if keyboard_input.pressed(KeyCode::L) {
+        Action::Discrete(0)
+    } else {
+        Action::Discrete(1)
+    };
+    for (_, mut opponent) in opponents.iter_mut().filter(|(i, _)| i.index == 0) {
+        opponent.action = Some(action.clone());
+    }
+}
+
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 50.0));
+    commands.spawn_bundle(camera);
+}
+
+// Side walls only, the ends are open
+fn spawn_walls(commands: &mut Commands, instance: &Instance, config: &BreakoutConfig) {
+    commands
+        .spawn_bundle(RigidBodyBundle {
+            position: instance.origin.into(),
+            body_type: RigidBodyType::Static,
+            ..Default::default()
+        })
+        .with_children(|parent| {
+            for side in [-1.0, 1.0].iter() {
+                parent
+                    .spawn_bundle(wall_bundle(
+                        instance,
+                        Vec2::new(side * config.board_size_half.x, 0.0),
+                        Vec2::new(config.board_line_size_half, config.board_size_half.y),
+                    ))
+                    .insert(ColliderPositionSync::Discrete)
+                    .insert(ColliderDebugRender::from(config.board_color))
+                    .insert(Wall)
+                    .insert(*instance);
+            }
+        })
+        .insert(PongCleanup)
+        .insert(*instance);
+}
+
+fn spawn_paddle(commands: &mut Commands, instance: &Instance, config: &BreakoutConfig, player: usize) {
+    let (y, color) = match player {
+        0 => (-config.board_size_half.y * 0.9, config.player_color),
+        _ => (config.board_size_half.y * 0.9, Color::RED),
+    };
+    let (body, collider) = paddle_bundle(instance, config, Vec2::new(0.0, y));
+    commands
+        .spawn_bundle(body)
+        .insert_bundle(collider)
+        .insert(RigidBodyPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(color))
+        .insert(Paddle { player: player })
+        .insert(PongCleanup)
+        .insert(*instance);
+}
+
+// From the middle, towards serve_to
+fn spawn_ball(commands: &mut Commands, instance: &Instance, config: &BreakoutConfig, rng: &mut Pcg64, serve_to: usize) {
+    let direction = if serve_to == 0 { -1.0 } else { 1.0 };
+    let velocity = Vec2::new(
+        rng.gen_range(config.ball_init_x_range.0..config.ball_init_x_range.1),
+        config.ball_init_y * direction,
+    );
+    let (body, collider) = ball_bundle(instance, config, Vec2::ZERO, velocity);
+    commands
+        .spawn_bundle(body)
+        .insert_bundle(collider)
+        .insert(ColliderPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(Color::BLACK))
+        .insert(Ball)
+        .insert(PongCleanup)
+        .insert(*instance);
+}
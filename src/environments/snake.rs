@@ -0,0 +1,460 @@
+// Snake
+//
+// A grid world, no physics, the snake moves one cell per action. Cells are kinematic
+// sensors so rapier can draw them, the game itself only works with grid positions.
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{ecs::component::Component, prelude::*};
+use bevy_rapier2d::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{environment::*, space::*};
+
+pub struct SnakePlugin {
+    pub render: bool,
+    pub human: bool,
+    pub config: SnakeConfig,
+}
+
+// Rules, see load_env_config
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnakeConfig {
+    pub width: i32,
+    pub height: i32,
+    pub observation: SnakeObservation,
+    pub start_length: usize,
+    pub food_reward: f32,
+    pub death_reward: f32,
+}
+
+impl Default for SnakeConfig {
+    fn default() -> Self {
+        Self {
+            width: 10,
+            height: 10,
+            observation: SnakeObservation::Features,
+            start_length: 3,
+            food_reward: 1.0,
+            death_reward: -1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnakeObservation {
+    // Danger ahead, right and left, direction and where the food is
+    Features,
+    // [body, head, food] channels over the grid, row 0 at the top
+    Grid,
+}
+
+impl SnakeConfig {
+    fn contains(&self, cell: IVec2) -> bool {
+        cell.x >= 0 && cell.x < self.width && cell.y >= 0 && cell.y < self.height
+    }
+
+    fn position(&self, cell: IVec2) -> Vec2 {
+        Vec2::new(
+            (cell.x as f32 - (self.width - 1) as f32 * 0.5) * CELL_SIZE,
+            (cell.y as f32 - (self.height - 1) as f32 * 0.5) * CELL_SIZE,
+        )
+    }
+
+    fn half_size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32) * CELL_SIZE * 0.5
+    }
+}
+
+impl Plugin for SnakePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let config = load_env_config(app.world(), self.config);
+        assert!(config.width >= 4 && config.height >= 4, "Snake needs at least a 4x4 grid");
+        assert!(
+            config.start_length >= 1 && config.start_length as i32 <= config.width / 2 + 1,
+            "Snake needs to start inside the grid"
+        );
+        app.insert_resource(config);
+        add_env_systems::<SnakePlugin>(app);
+
+        if self.render {
+            app.add_startup_system(setup_camera.system());
+        }
+        if self.human && self.render {
+            app.insert_resource(HumanPace(Timer::new(HUMAN_MOVE_TIME, true)))
+                .add_system(keyboard_input.system());
+            println!("Press W A S D, or the Arrows to turn\nR to reset");
+        }
+    }
+}
+
+const RAPIER_SCALE: f32 = 30.0;
+const CELL_SIZE: f32 = 1.0;
+const CELL_GAP: f32 = 0.05; // so segments can be told apart
+const WALL_SIZE_HALF: f32 = 0.1;
+const HUMAN_MOVE_TIME: Duration = Duration::from_millis(150);
+
+// Actions in order: up, right, down and left, each turning clockwise from the last
+const DIRECTIONS: [(i32, i32); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
+// danger (ahead, right, left), direction (up, right, down, left), food (up, right, down, left)
+const FEATURES_SIZE: usize = 3 + 4 + 4;
+
+// Makers
+struct Segment;
+struct Food;
+struct Wall;
+struct SnakeCleanup;
+
+// Humans can't play at one move a frame
+struct HumanPace(Timer);
+
+// Per instance game state
+#[derive(Default)]
+struct Game {
+    body: VecDeque<(IVec2, Entity)>, // head first
+    direction: usize,                // index into DIRECTIONS
+    food: Option<(IVec2, Entity)>,
+    score: usize,
+    step_reward: f32, // since the last observation
+    hunger: usize,    // steps since the last food
+    done: bool,
+}
+
+impl Env for SnakePlugin {
+    fn environment(world: &World) -> Environment {
+        let config = world.get_resource::<SnakeConfig>().unwrap();
+        let observation_space = match config.observation {
+            SnakeObservation::Features => Space::boxed(vec![0.0; FEATURES_SIZE], vec![1.0; FEATURES_SIZE]),
+            SnakeObservation::Grid => {
+                let size = 3 * (config.width * config.height) as usize;
+                Space::Box {
+                    low: vec![0.0; size],
+                    high: vec![1.0; size],
+                    shape: vec![3, config.height as usize, config.width as usize],
+                }
+            }
+        };
+        Environment {
+            action_space: Space::Discrete(4),
+            observation_space: observation_space,
+        }
+    }
+
+    fn instance_offset(world: &World) -> Vec2 {
+        let config = world.get_resource::<SnakeConfig>().unwrap();
+        Vec2::new(0.0, config.half_size().y * 2.0 + CELL_SIZE * 4.0)
+    }
+
+    fn view_half_size(world: &World) -> Vec2 {
+        let config = world.get_resource::<SnakeConfig>().unwrap();
+        config.half_size() + Vec2::splat(WALL_SIZE_HALF * 2.0)
+    }
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        let mut rapier_config = world.get_resource_mut::<RapierConfiguration>().unwrap();
+        rapier_config.scale = RAPIER_SCALE;
+        rapier_config.gravity = Vec2::ZERO.into();
+
+        despawn_instance::<SnakeCleanup>(world, instance);
+        let config = *world.get_resource::<SnakeConfig>().unwrap();
+
+        // Start in the middle heading right
+        let head = IVec2::new(config.width / 2, config.height / 2);
+        let cells: Vec<IVec2> = (0..config.start_length as i32).map(|i| head - IVec2::new(i, 0)).collect();
+        let food = pick_food(world, &config, cells.iter());
+
+        run_commands(world, |commands, _| {
+            spawn_walls(commands, instance, &config);
+            let body = cells
+                .iter()
+                .map(|cell| (*cell, spawn_cell(commands, instance, &config, *cell, Color::GREEN, Segment)))
+                .collect();
+            let food = food.map(|cell| (cell, spawn_cell(commands, instance, &config, cell, Color::RED, Food)));
+            commands
+                .spawn()
+                .insert(Game {
+                    body: body,
+                    direction: 1,
+                    food: food,
+                    ..Default::default()
+                })
+                .insert(SnakeCleanup)
+                .insert(*instance);
+        });
+        Self::observe(world, instance).observation
+    }
+
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        if !human_ready(world) {
+            return;
+        }
+        let config = *world.get_resource::<SnakeConfig>().unwrap();
+        let mut game = match take_game(world, instance) {
+            Some(game) if game.done => {
+                put_game(world, instance, game);
+                return;
+            }
+            Some(game) => game,
+            None => return,
+        };
+        let direction = match action.discrete() {
+            d if d < DIRECTIONS.len() => d,
+            _ => panic!("action invalid: {:?}", action),
+        };
+        // Turning back into the neck is ignored
+        if heading(direction) + heading(game.direction) != IVec2::ZERO {
+            game.direction = direction;
+        }
+
+        let head = game.body[0].0 + heading(game.direction);
+        let eating = game.food.map_or(false, |(food, _)| food == head);
+        // The tail moves out of the way, unless we are growing
+        let blocking = if eating { game.body.len() } else { game.body.len() - 1 };
+        if !config.contains(head) || game.body.iter().take(blocking).any(|(cell, _)| *cell == head) {
+            game.step_reward += config.death_reward;
+            game.done = true;
+            put_game(world, instance, game);
+            return;
+        }
+
+        let mut despawned = Vec::new();
+        if eating {
+            let (_, food) = game.food.take().unwrap();
+            despawned.push(food);
+            game.score += 1;
+            game.step_reward += config.food_reward;
+            game.hunger = 0;
+        } else {
+            let (_, tail) = game.body.pop_back().unwrap();
+            despawned.push(tail);
+            game.hunger += 1;
+        }
+
+        let mut head_entity = None;
+        run_commands(world, |commands, _| {
+            for e in despawned {
+                commands.entity(e).despawn_recursive();
+            }
+            head_entity = Some(spawn_cell(commands, instance, &config, head, Color::GREEN, Segment));
+        });
+        game.body.push_front((head, head_entity.unwrap()));
+
+        if eating {
+            // No room left for food, the grid is full and we won
+            match pick_food(world, &config, game.body.iter().map(|(cell, _)| cell)) {
+                Some(cell) => {
+                    let mut food = None;
+                    run_commands(world, |commands, _| {
+                        food = Some(spawn_cell(commands, instance, &config, cell, Color::RED, Food));
+                    });
+                    game.food = Some((cell, food.unwrap()));
+                }
+                None => game.done = true,
+            }
+        }
+        // Starved, stops a snake going round in circles forever
+        if game.hunger >= (config.width * config.height) as usize {
+            game.done = true;
+        }
+        put_game(world, instance, game);
+    }
+
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let config = *world.get_resource::<SnakeConfig>().unwrap();
+        let mut step = Step::default();
+        let mut game = match take_game(world, instance) {
+            Some(game) => game,
+            None => {
+                step.observation = vec![0.0; Self::environment(world).observation_space.flat_size()];
+                return step;
+            }
+        };
+        step.observation = match config.observation {
+            SnakeObservation::Features => features(&config, &game),
+            SnakeObservation::Grid => grid(&config, &game),
+        };
+        step.reward = game.step_reward;
+        step.is_done = game.done;
+        step.info.insert("score", game.score as f32);
+        step.info.insert("length", game.body.len() as f32);
+        game.step_reward = 0.0;
+        put_game(world, instance, game);
+        step
+    }
+}
+
+fn features(config: &SnakeConfig, game: &Game) -> Vec<f32> {
+    let head = game.body[0].0;
+    let danger = |direction: usize| {
+        let cell = head + heading(direction);
+        // The tail will have moved on by then
+        let body = game.body.iter().take(game.body.len() - 1);
+        !config.contains(cell) || body.map(|(c, _)| c).any(|c| *c == cell)
+    };
+    let mut observation = Vec::with_capacity(FEATURES_SIZE);
+    for turn in [0, 1, 3].iter() {
+        observation.push(danger(game.direction + turn) as u8 as f32);
+    }
+    for direction in 0..4 {
+        observation.push((direction == game.direction) as u8 as f32);
+    }
+    let food = game.food.map_or(head, |(food, _)| food);
+    observation.extend_from_slice(&[
+        (food.y > head.y) as u8 as f32,
+        (food.x > head.x) as u8 as f32,
+        (food.y < head.y) as u8 as f32,
+        (food.x < head.x) as u8 as f32,
+    ]);
+    observation
+}
+
+fn grid(config: &SnakeConfig, game: &Game) -> Vec<f32> {
+    let (width, height) = (config.width as usize, config.height as usize);
+    let mut observation = vec![0.0; 3 * width * height];
+    let mut set = |channel: usize, cell: IVec2| {
+        let row = height - 1 - cell.y as usize;
+        observation[channel * width * height + row * width + cell.x as usize] = 1.0;
+    };
+    for (cell, _) in game.body.iter() {
+        set(0, *cell);
+    }
+    set(1, game.body[0].0);
+    if let Some((cell, _)) = game.food {
+        set(2, cell);
+    }
+    observation
+}
+
+fn heading(direction: usize) -> IVec2 {
+    let (x, y) = DIRECTIONS[direction % DIRECTIONS.len()];
+    IVec2::new(x, y)
+}
+
+// A random free cell, None when the snake fills the grid
+fn pick_food<'a>(world: &mut World, config: &SnakeConfig, body: impl Iterator<Item = &'a IVec2>) -> Option<IVec2> {
+    let body: Vec<IVec2> = body.cloned().collect();
+    let free: Vec<IVec2> = (0..config.height)
+        .flat_map(|y| (0..config.width).map(move |x| IVec2::new(x, y)))
+        .filter(|cell| !body.contains(cell))
+        .collect();
+    if free.is_empty() {
+        return None;
+    }
+    let rng = &mut world.get_resource_mut::<EnvRng>().unwrap().0;
+    Some(free[rng.gen_range(0..free.len())])
+}
+
+fn human_ready(world: &mut World) -> bool {
+    let delta = world.get_resource::<Time>().map_or(Duration::default(), |time| time.delta());
+    match world.get_resource_mut::<HumanPace>() {
+        Some(mut pace) => pace.0.tick(delta).just_finished(),
+        None => true,
+    }
+}
+
+// Take the game state out of the world, so act can work on it alongside other queries
+fn take_game(world: &mut World, instance: &Instance) -> Option<Game> {
+    let mut games = world.query::<(&Instance, &mut Game)>();
+    games
+        .iter_mut(world)
+        .find(|(i, _)| i.index == instance.index)
+        .map(|(_, mut game)| std::mem::take(&mut *game))
+}
+
+fn put_game(world: &mut World, instance: &Instance, state: Game) {
+    let mut games = world.query::<(&Instance, &mut Game)>();
+    if let Some((_, mut game)) = games.iter_mut(world).find(|(i, _)| i.index == instance.index) {
+        *game = state;
+    }
+}
+
+fn keyboard_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut env_state: ResMut<EnvironmentState>,
+    mut games: Query<(&Instance, &mut Game)>,
+) {
+    let keys = [
+        (KeyCode::W, KeyCode::Up),
+        (KeyCode::D, KeyCode::Right),
+        (KeyCode::S, KeyCode::Down),
+        (KeyCode::A, KeyCode::Left),
+    ];
+    for (_, mut game) in games.iter_mut().filter(|(i, _)| i.index == 0) {
+        // Keep heading the same way until a key is pressed
+        if env_state.action.is_none() {
+            env_state.action = Some(Action::Discrete(game.direction));
+        }
+        for (direction, (key, arrow)) in keys.iter().enumerate() {
+            if keyboard_input.pressed(*key) || keyboard_input.pressed(*arrow) {
+                env_state.action = Some(Action::Discrete(direction));
+            }
+        }
+        if keyboard_input.just_pressed(KeyCode::R) {
+            game.done = true;
+        }
+    }
+}
+
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 50.0));
+    commands.spawn_bundle(camera);
+}
+
+fn spawn_walls(commands: &mut Commands, instance: &Instance, config: &SnakeConfig) {
+    let half = config.half_size() + Vec2::splat(WALL_SIZE_HALF);
+    let walls = [
+        (Vec2::new(0.0, half.y), Vec2::new(half.x + WALL_SIZE_HALF, WALL_SIZE_HALF)),
+        (Vec2::new(0.0, -half.y), Vec2::new(half.x + WALL_SIZE_HALF, WALL_SIZE_HALF)),
+        (Vec2::new(half.x, 0.0), Vec2::new(WALL_SIZE_HALF, half.y + WALL_SIZE_HALF)),
+        (Vec2::new(-half.x, 0.0), Vec2::new(WALL_SIZE_HALF, half.y + WALL_SIZE_HALF)),
+    ];
+    for (pos, size_half) in walls.iter() {
+        spawn_box(commands, instance, *pos, *size_half, Color::BLUE, Wall);
+    }
+}
+
+fn spawn_cell(
+    commands: &mut Commands,
+    instance: &Instance,
+    config: &SnakeConfig,
+    cell: IVec2,
+    color: Color,
+    component: impl Component,
+) -> Entity {
+    let half = CELL_SIZE * 0.5 - CELL_GAP;
+    spawn_box(commands, instance, config.position(cell), Vec2::splat(half), color, component)
+}
+
+fn spawn_box(
+    commands: &mut Commands,
+    instance: &Instance,
+    pos: Vec2,
+    size_half: Vec2,
+    color: Color,
+    component: impl Component,
+) -> Entity {
+    commands
+        .spawn_bundle(RigidBodyBundle {
+            position: (instance.origin + pos).into(),
+            body_type: RigidBodyType::KinematicPositionBased,
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            collider_type: ColliderType::Sensor,
+            shape: ColliderShape::cuboid(size_half.x, size_half.y),
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(RigidBodyPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(color))
+        .insert(component)
+        .insert(SnakeCleanup)
+        .insert(*instance)
+        .id()
+}
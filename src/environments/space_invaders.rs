@@ -1,252 +1,582 @@
-use crate::environment::*;
+// Space Invaders
+//
+// The game runs one tick per action, rapier is only used to draw it. Every entity is a
+// kinematic sensor moved to its Hitbox, hits are box overlaps checked in act.
 use bevy::{ecs::component::Component, prelude::*};
 use bevy_rapier2d::prelude::*;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{environment::*, space::*};
+
 pub struct SpaceInvadersPlugin {
     pub render: bool,
+    pub human: bool,
 }
 
 impl Plugin for SpaceInvadersPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        insert_env_resources(app, 2, 4);
-
-        app.add_startup_system(setup_environment.system())
-            .add_system(scroll_tubes.system())
-            .add_system(catchup_bird.system())
-            .add_system_to_stage(CoreStage::PreUpdate, update_state.system())
-            .add_system_to_stage(CoreStage::PostUpdate, take_action.system())
-            .add_system(reset_listener.system());
+        let config = load_env_config(app.world(), SpaceInvadersConfig::default());
+        assert!(config.row_points.len() == INVADER_ROWS, "row_points needs a value per invader row");
+        app.insert_resource(config);
+        add_env_systems::<SpaceInvadersPlugin>(app);
 
         if self.render {
-            app.add_startup_system(setup_camera.system())
-                .add_system(keyboard_input.system());
+            app.add_startup_system(setup_camera.system());
+        }
+        if self.human && self.render {
+            app.add_system(keyboard_input.system());
+            println!("Press A or D, or Left or Right Arrow to move\nSpace to fire\nR to reset");
         }
     }
 }
 
-// Makers to identify entities
-struct Tube(TubeType);
-struct Bird;
+// Rules, see load_env_config, speeds are per tick
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpaceInvadersConfig {
+    pub lives: usize,
+    pub player_speed: f32,
+    pub player_bullet_speed: f32,
+    pub invader_bullet_speed: f32,
+    pub invader_bullets_max: usize,
+    pub invader_fire_chance: f32,
+    pub invader_step_down: f32,
+    // The formation speeds up as invaders die, full speed with one left
+    pub invader_speed_start: f32,
+    pub invader_speed_max: f32,
+    pub row_points: Vec<usize>, // top row first
+}
 
-// Marking tubes so on respawn can spawn them as pair
-enum TubeType {
-    Top,
-    Bottom,
+impl Default for SpaceInvadersConfig {
+    fn default() -> Self {
+        Self {
+            lives: 3,
+            player_speed: 0.3,
+            player_bullet_speed: 0.8,
+            invader_bullet_speed: 0.3,
+            invader_bullets_max: 3,
+            invader_fire_chance: 0.05,
+            invader_step_down: 0.6,
+            invader_speed_start: 0.03,
+            invader_speed_max: 0.4,
+            row_points: vec![30, 20, 20, 10, 10],
+        }
+    }
 }
 
-const TUBE_SIZE_HALF_X: f32 = 1.0;
-const TUBE_SIZE_HALF_Y: f32 = 10.0;
-const TUBE_DESPAWN_LIMIT: f32 = -15.0;
-const TUBE_SPACING: f32 = 10.0;
-const TUBE_GAP_SIZE_HALF: f32 = 3.0;  // Control gap size between tubes in a set
-const TUBE_GAP_OFFSET_MAX: f32 = 4.0; // Control gap range off of y axis
-const TUBE_SPEED: f32 = 0.1;
-const TUBE_COUNT: usize = 5;
-const BIRD_SIZE_HALF: f32 = 0.5;
-const BIRD_RESET_LIMIT_Y: f32 = -10.0;
-const BIRD_RESET_LIMIT_X: f32 = -4.0;
-const ACTION_FORCE: f32 = 150.0; // F / dt
+const RAPIER_SCALE: f32 = 25.0;
+const BOARD_SIZE_HALF_X: f32 = 12.0;
+const BOARD_SIZE_HALF_Y: f32 = 14.0;
 
-fn take_action(
-    mut env_state: ResMut<EnvironmentState>,
-    mut bird: Query<&mut RigidBodyVelocity, With<Bird>>,
-    _params: Res<IntegrationParameters>,
-) {
-    if let Some(action) = env_state.action {
-        for _rb_vel in bird.iter_mut() {
-            match action {
-                0 => {} //action_up(rb_vel, &params),
-                1 => {} // Do nothing
-                _ => panic!("action invalid: {}", action),
-            }
+const PLAYER_Y: f32 = -12.5;
+const PLAYER_SIZE_HALF_X: f32 = 0.8;
+const PLAYER_SIZE_HALF_Y: f32 = 0.3;
+
+const BULLET_SIZE_HALF_X: f32 = 0.05;
+const BULLET_SIZE_HALF_Y: f32 = 0.3;
+
+const INVADER_ROWS: usize = 5;
+const INVADER_COLS: usize = 8;
+const INVADER_SIZE_HALF_X: f32 = 0.5;
+const INVADER_SIZE_HALF_Y: f32 = 0.35;
+const INVADER_SPACING_X: f32 = 1.6;
+const INVADER_SPACING_Y: f32 = 1.3;
+const INVADER_TOP_Y: f32 = 10.0;
+
+const SHIELD_COUNT: usize = 4;
+const SHIELD_Y: f32 = -9.0;
+const SHIELD_CELLS_X: usize = 6;
+const SHIELD_CELLS_Y: usize = 3;
+const SHIELD_CELL_SIZE_HALF: f32 = 0.2;
+
+const INVADER_BULLETS_OBSERVED: usize = 3;
+// player x, player bullet (active, x, y), formation offset (x, y), alive invaders,
+// nearest invader bullets (x, y) and lives
+const OBSERVATION_SIZE: usize = 4 + 2 + INVADER_ROWS * INVADER_COLS + INVADER_BULLETS_OBSERVED * 2 + 1;
+
+// Position and size relative to the instance origin, what the game logic works with
+#[derive(Debug, Clone, Copy)]
+struct Hitbox {
+    pos: Vec2,
+    half: Vec2,
+}
+
+impl Hitbox {
+    fn new(pos: Vec2, half_x: f32, half_y: f32) -> Self {
+        Self {
+            pos: pos,
+            half: Vec2::new(half_x, half_y),
         }
-        env_state.action = None;
+    }
+
+    fn overlaps(&self, other: &Hitbox) -> bool {
+        let distance = (self.pos - other.pos).abs();
+        distance.x < self.half.x + other.half.x && distance.y < self.half.y + other.half.y
     }
 }
 
-fn action_up(mut rb_vel: Mut<RigidBodyVelocity>, params: &Res<IntegrationParameters>) {
-    rb_vel.linvel = Vec2::new(0.0, ACTION_FORCE * params.dt).into();
+// Makers
+struct Player;
+#[derive(Clone, Copy)]
+struct Invader {
+    row: usize,
+    col: usize,
 }
+struct PlayerBullet;
+struct InvaderBullet;
+struct ShieldCell;
+struct Ground;
+struct SpaceInvadersCleanup;
 
-// Update Current State of the environment
-fn update_state(
-    mut state: ResMut<EnvironmentState>,
-    bird: Query<(&RigidBodyPosition, &RigidBodyVelocity), With<Bird>>,
-    mut ev_reset: EventWriter<EnvironmentResetEvent>,
-) {
-    // Find our observables
-    let mut bird_pos = Vec2::new(0.0, 0.0);
-    let mut bird_vel = 0.0;
-    for (rb_pos, rb_vel) in bird.iter() {
-        bird_pos.x = rb_pos.position.translation.x;
-        bird_pos.y = rb_pos.position.translation.y;
-        bird_vel = rb_vel.linvel[0];
+// Per instance game state
+struct Game {
+    score: usize,
+    step_score: usize, // since the last observation
+    lives: usize,
+    formation: Vec2, // offset of the invaders from where the wave started
+    direction: f32,
+    killed: usize, // this wave
+    done: bool,
+}
+
+impl Game {
+    fn new(lives: usize) -> Self {
+        Self {
+            score: 0,
+            step_score: 0,
+            lives: lives,
+            formation: Vec2::ZERO,
+            direction: 1.0,
+            killed: 0,
+            done: false,
+        }
     }
+}
 
-    // TODO: Update state using that info
-    state.observation = vec![bird_pos.x, bird_vel];
+impl Env for SpaceInvadersPlugin {
+    fn environment(_: &World) -> Environment {
+        // left, right, fire and no-op
+        Environment {
+            action_space: Space::Discrete(4),
+            observation_space: Space::unbounded(OBSERVATION_SIZE),
+        }
+    }
 
-    // state.reward = 1.0;
-    state.is_done = reset_check(bird_pos);
-    if state.is_done {
-        ev_reset.send(EnvironmentResetEvent);
+    fn instance_offset(_: &World) -> Vec2 {
+        Vec2::new(0.0, BOARD_SIZE_HALF_Y * 2.5)
     }
-}
 
-fn reset_check(bird_pos: Vec2) -> bool {
-    let mut result = false;
-    if bird_pos.x < BIRD_RESET_LIMIT_X {
-        result = true
+    fn view_half_size(_: &World) -> Vec2 {
+        Vec2::new(BOARD_SIZE_HALF_X, BOARD_SIZE_HALF_Y)
     }
-    if bird_pos.y < BIRD_RESET_LIMIT_Y  {
-        result = true
+
+    fn reset(world: &mut World, instance: &Instance) -> Vec<f32> {
+        let mut rapier_config = world.get_resource_mut::<RapierConfiguration>().unwrap();
+        rapier_config.scale = RAPIER_SCALE;
+        rapier_config.gravity = Vec2::ZERO.into();
+
+        despawn_instance::<SpaceInvadersCleanup>(world, instance);
+        run_commands(world, |commands, world| {
+            let lives = world.get_resource::<SpaceInvadersConfig>().unwrap().lives;
+            commands
+                .spawn()
+                .insert(Game::new(lives))
+                .insert(SpaceInvadersCleanup)
+                .insert(*instance);
+            spawn_box(
+                commands,
+                instance,
+                Hitbox::new(Vec2::new(0.0, -BOARD_SIZE_HALF_Y), BOARD_SIZE_HALF_X, 0.1),
+                Color::BLUE,
+                Ground,
+            );
+            spawn_box(
+                commands,
+                instance,
+                Hitbox::new(Vec2::new(0.0, PLAYER_Y), PLAYER_SIZE_HALF_X, PLAYER_SIZE_HALF_Y),
+                Color::BLUE,
+                Player,
+            );
+            spawn_shields(commands, instance);
+            spawn_wave(commands, instance);
+        });
+        Self::observe(world, instance).observation
     }
-    result
-}
 
-fn setup_camera(mut commands: Commands) {
-    let mut camera = OrthographicCameraBundle::new_2d();
-    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 50.0));
-    commands.spawn_bundle(camera);
+    fn act(world: &mut World, instance: &Instance, action: &Action) {
+        let mut game = match take_game(world, instance) {
+            Some(game) if game.done => {
+                put_game(world, instance, game);
+                return;
+            }
+            Some(game) => game,
+            None => return,
+        };
+        let config = world.get_resource::<SpaceInvadersConfig>().unwrap().clone();
+        let mut despawned = Vec::new();
+        let mut spawned = Vec::new();
+
+        // Player
+        let (player, mut player_box) = collect::<Player>(world, instance)[0];
+        match action.discrete() {
+            0 => player_box.pos.x -= config.player_speed,
+            1 => player_box.pos.x += config.player_speed,
+            2 => {
+                if collect::<PlayerBullet>(world, instance).is_empty() {
+                    spawned.push((
+                        Hitbox::new(
+                            player_box.pos + Vec2::new(0.0, PLAYER_SIZE_HALF_Y + BULLET_SIZE_HALF_Y),
+                            BULLET_SIZE_HALF_X,
+                            BULLET_SIZE_HALF_Y,
+                        ),
+                        true,
+                    ));
+                }
+            }
+            3 => {}
+            _ => panic!("action invalid: {:?}", action),
+        }
+        let limit = BOARD_SIZE_HALF_X - PLAYER_SIZE_HALF_X;
+        player_box.pos.x = player_box.pos.x.clamp(-limit, limit);
+        move_to(world, instance, player, player_box);
+
+        // Bullets, gone once they leave the board
+        let mut player_bullets = collect::<PlayerBullet>(world, instance);
+        for (e, hitbox) in player_bullets.iter_mut() {
+            hitbox.pos.y += config.player_bullet_speed;
+            move_to(world, instance, *e, *hitbox);
+            if hitbox.pos.y > BOARD_SIZE_HALF_Y {
+                despawned.push(*e);
+            }
+        }
+        let mut invader_bullets = collect::<InvaderBullet>(world, instance);
+        for (e, hitbox) in invader_bullets.iter_mut() {
+            hitbox.pos.y -= config.invader_bullet_speed;
+            move_to(world, instance, *e, *hitbox);
+            if hitbox.pos.y < -BOARD_SIZE_HALF_Y {
+                despawned.push(*e);
+            }
+        }
+
+        // Invaders march side to side, stepping down and turning at the edges
+        let mut invaders = collect_invaders(world, instance);
+        let total = INVADER_ROWS * INVADER_COLS;
+        let speed = config.invader_speed_start
+            + (config.invader_speed_max - config.invader_speed_start) * game.killed as f32 / (total - 1) as f32;
+        let next_x = game.formation.x + game.direction * speed;
+        let at_edge = invaders.iter().any(|(_, invader, _)| {
+            (invader_home(invader.row, invader.col).x + next_x).abs()
+                > BOARD_SIZE_HALF_X - INVADER_SIZE_HALF_X
+        });
+        if at_edge {
+            game.formation.y -= config.invader_step_down;
+            game.direction = -game.direction;
+        } else {
+            game.formation.x = next_x;
+        }
+        for (e, invader, hitbox) in invaders.iter_mut() {
+            hitbox.pos = invader_home(invader.row, invader.col) + game.formation;
+            move_to(world, instance, *e, *hitbox);
+        }
+
+        // The lowest invader of a random column fires now and then
+        let (fire_roll, pick) = {
+            let rng = &mut world.get_resource_mut::<EnvRng>().unwrap().0;
+            (rng.gen::<f32>(), rng.gen_range(0..INVADER_COLS))
+        };
+        if fire_roll < config.invader_fire_chance && invader_bullets.len() < config.invader_bullets_max {
+            let shooter = invaders
+                .iter()
+                .filter(|(_, invader, _)| invader.col == pick)
+                .min_by(|a, b| a.2.pos.y.partial_cmp(&b.2.pos.y).unwrap());
+            if let Some((_, _, hitbox)) = shooter {
+                spawned.push((
+                    Hitbox::new(
+                        hitbox.pos - Vec2::new(0.0, INVADER_SIZE_HALF_Y + BULLET_SIZE_HALF_Y),
+                        BULLET_SIZE_HALF_X,
+                        BULLET_SIZE_HALF_Y,
+                    ),
+                    false,
+                ));
+            }
+        }
+
+        // Hits, each entity can only be used up once
+        let shields = collect::<ShieldCell>(world, instance);
+        for (bullet, bullet_box) in player_bullets.iter() {
+            if despawned.contains(bullet) {
+                continue;
+            }
+            if let Some((invader, info, _)) = invaders
+                .iter()
+                .find(|(e, _, hitbox)| !despawned.contains(e) && hitbox.overlaps(bullet_box))
+            {
+                despawned.push(*bullet);
+                despawned.push(*invader);
+                game.score += config.row_points[info.row];
+                game.step_score += config.row_points[info.row];
+                game.killed += 1;
+            } else if let Some((cell, _)) = shields
+                .iter()
+                .find(|(e, hitbox)| !despawned.contains(e) && hitbox.overlaps(bullet_box))
+            {
+                despawned.push(*bullet);
+                despawned.push(*cell);
+            }
+        }
+        for (bullet, bullet_box) in invader_bullets.iter() {
+            if despawned.contains(bullet) {
+                continue;
+            }
+            if bullet_box.overlaps(&player_box) {
+                despawned.push(*bullet);
+                game.lives = game.lives.saturating_sub(1);
+            } else if let Some((cell, _)) = shields
+                .iter()
+                .find(|(e, hitbox)| !despawned.contains(e) && hitbox.overlaps(bullet_box))
+            {
+                despawned.push(*bullet);
+                despawned.push(*cell);
+            }
+        }
+        for (_, _, invader_box) in invaders.iter() {
+            for (cell, cell_box) in shields.iter() {
+                if !despawned.contains(cell) && cell_box.overlaps(invader_box) {
+                    despawned.push(*cell);
+                }
+            }
+            // Landed, game over whatever lives are left
+            if invader_box.pos.y - INVADER_SIZE_HALF_Y <= PLAYER_Y + PLAYER_SIZE_HALF_Y {
+                game.done = true;
+            }
+        }
+        if game.lives == 0 {
+            game.done = true;
+        }
+
+        // Cleared the wave, the next one starts from the top at the starting speed
+        let cleared = invaders.iter().all(|(e, ..)| despawned.contains(e));
+        if cleared {
+            game.formation = Vec2::ZERO;
+            game.direction = 1.0;
+            game.killed = 0;
+        }
+
+        put_game(world, instance, game);
+        run_commands(world, |commands, _| {
+            for e in despawned {
+                commands.entity(e).despawn_recursive();
+            }
+            for (hitbox, from_player) in spawned {
+                if from_player {
+                    spawn_box(commands, instance, hitbox, Color::BLACK, PlayerBullet);
+                } else {
+                    spawn_box(commands, instance, hitbox, Color::RED, InvaderBullet);
+                }
+            }
+            if cleared {
+                spawn_wave(commands, instance);
+            }
+        });
+    }
+
+    fn observe(world: &mut World, instance: &Instance) -> Step {
+        let mut observation = Vec::with_capacity(OBSERVATION_SIZE);
+
+        let player_box = collect::<Player>(world, instance).first().map(|(_, h)| *h);
+        let player_x = player_box.map_or(0.0, |h| h.pos.x);
+        observation.push(player_x);
+        match collect::<PlayerBullet>(world, instance).first() {
+            Some((_, hitbox)) => observation.extend_from_slice(&[1.0, hitbox.pos.x, hitbox.pos.y]),
+            None => observation.extend_from_slice(&[0.0, 0.0, 0.0]),
+        }
+
+        let mut alive = vec![0.0; INVADER_ROWS * INVADER_COLS];
+        for (_, invader, _) in collect_invaders(world, instance) {
+            alive[invader.row * INVADER_COLS + invader.col] = 1.0;
+        }
+
+        // Closest bullets to the player first, zeros when there are fewer
+        let mut bullets = collect::<InvaderBullet>(world, instance);
+        bullets.sort_by(|a, b| {
+            let a = (a.1.pos - Vec2::new(player_x, PLAYER_Y)).length();
+            let b = (b.1.pos - Vec2::new(player_x, PLAYER_Y)).length();
+            a.partial_cmp(&b).unwrap()
+        });
+        let mut nearest = vec![0.0; INVADER_BULLETS_OBSERVED * 2];
+        for (i, (_, hitbox)) in bullets.iter().take(INVADER_BULLETS_OBSERVED).enumerate() {
+            nearest[i * 2] = hitbox.pos.x;
+            nearest[i * 2 + 1] = hitbox.pos.y;
+        }
+
+        let mut step = Step::default();
+        if let Some(mut game) = take_game(world, instance) {
+            observation.extend_from_slice(&[game.formation.x, game.formation.y]);
+            observation.extend(alive);
+            observation.extend(nearest);
+            observation.push(game.lives as f32);
+
+            step.reward = game.step_score as f32;
+            step.is_done = game.done;
+            step.info.insert("score", game.score as f32);
+            step.info.insert("lives", game.lives as f32);
+            game.step_score = 0;
+            put_game(world, instance, game);
+        } else {
+            observation.resize(OBSERVATION_SIZE, 0.0);
+        }
+        step.observation = observation;
+        step
+    }
 }
 
-fn setup_environment(mut commands: Commands, mut rapier_config: ResMut<RapierConfiguration>) {
-    // Very useful to zoom in and out to see whats going on
-    // Also see https://rapier.rs/docs/user_guides/bevy_plugin/common_mistakes/#why-is-everything-moving-in-slow-motion
-    rapier_config.scale = 50.0;
+fn invader_home(row: usize, col: usize) -> Vec2 {
+    Vec2::new(
+        (col as f32 - (INVADER_COLS - 1) as f32 * 0.5) * INVADER_SPACING_X,
+        INVADER_TOP_Y - row as f32 * INVADER_SPACING_Y,
+    )
+}
 
-    spawn_env(&mut commands);
+// Entities of the instance with the marker T, with their hitboxes
+fn collect<T: Component>(world: &mut World, instance: &Instance) -> Vec<(Entity, Hitbox)> {
+    world
+        .query_filtered::<(Entity, &Instance, &Hitbox), With<T>>()
+        .iter(world)
+        .filter(|(_, i, _)| i.index == instance.index)
+        .map(|(e, _, hitbox)| (e, *hitbox))
+        .collect()
 }
 
-// Assumes clean slate, called at startup and on reset events
-fn spawn_env(commands: &mut Commands) {
-    // Create the Bird
-    commands
-        .spawn_bundle(RigidBodyBundle {
-            position: Vec2::new(0.0, 0.0).into(),
-            body_type: RigidBodyType::Dynamic,
-            ..Default::default()
-        })
-        .insert_bundle(ColliderBundle {
-            shape: ColliderShape::cuboid(BIRD_SIZE_HALF, BIRD_SIZE_HALF),
-            collider_type: ColliderType::Solid,
-            ..Default::default()
-        })
-        .insert(ColliderPositionSync::Discrete)
-        .insert(ColliderDebugRender::from(Color::RED))
-        .insert(Bird)
-        .id();
-
-    // setup tubes
-    for x in 0..TUBE_COUNT {
-        spawn_tube_set(commands, x as f32 * TUBE_SPACING);
-    }
+fn collect_invaders(world: &mut World, instance: &Instance) -> Vec<(Entity, Invader, Hitbox)> {
+    world
+        .query::<(Entity, &Instance, &Invader, &Hitbox)>()
+        .iter(world)
+        .filter(|(_, i, ..)| i.index == instance.index)
+        .map(|(e, _, invader, hitbox)| (e, *invader, *hitbox))
+        .collect()
 }
 
-fn spawn_tube_set(commands: &mut Commands, pos_x: f32) {
-    // figure out where the tubes should be
-    let mut rng = rand::thread_rng();
-    let gap_offset = rng.gen_range(-TUBE_GAP_OFFSET_MAX..TUBE_GAP_OFFSET_MAX);
-    let spacing = TUBE_SIZE_HALF_Y + TUBE_GAP_SIZE_HALF;
+// Take the game state out of the world, so act can work on it alongside other queries,
+// put_game puts it back
+fn take_game(world: &mut World, instance: &Instance) -> Option<Game> {
+    let mut games = world.query::<(&Instance, &mut Game)>();
+    games
+        .iter_mut(world)
+        .find(|(i, _)| i.index == instance.index)
+        .map(|(_, mut game)| std::mem::replace(&mut *game, Game::new(0)))
+}
 
-    //println!("spacing: {}, gap_offset: {}", spacing, gap_offset);
-    spawn_tube(
-        commands,
-        Vec2::new(pos_x, spacing + gap_offset),
-        Tube(TubeType::Top),
-    );
-    spawn_tube(
-        commands,
-        Vec2::new(pos_x, -spacing + gap_offset),
-        Tube(TubeType::Bottom),
-    );
+fn put_game(world: &mut World, instance: &Instance, state: Game) {
+    let mut games = world.query::<(&Instance, &mut Game)>();
+    if let Some((_, mut game)) = games.iter_mut(world).find(|(i, _)| i.index == instance.index) {
+        *game = state;
+    }
 }
 
-fn spawn_tube(commands: &mut Commands, pos: Vec2, tube_type: impl Component) -> Entity {
-    commands
-        .spawn_bundle(RigidBodyBundle {
-            position: pos.into(),
-            body_type: RigidBodyType::Static,
-            ..Default::default()
-        })
-        .insert_bundle(ColliderBundle {
-            collider_type: ColliderType::Solid,
-            shape: ColliderShape::cuboid(TUBE_SIZE_HALF_X, TUBE_SIZE_HALF_Y),
-            ..Default::default()
-        })
-        .insert(ColliderPositionSync::Discrete)
-        .insert(ColliderDebugRender::from(Color::GREEN))
-        .insert(tube_type)
-        .id()
+// Move the entity, its body follows on the next physics step
+fn move_to(world: &mut World, instance: &Instance, entity: Entity, hitbox: Hitbox) {
+    *world.get_mut::<Hitbox>(entity).unwrap() = hitbox;
+    let mut rb_pos = world.get_mut::<RigidBodyPosition>(entity).unwrap();
+    rb_pos.next_position.translation.x = instance.origin.x + hitbox.pos.x;
+    rb_pos.next_position.translation.y = instance.origin.y + hitbox.pos.y;
 }
 
-fn scroll_tubes(
-    mut commands: Commands,
-    mut tube_set: Query<(Entity, &mut RigidBodyPosition, &Tube)>,
+fn keyboard_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut env_state: ResMut<EnvironmentState>,
+    mut games: Query<(&Instance, &mut Game)>,
 ) {
-    for (e, mut rb_pos, tube) in tube_set.iter_mut() {
-        rb_pos.position.translation.x -= TUBE_SPEED;
-
-        // despawn when off screen and spawn new tube
-        if rb_pos.position.translation.x < TUBE_DESPAWN_LIMIT {
-            commands.entity(e).despawn();
-
-            match tube.0 {
-                TubeType::Top => spawn_tube_set(
-                    &mut commands,
-                    TUBE_COUNT as f32 * TUBE_SPACING + TUBE_DESPAWN_LIMIT,
-                ),
-                TubeType::Bottom => {}
-            }
+    let left = keyboard_input.pressed(KeyCode::A) || keyboard_input.pressed(KeyCode::Left);
+    let right = keyboard_input.pressed(KeyCode::D) || keyboard_input.pressed(KeyCode::Right);
+
+    env_state.action = if left {
+        Some(Action::Discrete(0))
+    } else if right {
+        Some(Action::Discrete(1))
+    } else if keyboard_input.pressed(KeyCode::Space) {
+        Some(Action::Discrete(2))
+    } else {
+        Some(Action::Discrete(3))
+    };
+
+    if keyboard_input.just_pressed(KeyCode::R) {
+        for (_, mut game) in games.iter_mut().filter(|(i, _)| i.index == 0) {
+            game.done = true;
         }
     }
 }
 
-// The bird can get stuck behind tubes and falls behind and skid off top of tubes
-// I could just trigger game over when a touch occurs but I like the effect
-// This is a system move bird back to x=0, so it can be used more than once or twice
-fn catchup_bird(
-    mut bird: Query<&mut RigidBodyPosition, With<Bird>>,
-) {
-    for mut rb_pos in bird.iter_mut() {
-        // poor mans lerp
-        let x = rb_pos.position.translation.x;
-        if x < 0.0 {
-            rb_pos.position.translation.x -= x / 60.0;
+fn setup_camera(mut commands: Commands) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, 50.0));
+    commands.spawn_bundle(camera);
+}
+
+fn spawn_wave(commands: &mut Commands, instance: &Instance) {
+    for row in 0..INVADER_ROWS {
+        for col in 0..INVADER_COLS {
+            spawn_box(
+                commands,
+                instance,
+                Hitbox::new(invader_home(row, col), INVADER_SIZE_HALF_X, INVADER_SIZE_HALF_Y),
+                Color::PURPLE,
+                Invader { row: row, col: col },
+            );
         }
     }
 }
 
-// Want to let the models trigger reset, so using events
-fn reset_listener(
-    mut commands: Commands,
-    mut ev_reset: EventReader<EnvironmentResetEvent>,
-    query_set: QuerySet<(Query<Entity, With<Bird>>, Query<Entity, With<Tube>>)>
-) {
-    let mut reset = false;
-    for _ in ev_reset.iter() {
-        reset = true;
-    }
-    if reset {
-        for e in query_set.q0().iter() {
-            commands.entity(e).despawn_recursive();
-        }
-        for e in query_set.q1().iter() {
-            commands.entity(e).despawn_recursive();
+// Shields are made of cells, each shot takes one out
+fn spawn_shields(commands: &mut Commands, instance: &Instance) {
+    let spacing = BOARD_SIZE_HALF_X * 2.0 / SHIELD_COUNT as f32;
+    let cell = SHIELD_CELL_SIZE_HALF * 2.0;
+    for shield in 0..SHIELD_COUNT {
+        let center_x = -BOARD_SIZE_HALF_X + spacing * (shield as f32 + 0.5);
+        for x in 0..SHIELD_CELLS_X {
+            for y in 0..SHIELD_CELLS_Y {
+                let pos = Vec2::new(
+                    center_x + (x as f32 - (SHIELD_CELLS_X - 1) as f32 * 0.5) * cell,
+                    SHIELD_Y + y as f32 * cell,
+                );
+                spawn_box(
+                    commands,
+                    instance,
+                    Hitbox::new(pos, SHIELD_CELL_SIZE_HALF, SHIELD_CELL_SIZE_HALF),
+                    Color::GREEN,
+                    ShieldCell,
+                );
+            }
         }
-
-        spawn_env(&mut commands);
     }
 }
 
-fn keyboard_input(
-    mut rigid_bodies: Query<&mut RigidBodyVelocity, With<Bird>>,
-    keyboard_input: Res<Input<KeyCode>>,
-    params: Res<IntegrationParameters>,
+fn spawn_box(
+    commands: &mut Commands,
+    instance: &Instance,
+    hitbox: Hitbox,
+    color: Color,
+    component: impl Component,
 ) {
-    for rb_vel in rigid_bodies.iter_mut() {
-        if keyboard_input.pressed(KeyCode::Space) {
-            action_up(rb_vel, &params);
-        }
-    }
+    commands
+        .spawn_bundle(RigidBodyBundle {
+            position: (instance.origin + hitbox.pos).into(),
+            body_type: RigidBodyType::KinematicPositionBased,
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            collider_type: ColliderType::Sensor,
+            shape: ColliderShape::cuboid(hitbox.half.x, hitbox.half.y),
+            flags: ColliderFlags {
+                collision_groups: instance.collision_groups(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(RigidBodyPositionSync::Discrete)
+        .insert(ColliderDebugRender::from(color))
+        .insert(hitbox)
+        .insert(component)
+        .insert(SpaceInvadersCleanup)
+        .insert(*instance);
 }
@@ -1,3 +1,6 @@
+use failure::Fail;
+
+#[allow(dead_code)]
 #[derive(Debug, Fail)]
 pub enum GymError {
 	#[fail(display = "Invalid action")]
@@ -8,4 +11,6 @@ pub enum GymError {
 	WrongType,
 	#[fail(display = "Unable to parse step result")]
 	WrongStepResult,
-}
\ No newline at end of file
+	#[fail(display = "Checkpoint failed: {}", _0)]
+	Checkpoint(String),
+}
@@ -0,0 +1,167 @@
+// Headless gym, drive an environment from rust code
+//
+// The app is built without a runner, each step applies the action then ticks the
+// schedule once, so exactly one physics step happens per action (unless wrappers repeat
+// it) and training runs as fast as the cpu allows.
+
+use bevy::prelude::*;
+use bevy_prototype_debug_lines::DebugLines;
+use bevy_rapier2d::physics::{NoUserData, RapierConfiguration, RapierPhysicsPlugin, TimestepMode};
+use bevy_rapier2d::prelude::IntegrationParameters;
+
+use crate::environment::*;
+use crate::environments::{load_environment, EnvironmentType};
+use crate::error::GymError;
+use crate::space::Action;
+
+pub const PHYSICS_DT: f32 = 3.0 / 60.0;
+
+pub struct Gym {
+    app: App,
+}
+
+#[allow(dead_code)]
+impl Gym {
+    pub fn new(env: EnvironmentType, config: EnvironmentConfig) -> Self {
+        Self::from_builder(Self::builder(env, config))
+    }
+
+    // Build the app without running it, useful to add more to it like a model
+    pub fn builder(env: EnvironmentType, config: EnvironmentConfig) -> AppBuilder {
+        let mut builder = App::build();
+        builder
+            .add_plugins(MinimalPlugins)
+            .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
+            .insert_resource(DebugLines::default())
+            .add_startup_system(setup_fixed_timestep.system());
+        load_environment(
+            &mut builder,
+            env,
+            EnvironmentConfig {
+                render: false,
+                ..config
+            },
+        );
+        builder
+    }
+
+    pub fn from_builder(mut builder: AppBuilder) -> Self {
+        let mut gym = Self {
+            app: std::mem::take(&mut builder.app),
+        };
+        // Run the startup systems, this will spawn the environment
+        gym.tick();
+        gym
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.app.world.get_resource::<Environment>().unwrap().clone()
+    }
+
+    // Clear and respawn the environment, returns the first observation
+    //
+    // With a seed the episode is replayed exactly
+    pub fn reset(&mut self, seed: Option<u64>) -> Vec<f32> {
+        if seed.is_some() {
+            self.app.world.insert_resource(EnvRng::new(seed));
+        }
+        let fns = *self.app.world.get_resource::<EnvironmentFns>().unwrap();
+        let observation = (fns.reset)(&mut self.app.world, &Instance::default());
+        let mut state = self.app.world.get_resource_mut::<EnvironmentState>().unwrap();
+        state.observation = observation.clone();
+        state.reward = 0.0;
+        state.is_done = None;
+        state.info.clear();
+        observation
+    }
+
+    // Take an action and advance the world one physics step, or more with action repeat
+    //
+    // Once a step is done, the next step will reset the environment first
+    pub fn step(&mut self, action: Action) -> Result<Step, GymError> {
+        if !self.environment().action_space.contains(&action) {
+            return Err(GymError::InvalidAction);
+        }
+        if self.state().is_done == Some(true) {
+            self.reset(None);
+        }
+
+        let fns = *self.app.world.get_resource::<EnvironmentFns>().unwrap();
+        let App { world, schedule, .. } = &mut self.app;
+        // The step ticks the schedule itself, keep the env systems out of it
+        let manual = world.contains_resource::<ManualStep>();
+        if !manual {
+            world.insert_resource(ManualStep);
+        }
+        let step = loop {
+            let mut advance = |world: &mut World| schedule.run(world);
+            if let Some(step) = (fns.step)(world, &Instance::default(), &action, &mut advance) {
+                break step;
+            }
+        };
+        if !manual {
+            world.remove_resource::<ManualStep>();
+        }
+
+        let mut state = world.get_resource_mut::<EnvironmentState>().unwrap();
+        state.action = Some(action);
+        state.observation = step.observation.clone();
+        state.reward = step.reward;
+        state.is_done = Some(step.is_done);
+        state.info = step.info.clone();
+        Ok(step)
+    }
+
+    // Advance the world once, without touching the action
+    pub fn tick(&mut self) {
+        self.app.update();
+    }
+
+    pub fn state(&self) -> &EnvironmentState {
+        self.app.world.get_resource::<EnvironmentState>().unwrap()
+    }
+
+    pub fn world(&self) -> &World {
+        &self.app.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.app.world
+    }
+}
+
+// Fixed so every tick is the same step no matter how fast we run
+fn setup_fixed_timestep(
+    mut params: ResMut<IntegrationParameters>,
+    mut rapier_config: ResMut<RapierConfiguration>,
+) {
+    rapier_config.timestep_mode = TimestepMode::FixedTimestep;
+    params.dt = PHYSICS_DT;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::test_config;
+
+    // Observations and rewards for a fixed list of actions
+    fn rollout(seed: u64) -> Vec<(Vec<f32>, f32)> {
+        let mut gym = Gym::new("cartpole".parse().unwrap(), test_config(Some(seed)));
+        let mut steps = vec![(gym.reset(Some(seed)), 0.0)];
+        for i in 0..50 {
+            let step = gym.step(Action::Discrete(i % 3 % 2)).unwrap();
+            steps.push((step.observation, step.reward));
+        }
+        steps
+    }
+
+    #[test]
+    fn same_seed_same_episodes() {
+        assert_eq!(rollout(42), rollout(42));
+    }
+
+    #[test]
+    fn different_seed_different_start() {
+        assert_ne!(rollout(1)[0], rollout(2)[0]);
+    }
+}
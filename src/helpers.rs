@@ -3,9 +3,10 @@ use std::fs;
 use bevy::{ecs::component::Component, prelude::*};
 use bevy_inspector_egui::*;
 use neat::parameters::Parameters;
+use serde::{Deserialize, Serialize};
 
 // Just used for const because Vec2 cant be
-#[derive(Inspectable,Debug)]
+#[derive(Inspectable, Debug, Serialize, Deserialize)]
 pub struct V2<T> {
     pub x: T,
     pub y: T,
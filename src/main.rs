@@ -1,9 +1,16 @@
 mod config;
 mod environment;
 mod environments;
+mod error;
+mod gym;
 mod helpers;
 mod models;
 mod menu;
+mod multi_agent;
+mod pixels;
+mod space;
+mod vec_env;
+mod wrappers;
 
 use bevy::prelude::*;
 use bevy_egui::EguiPlugin;
@@ -14,7 +21,10 @@ use bevy_rapier2d::prelude::{IntegrationParameters, PhysicsPipeline};
 use bevy_rapier2d::render::RapierRenderPlugin;
 use clap::Clap;
 use config::Config;
+use environment::EnvironmentConfig;
 use environments::*;
+use gym::{Gym, PHYSICS_DT};
+use models::{load_model, seed_models, train_vec_env};
 
 use crate::menu::MenuPlugin;
 
@@ -30,31 +40,44 @@ enum AppState {
 
 fn main() {
     let config = Config::parse();
-    let mut app = App::build();
+    // Only the toml, so it can be redirected to a file
+    if config.print_env_config {
+        match config.environment {
+            Some(e) => print!("{}", (e.entry().default_config)()),
+            None => eprintln!("--print-env-config needs an --environment"),
+        }
+        return;
+    }
+
     println!("{:?}", config);
+    if let Some(seed) = config.seed {
+        seed_models(seed, &config.model);
+    }
 
-    // Setup bevy
     if config.simulation {
-        app.add_plugins(MinimalPlugins);
-    } else {
-        app.insert_resource(Msaa { samples: 4 })
-            .insert_resource(ClearColor(Color::WHITE))
-            .insert_resource(WindowDescriptor {
-                title: match config.environment {
-                    Some(e) => e.to_string(),
-                    None => "Bevy Slyedoc Gym".to_string(),
-                },
-                ..Default::default()
-            })
-            .insert_resource(WorldInspectorParams {
-                enabled: true,
-                despawnable_entities: false,
-                ..Default::default()
-            })
-            .add_plugins(DefaultPlugins)
-            .add_plugin(EguiPlugin);
+        run_simulation(config);
+        return;
     }
 
+    // Setup bevy
+    let mut app = App::build();
+    app.insert_resource(Msaa { samples: 4 })
+        .insert_resource(ClearColor(Color::WHITE))
+        .insert_resource(WindowDescriptor {
+            title: match config.environment {
+                Some(e) => e.to_string(),
+                None => "Bevy Slyedoc Gym".to_string(),
+            },
+            ..Default::default()
+        })
+        .insert_resource(WorldInspectorParams {
+            enabled: true,
+            despawnable_entities: false,
+            ..Default::default()
+        })
+        .add_plugins(DefaultPlugins)
+        .add_plugin(EguiPlugin);
+
     // Setup Common Resources
     app
     .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
@@ -62,17 +85,34 @@ fn main() {
     .insert_resource(DebugLines {
         ..Default::default()
     })
-    .add_startup_system(setup_physics.system());
-
-    if !config.simulation {
-        app.add_plugin(RapierRenderPlugin);
-        app.add_plugin(MenuPlugin);
-    }
+    .add_startup_system(setup_physics.system())
+    .add_plugin(RapierRenderPlugin)
+    .add_plugin(MenuPlugin);
 
     // Add environment
     match config.environment {
         Some(e) => {
-            load_environment(&mut app, e, !config.simulation, config.human);
+            load_environment(
+                &mut app,
+                e,
+                EnvironmentConfig {
+                    render: true,
+                    human: config.human,
+                    model: config.model.clone(),
+                    seed: config.seed,
+                    checkpoint: config.checkpoint_config(),
+                    pixels: config.pixel_config(),
+                    wrappers: config.wrapper_config(),
+                    network: config.network.clone(),
+                    replay_size: config.replay_size,
+                    opponent: config.opponent,
+                    agents: None,
+                    env_config: config.env_config.clone(),
+                },
+            );
+            if !config.human {
+                load_model(&mut app, config.model.clone());
+            }
             app.add_state(AppState::Environment(e));
         }
         None => {
@@ -84,6 +124,39 @@ fn main() {
         .run();
 }
 
+// No window, step the world as fast as we can
+fn run_simulation(config: Config) {
+    let env = config
+        .environment
+        .expect("Simulation needs an environment, see --help");
+    let env_config = EnvironmentConfig {
+        render: false,
+        human: false,
+        model: config.model.clone(),
+        seed: config.seed,
+        checkpoint: config.checkpoint_config(),
+        pixels: config.pixel_config(),
+        wrappers: config.wrapper_config(),
+        network: config.network.clone(),
+        replay_size: config.replay_size,
+        opponent: config.opponent,
+        agents: None,
+        env_config: config.env_config.clone(),
+    };
+    if config.num_envs > 1 {
+        train_vec_env(env, config.num_envs, env_config);
+        return;
+    }
+
+    let mut app = Gym::builder(env, env_config);
+    load_model(&mut app, config.model);
+
+    let mut gym = Gym::from_builder(app);
+    loop {
+        gym.tick();
+    }
+}
+
 
 
 
@@ -92,7 +165,7 @@ fn setup_physics(
     mut rapier_config: ResMut<RapierConfiguration>,
 ) {
     rapier_config.timestep_mode = TimestepMode::VariableTimestep;
-    params.dt = 3.0 / 60.0
+    params.dt = PHYSICS_DT
 }
 
 
@@ -2,7 +2,7 @@ use bevy::app::AppExit;
 use bevy::prelude::*;
 use bevy_egui::{egui::*, *};
 
-use crate::{AppState, environments::EnvironmentType};
+use crate::{AppState, environments::{EnvironmentCategory, EnvironmentType}};
 
 pub struct MenuPlugin;
 impl Plugin for MenuPlugin {
@@ -25,12 +25,13 @@ fn draw_menu(
         .show(egui_ctx.ctx(), |ui| {
             ui.heading("Bevy Slyedoc Gym");
             ui.separator();
-            ui.label("Games");
-            if ui.button( EnvironmentType::Flappy.to_string()).clicked() {
-                state.set( AppState::Environment(EnvironmentType::Flappy)).unwrap();
-            }
-            if ui.button( EnvironmentType::Breakout.to_string()).clicked() {
-                state.set( AppState::Environment(EnvironmentType::Breakout)).unwrap();
+            for category in EnvironmentCategory::ALL.iter() {
+                ui.label(category.to_string());
+                for env in EnvironmentType::all().filter(|env| env.entry().category == *category) {
+                    if ui.button(env.to_string()).clicked() {
+                        state.set(AppState::Environment(env)).unwrap();
+                    }
+                }
             }
             ui.separator();
             if ui.button("Settings").clicked() {}
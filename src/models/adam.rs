@@ -0,0 +1,105 @@
+// Adam with its moment estimates kept in a VarStore, so checkpoints can save them
+//
+// tch's Adam keeps its state inside libtorch where it can't be read, a resumed run
+// would start the estimates from zero. Same defaults as nn::Adam, based on
+// https://arxiv.org/abs/1412.6980
+use tch::{nn, Device, Tensor};
+
+const BETA1: f64 = 0.9;
+const BETA2: f64 = 0.999;
+const EPS: f64 = 1e-8;
+
+pub struct Adam {
+    vs: nn::VarStore,
+    lr: f64,
+    params: Vec<Tensor>,
+    // First and second moment per param, same order
+    m: Vec<Tensor>,
+    v: Vec<Tensor>,
+    // Updates so far for the bias correction, a tensor so it's saved with the moments
+    t: Tensor,
+}
+
+impl Adam {
+    // Optimizes every trainable variable of the model, build it after the model
+    pub fn new(model: &nn::VarStore, lr: f64) -> Self {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let params = model.trainable_variables();
+        let root = vs.root();
+        let m = params
+            .iter()
+            .enumerate()
+            .map(|(i, p)| root.zeros_no_train(&format!("m{}", i), &p.size()))
+            .collect();
+        let v = params
+            .iter()
+            .enumerate()
+            .map(|(i, p)| root.zeros_no_train(&format!("v{}", i), &p.size()))
+            .collect();
+        let t = root.zeros_no_train("t", &[1]);
+
+        Self {
+            vs: vs,
+            lr: lr,
+            params: params,
+            m: m,
+            v: v,
+            t: t,
+        }
+    }
+
+    pub fn backward_step(&mut self, loss: &Tensor) {
+        for param in self.params.iter_mut() {
+            param.zero_grad();
+        }
+        loss.backward();
+
+        let lr = self.lr;
+        let (params, m, v, t) = (&mut self.params, &mut self.m, &mut self.v, &mut self.t);
+        tch::no_grad(|| {
+            *t += 1.0;
+            let t = f64::from(&*t);
+            let correction1 = 1.0 - BETA1.powf(t);
+            let correction2 = 1.0 - BETA2.powf(t);
+            for ((param, m), v) in params.iter_mut().zip(m.iter_mut()).zip(v.iter_mut()) {
+                let grad = param.grad();
+                // Params the loss doesn't reach have no gradient
+                if !grad.defined() {
+                    continue;
+                }
+                *m *= BETA1;
+                *m += &grad * (1.0 - BETA1);
+                *v *= BETA2;
+                *v += &grad * &grad * (1.0 - BETA2);
+                *param -= (&*m / correction1) / ((&*v / correction2).sqrt() + EPS) * lr;
+            }
+        });
+    }
+
+    // The moments and update count, to checkpoint next to the model
+    pub fn var_store(&mut self) -> &mut nn::VarStore {
+        &mut self.vs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimizes_and_keeps_state_in_var_store() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let x = vs.root().zeros("x", &[1]);
+        let mut adam = Adam::new(&vs, 0.1);
+        for _ in 0..500 {
+            let loss = (&x - 3.0) * (&x - 3.0);
+            adam.backward_step(&loss.sum(tch::Kind::Float));
+        }
+        assert!((f64::from(&x) - 3.0).abs() < 0.05);
+
+        // Moments and the update count are what a checkpoint saves
+        let variables = adam.var_store().variables();
+        assert_eq!(f64::from(&variables["t"]), 500.0);
+        assert!(variables.contains_key("m0") && variables.contains_key("v0"));
+    }
+}
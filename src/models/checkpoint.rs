@@ -0,0 +1,114 @@
+// Save and resume tch models
+//
+// A checkpoint is a directory with one file per VarStore plus the step counter, written
+// every CHECKPOINT_EVERY steps to the run directory as checkpoint-<steps>.
+//
+// The models use our own Adam so its moment estimates are a VarStore saved with the
+// weights, a resumed run picks up where it stopped, exploration schedules included.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use tch::nn;
+
+use crate::error::GymError;
+
+pub const CHECKPOINT_EVERY: usize = 10_000;
+const STEPS_FILE: &str = "steps";
+
+#[derive(Debug, Clone)]
+pub struct CheckpointConfig {
+    // Where checkpoints are written
+    pub run_dir: PathBuf,
+    // Checkpoint directory to resume from, for neat the directory with population.json
+    pub resume: Option<PathBuf>,
+    // Neat champion to play instead of evolving
+    pub genome: Option<PathBuf>,
+}
+
+// Last step count we saved at, or resumed from, so each checkpoint is only written once
+struct LastCheckpoint(usize);
+
+pub trait Checkpoint {
+    // Every VarStore the model needs, by file name
+    fn var_stores(&mut self) -> Vec<(&'static str, &mut nn::VarStore)>;
+
+    fn steps(&self) -> usize;
+
+    fn set_steps(&mut self, steps: usize);
+
+    fn save(&mut self, dir: &Path) -> Result<(), GymError> {
+        fs::create_dir_all(dir).map_err(|e| GymError::Checkpoint(e.to_string()))?;
+        fs::write(dir.join(STEPS_FILE), self.steps().to_string())
+            .map_err(|e| GymError::Checkpoint(e.to_string()))?;
+        for (name, vs) in self.var_stores() {
+            vs.save(dir.join(format!("{}.ot", name)))
+                .map_err(|e| GymError::Checkpoint(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, dir: &Path) -> Result<(), GymError> {
+        let steps = fs::read_to_string(dir.join(STEPS_FILE))
+            .map_err(|e| GymError::Checkpoint(e.to_string()))?;
+        let steps = steps
+            .trim()
+            .parse()
+            .map_err(|_| GymError::Checkpoint(format!("bad step count: {}", steps)))?;
+        for (name, vs) in self.var_stores() {
+            vs.load(dir.join(format!("{}.ot", name)))
+                .map_err(|e| GymError::Checkpoint(e.to_string()))?;
+        }
+        self.set_steps(steps);
+        Ok(())
+    }
+}
+
+// Resume if asked and add the system writing checkpoints, the model needs to be inserted first
+pub fn add_checkpoints<M: Checkpoint + 'static>(app: &mut AppBuilder, config: CheckpointConfig) {
+    let steps = {
+        let mut model = app.world_mut().get_non_send_resource_mut::<M>().unwrap();
+        resume_checkpoint(&mut *model, &config);
+        model.steps()
+    };
+
+    app.insert_resource(config)
+        .insert_resource(LastCheckpoint(steps))
+        .add_system(checkpoint_system::<M>.exclusive_system().at_start());
+}
+
+// Load the checkpoint to resume from, if there is one
+pub fn resume_checkpoint<M: Checkpoint>(model: &mut M, config: &CheckpointConfig) {
+    if let Some(resume) = &config.resume {
+        if let Err(e) = model.load(resume) {
+            panic!("Couldn't load checkpoint {}: {}", resume.display(), e);
+        }
+        println!("Resumed from {} at step {}", resume.display(), model.steps());
+    }
+}
+
+// Save once the steps pass a multiple of CHECKPOINT_EVERY since last, models stepping
+// several instances at once won't land on it exactly
+pub fn checkpoint_if_due<M: Checkpoint>(model: &mut M, run_dir: &Path, last: &mut usize) {
+    let steps = model.steps();
+    if steps / CHECKPOINT_EVERY <= *last / CHECKPOINT_EVERY {
+        return;
+    }
+    *last = steps;
+
+    let dir = run_dir.join(format!("checkpoint-{}", steps));
+    match model.save(&dir) {
+        Ok(_) => println!("Checkpoint saved to {}", dir.display()),
+        // Keep training, a missed checkpoint is better than a crash
+        Err(e) => eprintln!("Checkpoint to {} failed: {}", dir.display(), e),
+    }
+}
+
+fn checkpoint_system<M: Checkpoint + 'static>(world: &mut World) {
+    let run_dir = world.get_resource::<CheckpointConfig>().unwrap().run_dir.clone();
+    let mut last = world.get_resource::<LastCheckpoint>().unwrap().0;
+    checkpoint_if_due(&mut *world.get_non_send_resource_mut::<M>().unwrap(), &run_dir, &mut last);
+    world.get_resource_mut::<LastCheckpoint>().unwrap().0 = last;
+}
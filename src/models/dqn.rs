@@ -0,0 +1,198 @@
+// Deep Q-Network
+//
+// Off-policy, learns Q values from a replay buffer with a target network kept in sync
+// every so often, optionally Double DQN where the online network picks the next action.
+// Based on https://www.nature.com/articles/nature14236 and https://arxiv.org/abs/1509.06461
+use bevy::prelude::World;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use tch::{
+    nn,
+    Device, Kind,
+    Reduction, Tensor,
+};
+
+use crate::{environment::EnvironmentState, space::Action};
+
+use super::{
+    adam::Adam,
+    checkpoint::Checkpoint,
+    network::{Activation, NetworkSpec},
+    replay::{ReplayBatch, ReplayBuffer, Transition},
+    self_play::{FrozenPolicy, SelfPlay},
+    MLModel,
+};
+
+pub const BUFFER_SIZE: usize = 50_000; // default, see replay::default_capacity
+const BATCH_SIZE: usize = 64;
+const WARMUP: usize = 1_000; // steps before training starts
+const TARGET_SYNC: usize = 500; // steps between target network copies
+const GAMMA: f64 = 0.99;
+const LEARNING_RATE: f64 = 1e-3;
+const EPSILON_START: f32 = 1.0;
+const EPSILON_END: f32 = 0.05;
+const EPSILON_DECAY_STEPS: usize = 10_000;
+
+pub struct DqnModel {
+    vs: nn::VarStore,
+    target_vs: nn::VarStore,
+    pub model: nn::Sequential,
+    pub target: nn::Sequential,
+    pub opt: Adam,
+    pub double: bool,
+    // What the model was built from, for snapshots
+    network: NetworkSpec,
+    input_shape: Vec<i64>,
+    action_space: i64,
+    buffer: ReplayBuffer,
+    last_action: Option<(Vec<f32>, i64)>,
+    steps: usize,
+    rng: Pcg64,
+}
+
+impl DqnModel {
+    pub fn default_network() -> NetworkSpec {
+        NetworkSpec::mlp(&[64, 64], Activation::Relu)
+    }
+
+    pub fn new(
+        input_shape: &[i64],
+        output: i64,
+        network: &NetworkSpec,
+        double: bool,
+        buffer_size: usize,
+        rng: Pcg64,
+    ) -> Self {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mut target_vs = nn::VarStore::new(Device::Cpu);
+        let model = network.build(vs.root(), input_shape, output);
+        let target = network.build(target_vs.root(), input_shape, output);
+        target_vs.copy(&vs).unwrap();
+        let opt = Adam::new(&vs, LEARNING_RATE);
+
+        Self {
+            vs: vs,
+            target_vs: target_vs,
+            model: model,
+            target: target,
+            opt: opt,
+            double: double,
+            network: network.clone(),
+            input_shape: input_shape.to_vec(),
+            action_space: output,
+            buffer: ReplayBuffer::new(buffer_size),
+            last_action: None,
+            steps: 0,
+            rng: rng,
+        }
+    }
+
+    // Linear decay from start to end
+    fn epsilon(&self) -> f32 {
+        let progress = (self.steps as f32 / EPSILON_DECAY_STEPS as f32).min(1.0);
+        EPSILON_START + (EPSILON_END - EPSILON_START) * progress
+    }
+
+    fn act(&mut self, observation: &[f32]) -> i64 {
+        if self.rng.gen::<f32>() < self.epsilon() {
+            return self.rng.gen_range(0..self.action_space);
+        }
+        tch::no_grad(|| {
+            let q = Tensor::of_slice(observation).unsqueeze(0).apply(&self.model);
+            i64::from(q.argmax(1, false))
+        })
+    }
+
+    pub fn train(&mut self) {
+        let ReplayBatch {
+            observations: obs,
+            actions,
+            rewards,
+            next_observations: next_obs,
+            not_done,
+        } = self.buffer.sample(&mut self.rng, BATCH_SIZE);
+        let actions = actions.to_kind(Kind::Int64);
+
+        let targets = tch::no_grad(|| {
+            let next_q = if self.double {
+                let next_actions = next_obs.apply(&self.model).argmax(1, true);
+                next_obs
+                    .apply(&self.target)
+                    .gather(1, &next_actions, false)
+                    .view([-1])
+            } else {
+                next_obs.apply(&self.target).max_dim(1, false).0
+            };
+            rewards + next_q * not_done * GAMMA
+        });
+
+        let q = obs.apply(&self.model).gather(1, &actions, false).view([-1]);
+        let loss = q.mse_loss(&targets, Reduction::Mean);
+        self.opt.backward_step(&loss);
+    }
+
+    fn sync_target(&mut self) {
+        self.target_vs.copy(&self.vs).unwrap();
+    }
+}
+
+impl MLModel for DqnModel {
+    fn update_action(world: &mut World) {
+        let state = world.get_resource::<EnvironmentState>().unwrap().clone();
+        let mut dqn = world.get_non_send_resource_mut::<DqnModel>().unwrap();
+
+        // Store what our last action did, then learn from a random batch
+        if let (Some((observation, action)), Some(is_done)) = (dqn.last_action.take(), state.is_done) {
+            dqn.buffer.push(Transition {
+                observation: observation,
+                action: vec![action as f32],
+                reward: state.reward,
+                next_observation: state.observation.clone(),
+                terminal: is_done && !state.info.contains_key("truncated"),
+            });
+            dqn.steps += 1;
+            if dqn.buffer.len() >= WARMUP {
+                dqn.train();
+            }
+            if dqn.steps % TARGET_SYNC == 0 {
+                dqn.sync_target();
+            }
+            if is_done {
+                return;
+            }
+        }
+
+        let action = dqn.act(&state.observation);
+        dqn.last_action = Some((state.observation, action));
+
+        world.get_resource_mut::<EnvironmentState>().unwrap().action =
+            Some(Action::Discrete(action as usize));
+    }
+}
+
+impl Checkpoint for DqnModel {
+    fn var_stores(&mut self) -> Vec<(&'static str, &mut nn::VarStore)> {
+        vec![
+            ("model", &mut self.vs),
+            ("target", &mut self.target_vs),
+            ("adam", self.opt.var_store()),
+        ]
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn set_steps(&mut self, steps: usize) {
+        self.steps = steps;
+    }
+}
+
+impl SelfPlay for DqnModel {
+    fn snapshot(&self) -> FrozenPolicy {
+        let mut vs = nn::VarStore::new(Device::Cpu);
+        let model = self.network.build(vs.root(), &self.input_shape, self.action_space);
+        vs.copy(&self.vs).unwrap();
+        FrozenPolicy::greedy(vs, model)
+    }
+}
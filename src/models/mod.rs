@@ -1,13 +1,44 @@
+pub mod adam;
+pub mod checkpoint;
+pub mod dqn;
+pub mod network;
 pub mod policy_gradient;
 pub mod neat;
+pub mod ppo;
+pub mod replay;
+pub mod sac;
+pub mod self_play;
 
 use std::str::FromStr;
 
-use bevy::prelude::World;
+use bevy::prelude::*;
+use rand_pcg::Pcg64;
+
+use crate::{
+    environment::{EnvRng, Environment, EnvironmentConfig, OpponentType},
+    environments::EnvironmentType,
+    space::Space,
+    vec_env::VecEnv,
+    wrappers::Wrappers,
+};
+
+use self::{
+    checkpoint::{add_checkpoints, checkpoint_if_due, resume_checkpoint, Checkpoint, CheckpointConfig},
+    dqn::DqnModel,
+    network::{NetworkSpec, NATURE_CNN_MIN_SIZE},
+    policy_gradient::PolicyGradientModel,
+    ppo::PpoModel,
+    replay::default_capacity,
+    sac::SacModel,
+    self_play::{self_play_system, SelfPlay, SelfPlayOpponent},
+};
 
 #[derive(Clone, Debug)]
 pub enum ModelType {
     PolicyGradient,
+    Ppo,
+    Dqn,
+    Sac,
     Neat,
 }
 
@@ -18,6 +49,9 @@ impl FromStr for ModelType {
         match s.to_lowercase().as_str() {
             "policy_gradient" => Ok(ModelType::PolicyGradient),
             "pg" => Ok(ModelType::PolicyGradient),
+            "ppo" => Ok(ModelType::Ppo),
+            "dqn" => Ok(ModelType::Dqn),
+            "sac" => Ok(ModelType::Sac),
             "neat" => Ok(ModelType::Neat),
             _ => Err("no model match"),
         }
@@ -26,4 +60,190 @@ impl FromStr for ModelType {
 
 pub trait MLModel {
     fn update_action(world: &mut World);
-}
\ No newline at end of file
+}
+
+// Models that can learn from several copies of an environment at once, see train_vec_env
+pub trait VecModel: Checkpoint {
+    // Step every instance at least once and learn from it, observations are the
+    // current ones and get replaced by the next
+    fn train_vec(&mut self, vec_env: &mut VecEnv, observations: &mut Vec<Vec<f32>>);
+}
+
+fn model_system<M: MLModel>(world: &mut World) {
+    // Keep the action while the environment is repeating it
+    if world.get_resource::<Wrappers>().map_or(false, |w| w.is_repeating(0)) {
+        return;
+    }
+    M::update_action(world);
+}
+
+// Seed the models, environments are seeded through EnvironmentConfig
+//
+// Not neat, its Pool takes no rng or seed, so a seeded neat run
+// still evolves differently each time until the neat crate accepts one
+pub fn seed_models(seed: u64, model: &ModelType) {
+    tch::manual_seed(seed as i64);
+    if matches!(model, ModelType::Neat) {
+        eprintln!("--seed doesn't seed neat's evolution, only the environments");
+    }
+}
+
+// Adds a model to drive the environment, needs to be called after the environment is loaded
+//
+// Tch-rs is not thread safe, so models are non send resources and run as exclusive systems
+pub fn load_model(app: &mut AppBuilder, model: ModelType) {
+    let env = app
+        .world()
+        .get_resource::<Environment>()
+        .expect("Environment should be loaded before the model")
+        .clone();
+    let config = app.world().get_resource::<EnvironmentConfig>().unwrap();
+    let checkpoint = config.checkpoint.clone();
+    let spec = config.network.clone();
+    let replay_size = config.replay_size;
+    let self_play = config.opponent == OpponentType::SelfPlay;
+    let input_shape: Vec<i64> = env.observation_space.shape().iter().map(|s| *s as i64).collect();
+    let network = |default: NetworkSpec| pick_network(&spec, &input_shape, default);
+
+    match model {
+        ModelType::PolicyGradient => {
+            if !matches!(env.action_space, Space::Discrete(_)) {
+                panic!("Policy gradient needs a discrete action space, got {:?}", env.action_space);
+            }
+            app.insert_non_send_resource(PolicyGradientModel::new(
+                &input_shape,
+                env.action_space.output_size() as i64,
+                &network(PolicyGradientModel::default_network()),
+            ))
+            .add_system(model_system::<PolicyGradientModel>.exclusive_system().at_start());
+            add_checkpoints::<PolicyGradientModel>(app, checkpoint);
+            if self_play {
+                add_self_play::<PolicyGradientModel>(app);
+            }
+        }
+        ModelType::Ppo => {
+            if !matches!(env.action_space, Space::Discrete(_)) {
+                panic!("PPO needs a discrete action space, got {:?}", env.action_space);
+            }
+            app.insert_non_send_resource(PpoModel::new(
+                &input_shape,
+                env.action_space.output_size() as i64,
+                &network(PpoModel::default_network()),
+            ))
+            .add_system(model_system::<PpoModel>.exclusive_system().at_start());
+            add_checkpoints::<PpoModel>(app, checkpoint);
+            if self_play {
+                add_self_play::<PpoModel>(app);
+            }
+        }
+        ModelType::Dqn => {
+            if !matches!(env.action_space, Space::Discrete(_)) {
+                panic!("DQN needs a discrete action space, got {:?}", env.action_space);
+            }
+            let rng = model_rng(app);
+            app.insert_non_send_resource(DqnModel::new(
+                &input_shape,
+                env.action_space.output_size() as i64,
+                &network(DqnModel::default_network()),
+                true,
+                replay_size.unwrap_or_else(|| default_capacity(dqn::BUFFER_SIZE, env.observation_space.flat_size())),
+                rng,
+            ))
+            .add_system(model_system::<DqnModel>.exclusive_system().at_start());
+            add_checkpoints::<DqnModel>(app, checkpoint);
+            if self_play {
+                add_self_play::<DqnModel>(app);
+            }
+        }
+        ModelType::Sac => {
+            if self_play {
+                panic!("Self-play needs a discrete model, pg, ppo or dqn");
+            }
+            let (low, high) = match &env.action_space {
+                Space::Box { low, high, .. } => (low.clone(), high.clone()),
+                _ => panic!("SAC needs a continuous action space, got {:?}", env.action_space),
+            };
+            let rng = model_rng(app);
+            app.insert_non_send_resource(SacModel::new(
+                env.observation_space.flat_size() as i64,
+                low,
+                high,
+                replay_size.unwrap_or_else(|| default_capacity(sac::BUFFER_SIZE, env.observation_space.flat_size())),
+                rng,
+            ))
+            .add_system(model_system::<SacModel>.exclusive_system().at_start());
+            add_checkpoints::<SacModel>(app, checkpoint);
+        }
+        // Neat runs a population, environments that support it add it themselves
+        ModelType::Neat => {}
+    }
+}
+
+// Trains pg or ppo on n copies of the environment in one VecEnv, runs until killed
+//
+// Nothing is rendered, the copies step as fast as the models can keep up
+pub fn train_vec_env(env: EnvironmentType, n: usize, config: EnvironmentConfig) {
+    if config.opponent == OpponentType::SelfPlay {
+        panic!("Self-play isn't supported with several environments");
+    }
+    let model = config.model.clone();
+    let checkpoint = config.checkpoint.clone();
+    let spec = config.network.clone();
+    let vec_env = VecEnv::new(env, n, config);
+    let env = vec_env.environment();
+    if !matches!(env.action_space, Space::Discrete(_)) {
+        panic!("{:?} needs a discrete action space, got {:?}", model, env.action_space);
+    }
+    let input_shape: Vec<i64> = env.observation_space.shape().iter().map(|s| *s as i64).collect();
+    let output = env.action_space.output_size() as i64;
+
+    match model {
+        ModelType::PolicyGradient => {
+            let network = pick_network(&spec, &input_shape, PolicyGradientModel::default_network());
+            let model = PolicyGradientModel::new(&input_shape, output, &network);
+            run_vec_env(model, vec_env, &checkpoint);
+        }
+        ModelType::Ppo => {
+            let network = pick_network(&spec, &input_shape, PpoModel::default_network());
+            let model = PpoModel::new(&input_shape, output, &network);
+            run_vec_env(model, vec_env, &checkpoint);
+        }
+        _ => panic!("Only pg and ppo can train on several environments, got {:?}", model),
+    }
+}
+
+fn run_vec_env<M: VecModel>(mut model: M, mut vec_env: VecEnv, checkpoint: &CheckpointConfig) {
+    resume_checkpoint(&mut model, checkpoint);
+    let mut last = model.steps();
+    let mut observations = vec_env.reset(None);
+    loop {
+        model.train_vec(&mut vec_env, &mut observations);
+        checkpoint_if_due(&mut model, &checkpoint.run_dir, &mut last);
+    }
+}
+
+// Each model has its own default, images big enough for it get the nature cnn
+fn pick_network(spec: &Option<NetworkSpec>, input_shape: &[i64], default: NetworkSpec) -> NetworkSpec {
+    match (spec, input_shape) {
+        (Some(spec), _) => spec.clone(),
+        (None, [_, height, width]) if *height >= NATURE_CNN_MIN_SIZE && *width >= NATURE_CNN_MIN_SIZE => {
+            NetworkSpec::nature_cnn()
+        }
+        (None, _) => default,
+    }
+}
+
+// A frozen snapshot of the model drives the environment's Opponent
+fn add_self_play<M: SelfPlay + 'static>(app: &mut AppBuilder) {
+    app.insert_non_send_resource(SelfPlayOpponent::default())
+        .add_system(self_play_system::<M>.exclusive_system().at_start());
+}
+
+// Same seed as the environment, so a run can be repeated
+fn model_rng(app: &AppBuilder) -> Pcg64 {
+    let seed = app
+        .world()
+        .get_resource::<EnvironmentConfig>()
+        .and_then(|config| config.seed);
+    EnvRng::new(seed).0
+}
@@ -1,21 +1,47 @@
 // Neat example.
 //
 // https://github.com/suhdonghwi/neat
+//
+// Each generation the champion and the whole population are saved as json to
+// <run_dir>/neat/<env>, resume evolving with --checkpoint pointing at that directory
+// or play the champion with --load-genome.
+//
+// Pool, InnovationRecord and Feedforward derive serde upstream at the rev pinned in
+// Cargo.toml, assert_serde below fails the build if a bump drops it.
 
-use std::time::Duration;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use crate::helpers;
+use bevy::prelude::*;
+
+use crate::{
+    environment::Environment,
+    error::GymError,
+    helpers,
+    models::checkpoint::CheckpointConfig,
+    multi_agent::MultiAgentState,
+    space::{Action, Space},
+};
 use neat::innovation_record::InnovationRecord;
 use neat::network::feedforward::Feedforward;
 use neat::network::Network;
 use neat::pool::Pool;
 
+const POPULATION_FILE: &str = "population.json";
+const CHAMPION_FILE: &str = "champion.json";
+
 pub struct NeatML {
     innov_record: InnovationRecord,
     pub pool: Pool<Feedforward>,
     pub population: usize,
-    generation_start: Duration,
+    // Physics steps since the generation started, what agents are scored on
+    generation_steps: usize,
     complete_agents: Vec<CompleteAgent>,
+    save_dir: Option<PathBuf>,
+    // When set we only play this network, no evolution
+    champion: Option<Feedforward>,
 }
 
 pub struct CompleteAgent {
@@ -24,7 +50,7 @@ pub struct CompleteAgent {
 }
 
 impl NeatML {
-    pub fn new(path: &str, start: Duration, verbosity: bool) -> Self {
+    pub fn new(path: &str, verbosity: bool) -> Self {
         let verbosity = match verbosity {
             true => 1,
             false => 0,
@@ -37,14 +63,52 @@ impl NeatML {
             innov_record: innov_record,
             pool: pool,
             population: params.population,
-            generation_start: start,
+            generation_steps: 0,
             complete_agents: Vec::new(),
+            save_dir: None,
+            champion: None,
         }
     }
 
-    pub fn next_generation(&mut self, time: Duration) {
+    // Save each generation under the run directory, and resume or load a champion if asked
+    pub fn with_checkpoints(mut self, name: &str, config: &CheckpointConfig) -> Self {
+        self.save_dir = Some(config.run_dir.join("neat").join(name));
+
+        if let Some(genome) = &config.genome {
+            let champion = read_json(genome)
+                .unwrap_or_else(|e| panic!("Couldn't load genome {}: {}", genome.display(), e));
+            self.champion = Some(champion);
+            self.population = 1;
+            println!("Playing champion from {}", genome.display());
+        } else if let Some(resume) = &config.resume {
+            let (pool, innov_record) = read_json(&resume.join(POPULATION_FILE))
+                .unwrap_or_else(|e| panic!("Couldn't resume from {}: {}", resume.display(), e));
+            self.pool = pool;
+            self.innov_record = innov_record;
+            println!("Resumed from {} at generation {}", resume.display(), self.pool.generation());
+        }
+        self
+    }
+
+    // Run an agent's network, the champion stands in for every agent when loaded
+    pub fn activate(&mut self, index: usize, inputs: &[f64]) -> Vec<f64> {
+        match &mut self.champion {
+            Some(champion) => champion.activate(inputs).unwrap(),
+            None => self.pool.activate_nth(index, inputs).unwrap(),
+        }
+    }
+
+    pub fn next_generation(&mut self) {
         let generation = self.pool.generation();
+        self.generation_steps = 0;
 
+        if self.champion.is_some() {
+            if let Some(agent) = self.complete_agents.pop() {
+                println!(" champion fitness: {}", agent.fitness);
+            }
+            self.complete_agents.clear();
+            return;
+        }
 
         if self.complete_agents.len() > 0 {
             // build up fitness list, and reset complete agents
@@ -68,14 +132,138 @@ impl NeatML {
             );
 
             self.pool.evolve(&mut self.innov_record);
-            self.generation_start = time;
+
+            if let Err(e) = self.save(&best_network) {
+                eprintln!("Saving generation {} failed: {}", generation, e);
+            }
         }
     }
 
-    pub fn record_complete_agent(&mut self, index: usize, time: Duration) {
+    // Count a physics step of the generation
+    pub fn step(&mut self) {
+        self.generation_steps += 1;
+    }
+
+    // Fitness is how many steps the agent lasted, the same however fast the app runs
+    pub fn record_complete_agent(&mut self, index: usize) {
+        self.record_fitness(index, self.generation_steps as f64);
+    }
+
+    pub fn record_fitness(&mut self, index: usize, fitness: f64) {
         self.complete_agents.push(CompleteAgent {
             index: index,
-            fitness: (time - self.generation_start).as_secs_f64(),
+            fitness: fitness,
         })
     }
+
+    // Champion of the last generation and the evolved population, ready for the next
+    fn save(&self, champion: &Feedforward) -> Result<(), GymError> {
+        let dir = match &self.save_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        fs::create_dir_all(dir).map_err(|e| GymError::Checkpoint(e.to_string()))?;
+        write_json(&dir.join(CHAMPION_FILE), champion)?;
+        write_json(&dir.join(POPULATION_FILE), &(&self.pool, &self.innov_record))
+    }
+}
+
+// Drives a multi-agent environment, one genome per agent, see add_multi_agent_systems
+//
+// Fitness is how long the agent lasted, once every agent is done neat runs though a
+// generation and the environment resets itself
+pub fn neat_agents_system(world: &mut World) {
+    let action_space = world.get_resource::<Environment>().unwrap().action_space.clone();
+    world.resource_scope(|world, mut neat: Mut<NeatML>| {
+        let mut state = world.get_resource_mut::<MultiAgentState>().unwrap();
+        for (agent, _) in state.steps.iter().filter(|(_, step)| step.is_done) {
+            neat.record_complete_agent(*agent);
+        }
+        // Only record them once
+        state.steps.clear();
+
+        if state.agents.is_empty() {
+            neat.next_generation();
+            return;
+        }
+        neat.step();
+
+        let actions = state
+            .agents
+            .iter()
+            .map(|agent| {
+                let observation: Vec<f64> = state.observations[agent].iter().map(|v| *v as f64).collect();
+                (*agent, output_action(&neat.activate(*agent, &observation), &action_space))
+            })
+            .collect();
+        state.actions = actions;
+    });
+}
+
+// A single output is split evenly across a discrete space, otherwise the largest output wins
+fn output_action(output: &[f64], action_space: &Space) -> Action {
+    match action_space {
+        Space::Discrete(n) if output.len() == 1 => {
+            Action::Discrete(((output[0].clamp(0.0, 1.0) * *n as f64) as usize).min(n - 1))
+        }
+        Space::Discrete(_) => {
+            let (action, _) = output
+                .iter()
+                .enumerate()
+                .fold((0, f64::NEG_INFINITY), |best, (i, o)| if *o > best.1 { (i, *o) } else { best });
+            Action::Discrete(action)
+        }
+        Space::Box { .. } => Action::Box(output.iter().map(|o| *o as f32).collect()),
+        _ => panic!("neat doesn't support {:?}", action_space),
+    }
+}
+
+// Checked at compile time so a neat bump that drops serde can't slip through
+#[allow(dead_code)]
+fn assert_serde() {
+    fn serde<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+    serde::<Pool<Feedforward>>();
+    serde::<InnovationRecord>();
+    serde::<Feedforward>();
+}
+
+fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), GymError> {
+    let json = serde_json::to_string(value).map_err(|e| GymError::Checkpoint(e.to_string()))?;
+    fs::write(path, json).map_err(|e| GymError::Checkpoint(e.to_string()))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, GymError> {
+    let json = fs::read_to_string(path).map_err(|e| GymError::Checkpoint(e.to_string()))?;
+    serde_json::from_str(&json).map_err(|e| GymError::Checkpoint(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn population_and_champion_round_trip() {
+        let mut neat = NeatML::new("./params/flappy.toml", false);
+        let dir = std::env::temp_dir().join(format!("neat-round-trip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let inputs = [0.1, 0.5, -0.3];
+
+        write_json(&dir.join(POPULATION_FILE), &(&neat.pool, &neat.innov_record)).unwrap();
+        let (mut pool, _): (Pool<Feedforward>, InnovationRecord) =
+            read_json(&dir.join(POPULATION_FILE)).unwrap();
+        assert_eq!(pool.generation(), neat.pool.generation());
+        for i in 0..neat.population {
+            assert_eq!(
+                pool.activate_nth(i, &inputs).unwrap(),
+                neat.pool.activate_nth(i, &inputs).unwrap()
+            );
+        }
+
+        let mut champion = neat.pool.evaluate(|_, network| network.evaluate(1.0)).clone();
+        write_json(&dir.join(CHAMPION_FILE), &champion).unwrap();
+        let mut loaded: Feedforward = read_json(&dir.join(CHAMPION_FILE)).unwrap();
+        assert_eq!(loaded.activate(&inputs).unwrap(), champion.activate(&inputs).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
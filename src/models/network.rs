@@ -0,0 +1,117 @@
+// Network architecture spec
+//
+// The tch models build their networks from a NetworkSpec, so the same model can learn
+// from an environment's values with an mlp or from pixels with a conv stack.
+// On the command line: "mlp:64,64", "mlp:64,64:relu" or "cnn".
+use std::str::FromStr;
+
+use tch::nn;
+
+// Smallest image the nature cnn's convs fit
+pub const NATURE_CNN_MIN_SIZE: i64 = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    Relu,
+    Tanh,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkSpec {
+    // Fully connected, one entry per hidden layer
+    Mlp {
+        hidden: Vec<i64>,
+        activation: Activation,
+    },
+    // The Nature DQN conv stack then one hidden layer, needs [channels, height, width] observations
+    NatureCnn { hidden: i64 },
+}
+
+impl NetworkSpec {
+    pub fn mlp(hidden: &[i64], activation: Activation) -> Self {
+        NetworkSpec::Mlp {
+            hidden: hidden.to_vec(),
+            activation: activation,
+        }
+    }
+
+    pub fn nature_cnn() -> Self {
+        NetworkSpec::NatureCnn { hidden: 512 }
+    }
+
+    // Takes flat observations, as environments return them, input_shape is the
+    // observation space shape
+    pub fn build(&self, p: nn::Path, input_shape: &[i64], output: i64) -> nn::Sequential {
+        match self {
+            NetworkSpec::Mlp { hidden, activation } => {
+                let mut size = input_shape.iter().product();
+                let mut seq = nn::seq();
+                for (i, layer) in hidden.iter().enumerate() {
+                    seq = seq.add(nn::linear(&p / format!("lin{}", i + 1), size, *layer, Default::default()));
+                    seq = match activation {
+                        Activation::Relu => seq.add_fn(|xs| xs.relu()),
+                        Activation::Tanh => seq.add_fn(|xs| xs.tanh()),
+                    };
+                    size = *layer;
+                }
+                seq.add(nn::linear(&p / format!("lin{}", hidden.len() + 1), size, output, Default::default()))
+            }
+            NetworkSpec::NatureCnn { hidden } => {
+                let (channels, height, width) = match input_shape {
+                    [channels, height, width] => (*channels, *height, *width),
+                    _ => panic!("Nature cnn needs [channels, height, width] observations, got {:?}", input_shape),
+                };
+                // Size after each conv, no padding
+                let conv_out = |size: i64| {
+                    let size = (size - 8) / 4 + 1;
+                    let size = (size - 4) / 2 + 1;
+                    size - 3 + 1
+                };
+                let stride = |stride| nn::ConvConfig {
+                    stride: stride,
+                    ..Default::default()
+                };
+                nn::seq()
+                    .add_fn(move |xs| xs.view([-1, channels, height, width]))
+                    .add(nn::conv2d(&p / "conv1", channels, 32, 8, stride(4)))
+                    .add_fn(|xs| xs.relu())
+                    .add(nn::conv2d(&p / "conv2", 32, 64, 4, stride(2)))
+                    .add_fn(|xs| xs.relu())
+                    .add(nn::conv2d(&p / "conv3", 64, 64, 3, stride(1)))
+                    .add_fn(|xs| xs.relu().flat_view())
+                    .add(nn::linear(&p / "lin1", 64 * conv_out(height) * conv_out(width), *hidden, Default::default()))
+                    .add_fn(|xs| xs.relu())
+                    .add(nn::linear(&p / "lin2", *hidden, output, Default::default()))
+            }
+        }
+    }
+}
+
+impl FromStr for NetworkSpec {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["cnn"] => Ok(NetworkSpec::nature_cnn()),
+            ["mlp", sizes] | ["mlp", sizes, _] => {
+                let hidden = sizes
+                    .split(',')
+                    .map(|size| size.trim().parse::<i64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| "mlp sizes should be numbers, like mlp:64,64")?;
+                let activation = match parts.get(2) {
+                    None | Some(&"tanh") => Activation::Tanh,
+                    Some(&"relu") => Activation::Relu,
+                    _ => return Err("activation should be relu or tanh"),
+                };
+                Ok(NetworkSpec::Mlp {
+                    hidden: hidden,
+                    activation: activation,
+                })
+            }
+            _ => Err("no network match, try mlp:64,64, mlp:64,64:relu or cnn"),
+        }
+    }
+}
@@ -1,16 +1,36 @@
 // Policy gradient example.
 
 // This is based mainly on tch-rs examples and of course OpenAI Gym
+use bevy::prelude::World;
 use tch::{
-    nn::{self, OptimizerConfig},
+    nn,
     Kind::{self, Float},
     Tensor,
 };
 
+use crate::{environment::EnvironmentState, space::Action, vec_env::VecEnv};
+
+use super::{
+    adam::Adam,
+    checkpoint::Checkpoint,
+    network::{Activation, NetworkSpec},
+    self_play::{FrozenPolicy, SelfPlay},
+    MLModel, VecModel,
+};
+
 pub struct PolicyGradientModel {
+    vs: nn::VarStore,
     pub model: nn::Sequential,
-    pub opt: nn::Optimizer<nn::Adam>,
+    pub opt: Adam,
+    // What the model was built from, for snapshots
+    network: NetworkSpec,
+    input_shape: Vec<i64>,
+    action_space: i64,
     history: Vec<HistoryState>,
+    // Episodes in progress when training on a VecEnv, one per instance
+    vec_histories: Vec<Vec<HistoryState>>,
+    last_action: Option<(Vec<f32>, usize)>,
+    steps: usize,
 }
 
 struct HistoryState {
@@ -21,25 +41,25 @@ struct HistoryState {
 }
 
 impl PolicyGradientModel {
-    pub fn new(input_size: i64, output: i64) -> Self {
+    pub fn default_network() -> NetworkSpec {
+        NetworkSpec::mlp(&[32], Activation::Tanh)
+    }
 
+    pub fn new(input_shape: &[i64], output: i64, network: &NetworkSpec) -> Self {
         let vs = nn::VarStore::new(tch::Device::Cpu);
-        let p = &vs.root();
-
-        let model = nn::seq()
-            .add(nn::linear(
-                p / "lin1",
-                input_size,
-                32,
-                Default::default(),
-            ))
-            .add_fn(|xs| xs.tanh())
-            .add(nn::linear(p / "lin2", 32, output, Default::default()));
+        let model = network.build(vs.root(), input_shape, output);
 
         Self {
+            opt: Adam::new(&vs, 1e-2),
+            vs: vs,
             model: model,
-            opt: nn::Adam::default().build(&vs, 1e-2).unwrap(),
+            network: network.clone(),
+            input_shape: input_shape.to_vec(),
+            action_space: output,
             history: vec![],
+            vec_histories: vec![],
+            last_action: None,
+            steps: 0,
         }
     }
 
@@ -61,8 +81,8 @@ impl PolicyGradientModel {
             .collect();
         let actions = Tensor::of_slice(&actions).unsqueeze(1);
         let rewards = Tensor::of_slice(&self.accumulate_rewards()).to_kind(Kind::Float);
-        let action_mask =
-            Tensor::zeros(&[batch_size, 2], tch::kind::FLOAT_CPU).scatter_value(1, &actions, 1.0);
+        let action_mask = Tensor::zeros(&[batch_size, self.action_space], tch::kind::FLOAT_CPU)
+            .scatter_value(1, &actions, 1.0);
         let obs: Vec<Tensor> = self
             .history
             .iter()
@@ -75,6 +95,18 @@ impl PolicyGradientModel {
         self.opt.backward_step(&loss);
     }
 
+    // Sample an action from the policy
+    fn pick_action(&self, observation: &[f32]) -> usize {
+        let action = tch::no_grad(|| {
+            Tensor::of_slice(observation)
+                .unsqueeze(0)
+                .apply(&self.model)
+                .softmax(1, Kind::Float)
+                .multinomial(1, true)
+        });
+        i64::from(action) as usize
+    }
+
     fn accumulate_rewards(&self) -> Vec<f32> {
         let mut rewards: Vec<f32> = self.history.iter().map(|s| s.reward).collect();
         let mut acc_reward = 0f32;
@@ -89,4 +121,78 @@ impl PolicyGradientModel {
     }
 }
 
+impl MLModel for PolicyGradientModel {
+    fn update_action(world: &mut World) {
+        let state = world.get_resource::<EnvironmentState>().unwrap().clone();
+        let mut pg = world
+            .get_non_send_resource_mut::<PolicyGradientModel>()
+            .unwrap();
+
+        // Record what happened with our last action, train at the end of each episode
+        if let (Some((observation, action)), Some(is_done)) = (pg.last_action.take(), state.is_done) {
+            pg.record_history(observation, state.reward, is_done, action as f32);
+            pg.steps += 1;
+            if is_done {
+                pg.train();
+                pg.history.clear();
+                return;
+            }
+        }
+
+        // Using our observations get an action
+        let action = pg.pick_action(&state.observation);
+        pg.last_action = Some((state.observation, action));
+
+        world.get_resource_mut::<EnvironmentState>().unwrap().action = Some(Action::Discrete(action));
+    }
+}
+
+impl VecModel for PolicyGradientModel {
+    // Steps every instance once, trains on each episode as it finishes
+    fn train_vec(&mut self, vec_env: &mut VecEnv, observations: &mut Vec<Vec<f32>>) {
+        self.vec_histories.resize_with(vec_env.len(), Vec::new);
+        let actions: Vec<usize> = observations.iter().map(|o| self.pick_action(o)).collect();
+        let step = vec_env
+            .step(&actions.iter().map(|a| Action::Discrete(*a)).collect::<Vec<_>>())
+            .unwrap();
 
+        let observations = std::mem::replace(observations, step.observations);
+        for (i, observation) in observations.into_iter().enumerate() {
+            self.vec_histories[i].push(HistoryState {
+                reward: step.rewards[i],
+                is_done: step.dones[i],
+                action: actions[i] as f32,
+                observations: observation,
+            });
+            self.steps += 1;
+            if step.dones[i] {
+                self.history = std::mem::take(&mut self.vec_histories[i]);
+                self.train();
+                self.history.clear();
+            }
+        }
+    }
+}
+
+impl Checkpoint for PolicyGradientModel {
+    fn var_stores(&mut self) -> Vec<(&'static str, &mut nn::VarStore)> {
+        vec![("model", &mut self.vs), ("adam", self.opt.var_store())]
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn set_steps(&mut self, steps: usize) {
+        self.steps = steps;
+    }
+}
+
+impl SelfPlay for PolicyGradientModel {
+    fn snapshot(&self) -> FrozenPolicy {
+        let mut vs = nn::VarStore::new(tch::Device::Cpu);
+        let model = self.network.build(vs.root(), &self.input_shape, self.action_space);
+        vs.copy(&self.vs).unwrap();
+        FrozenPolicy::sample(vs, model)
+    }
+}
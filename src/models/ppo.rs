@@ -0,0 +1,300 @@
+// Proximal Policy Optimization
+//
+// Clipped objective with an actor critic, advantages from GAE, based on
+// https://arxiv.org/abs/1707.06347 and the tch-rs examples
+use bevy::prelude::World;
+use tch::{
+    nn,
+    Device,
+    Kind::{self, Float},
+    Tensor,
+};
+
+use crate::{environment::EnvironmentState, space::Action, vec_env::VecEnv};
+
+use super::{
+    adam::Adam,
+    checkpoint::Checkpoint,
+    network::{Activation, NetworkSpec},
+    self_play::{FrozenPolicy, SelfPlay},
+    MLModel, VecModel,
+};
+
+const ROLLOUT_SIZE: usize = 512; // steps collected before each update
+const EPOCHS: usize = 4;
+const MINIBATCH_SIZE: usize = 64;
+const GAMMA: f32 = 0.99;
+const GAE_LAMBDA: f32 = 0.95;
+const CLIP: f64 = 0.2;
+const VALUE_COEF: f64 = 0.5;
+const ENTROPY_COEF: f64 = 0.01;
+const LEARNING_RATE: f64 = 3e-4;
+
+pub struct PpoModel {
+    vs: nn::VarStore,
+    pub actor: nn::Sequential,
+    pub critic: nn::Sequential,
+    pub opt: Adam,
+    // What the actor was built from, for snapshots
+    network: NetworkSpec,
+    input_shape: Vec<i64>,
+    output: i64,
+    rollout: Vec<Transition>,
+    last_action: Option<Transition>,
+    steps: usize,
+}
+
+struct Transition {
+    observation: Vec<f32>,
+    action: i64,
+    log_prob: f32,
+    value: f32,
+    reward: f32,
+    is_done: bool,
+}
+
+impl PpoModel {
+    pub fn default_network() -> NetworkSpec {
+        NetworkSpec::mlp(&[64, 64], Activation::Tanh)
+    }
+
+    // The actor and critic each get their own network from the spec
+    pub fn new(input_shape: &[i64], output: i64, network: &NetworkSpec) -> Self {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let p = &vs.root();
+        let actor = network.build(p / "actor", input_shape, output);
+        let critic = network.build(p / "critic", input_shape, 1);
+
+        Self {
+            opt: Adam::new(&vs, LEARNING_RATE),
+            vs: vs,
+            actor: actor,
+            critic: critic,
+            network: network.clone(),
+            input_shape: input_shape.to_vec(),
+            output: output,
+            rollout: Vec::with_capacity(ROLLOUT_SIZE),
+            last_action: None,
+            steps: 0,
+        }
+    }
+
+    // Sample an action, returns it with its log probability and the state value
+    fn act(&self, observation: &[f32]) -> (i64, f32, f32) {
+        tch::no_grad(|| {
+            let obs = Tensor::of_slice(observation).unsqueeze(0);
+            let log_probs = obs.apply(&self.actor).log_softmax(1, Float);
+            let action = log_probs.exp().multinomial(1, true);
+            let log_prob = log_probs.gather(1, &action, false);
+            let value = obs.apply(&self.critic);
+            (
+                i64::from(&action),
+                f64::from(&log_prob) as f32,
+                f64::from(&value) as f32,
+            )
+        })
+    }
+
+    fn value(&self, observation: &[f32]) -> f32 {
+        tch::no_grad(|| {
+            let value = Tensor::of_slice(observation).unsqueeze(0).apply(&self.critic);
+            f64::from(&value) as f32
+        })
+    }
+
+    // Train on the rollout, last_value is the value of the state after it
+    pub fn train(&mut self, last_value: f32) {
+        let rollout = std::mem::take(&mut self.rollout);
+        let (advantages, returns) = advantages(&rollout, last_value);
+        self.update(&rollout, &advantages, &returns);
+    }
+
+    fn update(&mut self, rollout: &[Transition], advantages: &[f32], returns: &[f32]) {
+        let size = rollout.len() as i64;
+
+        let obs: Vec<Tensor> = rollout
+            .iter()
+            .map(|t| Tensor::of_slice(&t.observation).to_kind(Kind::Float))
+            .collect();
+        let obs = Tensor::stack(&obs, 0);
+        let actions: Vec<i64> = rollout.iter().map(|t| t.action).collect();
+        let actions = Tensor::of_slice(&actions);
+        let old_log_probs: Vec<f32> = rollout.iter().map(|t| t.log_prob).collect();
+        let old_log_probs = Tensor::of_slice(&old_log_probs);
+        let returns = Tensor::of_slice(returns);
+        let advantages = Tensor::of_slice(advantages);
+        let advantages = (&advantages - advantages.mean(Float)) / (advantages.std(true) + 1e-8);
+
+        for _ in 0..EPOCHS {
+            let indexes = Tensor::randperm(size, (Kind::Int64, Device::Cpu));
+            for start in (0..size).step_by(MINIBATCH_SIZE) {
+                let batch = indexes.narrow(0, start, (MINIBATCH_SIZE as i64).min(size - start));
+                let obs = obs.index_select(0, &batch);
+                let actions = actions.index_select(0, &batch);
+                let old_log_probs = old_log_probs.index_select(0, &batch);
+                let returns = returns.index_select(0, &batch);
+                let advantages = advantages.index_select(0, &batch);
+
+                let all_log_probs = obs.apply(&self.actor).log_softmax(1, Float);
+                let log_probs = all_log_probs
+                    .gather(1, &actions.unsqueeze(1), false)
+                    .view([-1]);
+                let entropy = -(all_log_probs.exp() * &all_log_probs)
+                    .sum_dim_intlist(&[1], false, Float)
+                    .mean(Float);
+
+                // Clipped surrogate, min(a, b) written as a - relu(a - b)
+                let ratio = (log_probs - old_log_probs).exp();
+                let surr1 = &ratio * &advantages;
+                let surr2 = ratio.clamp(1.0 - CLIP, 1.0 + CLIP) * &advantages;
+                let policy_loss = -(&surr1 - (&surr1 - &surr2).relu()).mean(Float);
+
+                let values = obs.apply(&self.critic).view([-1]);
+                let value_error = returns - values;
+                let value_loss = (&value_error * &value_error).mean(Float);
+
+                let loss = policy_loss + value_loss * VALUE_COEF - entropy * ENTROPY_COEF;
+                self.opt.backward_step(&loss);
+            }
+        }
+    }
+}
+
+// Generalized advantage estimation, last_value is the value of the state after the rollout
+fn advantages(rollout: &[Transition], last_value: f32) -> (Vec<f32>, Vec<f32>) {
+    let mut advantages = vec![0.0; rollout.len()];
+    let mut gae = 0.0;
+    for (i, t) in rollout.iter().enumerate().rev() {
+        let next_value = match rollout.get(i + 1) {
+            Some(next) => next.value,
+            None => last_value,
+        };
+        let not_done = if t.is_done { 0.0 } else { 1.0 };
+        let delta = t.reward + GAMMA * next_value * not_done - t.value;
+        gae = delta + GAMMA * GAE_LAMBDA * not_done * gae;
+        advantages[i] = gae;
+    }
+    let returns = advantages
+        .iter()
+        .zip(rollout.iter())
+        .map(|(a, t)| a + t.value)
+        .collect();
+    (advantages, returns)
+}
+
+impl VecModel for PpoModel {
+    // Collects about a rollout's worth of steps across the instances, then updates once
+    fn train_vec(&mut self, vec_env: &mut VecEnv, observations: &mut Vec<Vec<f32>>) {
+        let n = vec_env.len();
+        let mut rollouts: Vec<Vec<Transition>> = (0..n).map(|_| Vec::new()).collect();
+        for _ in 0..(ROLLOUT_SIZE / n).max(1) {
+            let transitions: Vec<Transition> = observations
+                .iter()
+                .map(|observation| {
+                    let (action, log_prob, value) = self.act(observation);
+                    Transition {
+                        observation: observation.clone(),
+                        action: action,
+                        log_prob: log_prob,
+                        value: value,
+                        reward: 0.0,
+                        is_done: false,
+                    }
+                })
+                .collect();
+            let actions: Vec<Action> = transitions
+                .iter()
+                .map(|t| Action::Discrete(t.action as usize))
+                .collect();
+            let step = vec_env.step(&actions).unwrap();
+
+            for (i, mut transition) in transitions.into_iter().enumerate() {
+                transition.reward = step.rewards[i];
+                transition.is_done = step.dones[i];
+                // A time limit isn't a real end, bootstrap from where the episode stopped
+                if step.infos[i].contains_key("truncated") {
+                    if let Some(final_observation) = &step.final_observations[i] {
+                        transition.reward += GAMMA * self.value(final_observation);
+                    }
+                }
+                rollouts[i].push(transition);
+            }
+            *observations = step.observations;
+            self.steps += n;
+        }
+
+        // Advantages per instance, then one update over all of them
+        let mut batch = Vec::new();
+        let mut batch_advantages = Vec::new();
+        let mut batch_returns = Vec::new();
+        for (rollout, observation) in rollouts.into_iter().zip(observations.iter()) {
+            let (advantages, returns) = advantages(&rollout, self.value(observation));
+            batch.extend(rollout);
+            batch_advantages.extend(advantages);
+            batch_returns.extend(returns);
+        }
+        self.update(&batch, &batch_advantages, &batch_returns);
+    }
+}
+
+impl MLModel for PpoModel {
+    fn update_action(world: &mut World) {
+        let state = world.get_resource::<EnvironmentState>().unwrap().clone();
+        let mut ppo = world.get_non_send_resource_mut::<PpoModel>().unwrap();
+
+        // Finish the last transition now we know its reward, update once the rollout is full
+        if let (Some(mut transition), Some(is_done)) = (ppo.last_action.take(), state.is_done) {
+            transition.reward = state.reward;
+            transition.is_done = is_done;
+            // A time limit isn't a real end, bootstrap from where the episode stopped like train_vec
+            if state.info.contains_key("truncated") {
+                transition.reward += GAMMA * ppo.value(&state.observation);
+            }
+            ppo.rollout.push(transition);
+            ppo.steps += 1;
+            if ppo.rollout.len() >= ROLLOUT_SIZE {
+                let last_value = if is_done { 0.0 } else { ppo.value(&state.observation) };
+                ppo.train(last_value);
+            }
+            if is_done {
+                return;
+            }
+        }
+
+        let (action, log_prob, value) = ppo.act(&state.observation);
+        ppo.last_action = Some(Transition {
+            observation: state.observation,
+            action: action,
+            log_prob: log_prob,
+            value: value,
+            reward: 0.0,
+            is_done: false,
+        });
+
+        world.get_resource_mut::<EnvironmentState>().unwrap().action =
+            Some(Action::Discrete(action as usize));
+    }
+}
+
+impl Checkpoint for PpoModel {
+    fn var_stores(&mut self) -> Vec<(&'static str, &mut nn::VarStore)> {
+        vec![("model", &mut self.vs), ("adam", self.opt.var_store())]
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn set_steps(&mut self, steps: usize) {
+        self.steps = steps;
+    }
+}
+
+impl SelfPlay for PpoModel {
+    fn snapshot(&self) -> FrozenPolicy {
+        let mut vs = nn::VarStore::new(Device::Cpu);
+        let actor = self.network.build(&vs.root() / "actor", &self.input_shape, self.output);
+        vs.copy(&self.vs).unwrap();
+        FrozenPolicy::sample(vs, actor)
+    }
+}
@@ -0,0 +1,120 @@
+// Experience replay, shared by the off-policy models
+use std::collections::VecDeque;
+
+use rand::Rng;
+use tch::Tensor;
+
+pub struct Transition {
+    pub observation: Vec<f32>,
+    pub action: Vec<f32>,
+    pub reward: f32,
+    pub next_observation: Vec<f32>,
+    // Ended for good, an episode cut short by a step limit still bootstraps from next_observation
+    pub terminal: bool,
+}
+
+// A sampled batch, one row per transition
+pub struct ReplayBatch {
+    pub observations: Tensor,
+    pub actions: Tensor,
+    pub rewards: Tensor,
+    pub next_observations: Tensor,
+    pub not_done: Tensor,
+}
+
+// Memory a buffer gets by default, 50k frame stacked pixel observations would take 11GB
+const DEFAULT_MEMORY: usize = 2 << 30;
+
+// The model's capacity, or less when that many observations won't fit in DEFAULT_MEMORY
+pub fn default_capacity(capacity: usize, observation_size: usize) -> usize {
+    // Each transition keeps the observation and the next one as f32
+    let transition_size = 2 * observation_size * std::mem::size_of::<f32>();
+    capacity.min(DEFAULT_MEMORY / transition_size.max(1)).max(1)
+}
+
+pub struct ReplayBuffer {
+    buffer: VecDeque<Transition>,
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Oldest transitions are dropped once full
+    pub fn push(&mut self, transition: Transition) {
+        if self.buffer.len() == self.capacity {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(transition);
+    }
+
+    // Uniform sample with replacement
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R, batch_size: usize) -> ReplayBatch {
+        let batch: Vec<&Transition> = (0..batch_size)
+            .map(|_| &self.buffer[rng.gen_range(0..self.buffer.len())])
+            .collect();
+
+        let stack = |rows: Vec<Tensor>| Tensor::stack(&rows, 0);
+        let rewards: Vec<f32> = batch.iter().map(|t| t.reward).collect();
+        let not_done: Vec<f32> = batch.iter().map(|t| if t.terminal { 0.0 } else { 1.0 }).collect();
+        ReplayBatch {
+            observations: stack(batch.iter().map(|t| Tensor::of_slice(&t.observation)).collect()),
+            actions: stack(batch.iter().map(|t| Tensor::of_slice(&t.action)).collect()),
+            rewards: Tensor::of_slice(&rewards),
+            next_observations: stack(
+                batch
+                    .iter()
+                    .map(|t| Tensor::of_slice(&t.next_observation))
+                    .collect(),
+            ),
+            not_done: Tensor::of_slice(&not_done),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_capacity_shrinks_for_pixels() {
+        // CartPole keeps the full buffer
+        assert_eq!(default_capacity(50_000, 4), 50_000);
+        // 4 stacked 84x84 frames don't fit 50k times
+        let capacity = default_capacity(50_000, 4 * 84 * 84);
+        assert!(capacity < 50_000);
+        assert!(capacity * 2 * 4 * 84 * 84 * 4 <= DEFAULT_MEMORY);
+    }
+
+    #[test]
+    fn only_terminal_transitions_drop_the_bootstrap() {
+        use rand::SeedableRng;
+
+        let mut buffer = ReplayBuffer::new(2);
+        for &terminal in &[true, false] {
+            buffer.push(Transition {
+                observation: vec![0.0],
+                action: vec![0.0],
+                // Tells the rows apart
+                reward: if terminal { 1.0 } else { 0.0 },
+                next_observation: vec![0.0],
+                terminal: terminal,
+            });
+        }
+        let batch = buffer.sample(&mut rand_pcg::Pcg64::seed_from_u64(0), 16);
+        let rewards = Vec::<f32>::from(&batch.rewards);
+        let not_done = Vec::<f32>::from(&batch.not_done);
+        for (reward, not_done) in rewards.iter().zip(not_done.iter()) {
+            assert_eq!(*not_done, 1.0 - reward);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// Soft Actor-Critic, for continuous action spaces
+//
+// Twin Q networks with polyak averaged targets, a tanh squashed gaussian policy and
+// automatic entropy tuning, based on https://arxiv.org/abs/1812.05905
+use bevy::prelude::World;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use tch::{
+    nn,
+    Device,
+    Kind::Float,
+    Reduction, Tensor,
+};
+
+use crate::{environment::EnvironmentState, space::Action};
+
+use super::{
+    adam::Adam,
+    checkpoint::Checkpoint,
+    replay::{ReplayBatch, ReplayBuffer, Transition},
+    MLModel,
+};
+
+pub const BUFFER_SIZE: usize = 100_000; // default, see replay::default_capacity
+const BATCH_SIZE: usize = 128;
+const WARMUP: usize = 1_000; // random actions before training starts
+const GAMMA: f64 = 0.99;
+const TAU: f64 = 0.005; // target network averaging
+const LEARNING_RATE: f64 = 3e-4;
+const HIDDEN_SIZE: i64 = 128;
+const LOG_STD_MIN: f64 = -20.0;
+const LOG_STD_MAX: f64 = 2.0;
+
+struct Actor {
+    body: nn::Sequential,
+    mean: nn::Linear,
+    log_std: nn::Linear,
+}
+
+impl Actor {
+    fn new(p: nn::Path, input_size: i64, action_size: i64) -> Self {
+        Self {
+            body: mlp(&p / "body", input_size, HIDDEN_SIZE),
+            mean: nn::linear(&p / "mean", HIDDEN_SIZE, action_size, Default::default()),
+            log_std: nn::linear(&p / "log_std", HIDDEN_SIZE, action_size, Default::default()),
+        }
+    }
+
+    // Reparameterized sample squashed into -1..1, with its log probability
+    fn sample(&self, obs: &Tensor) -> (Tensor, Tensor) {
+        let hidden = obs.apply(&self.body).relu();
+        let mean = hidden.apply(&self.mean);
+        let log_std = hidden.apply(&self.log_std).clamp(LOG_STD_MIN, LOG_STD_MAX);
+        let std = log_std.exp();
+        let noise = Tensor::randn(&mean.size(), (Float, Device::Cpu));
+        let u = &mean + &std * &noise;
+        let action = u.tanh();
+
+        // Gaussian log prob, corrected for the tanh squash
+        let log_prob = (&noise * &noise) * -0.5
+            - log_std
+            - 0.5 * (2.0 * std::f64::consts::PI).ln()
+            - (1.0 - &action * &action + 1e-6).log();
+        (action, log_prob.sum_dim_intlist(&[1], false, Float))
+    }
+}
+
+fn mlp(p: nn::Path, input_size: i64, output: i64) -> nn::Sequential {
+    nn::seq()
+        .add(nn::linear(&p / "lin1", input_size, HIDDEN_SIZE, Default::default()))
+        .add_fn(|xs| xs.relu())
+        .add(nn::linear(&p / "lin2", HIDDEN_SIZE, output, Default::default()))
+}
+
+// Both Q networks, takes the observation and action together
+fn critics(p: nn::Path, input_size: i64) -> (nn::Sequential, nn::Sequential) {
+    let q = |name: &str| {
+        mlp(&p / name, input_size, HIDDEN_SIZE)
+            .add_fn(|xs| xs.relu())
+            .add(nn::linear(&p / name / "out", HIDDEN_SIZE, 1, Default::default()))
+    };
+    (q("q1"), q("q2"))
+}
+
+pub struct SacModel {
+    actor_vs: nn::VarStore,
+    critic_vs: nn::VarStore,
+    target_vs: nn::VarStore,
+    alpha_vs: nn::VarStore,
+    actor: Actor,
+    q1: nn::Sequential,
+    q2: nn::Sequential,
+    target_q1: nn::Sequential,
+    target_q2: nn::Sequential,
+    log_alpha: Tensor,
+    target_entropy: f64,
+    actor_opt: Adam,
+    critic_opt: Adam,
+    alpha_opt: Adam,
+    low: Vec<f32>,
+    high: Vec<f32>,
+    buffer: ReplayBuffer,
+    last_action: Option<(Vec<f32>, Vec<f32>)>,
+    steps: usize,
+    rng: Pcg64,
+}
+
+impl SacModel {
+    // low and high are the action space bounds, the policy outputs -1..1 and is scaled to them
+    pub fn new(input_size: i64, low: Vec<f32>, high: Vec<f32>, buffer_size: usize, rng: Pcg64) -> Self {
+        let action_size = low.len() as i64;
+
+        let actor_vs = nn::VarStore::new(Device::Cpu);
+        let actor = Actor::new(actor_vs.root(), input_size, action_size);
+
+        let critic_vs = nn::VarStore::new(Device::Cpu);
+        let (q1, q2) = critics(critic_vs.root(), input_size + action_size);
+        let mut target_vs = nn::VarStore::new(Device::Cpu);
+        let (target_q1, target_q2) = critics(target_vs.root(), input_size + action_size);
+        target_vs.copy(&critic_vs).unwrap();
+
+        let alpha_vs = nn::VarStore::new(Device::Cpu);
+        let log_alpha = alpha_vs.root().zeros("log_alpha", &[1]);
+
+        Self {
+            actor_opt: Adam::new(&actor_vs, LEARNING_RATE),
+            critic_opt: Adam::new(&critic_vs, LEARNING_RATE),
+            alpha_opt: Adam::new(&alpha_vs, LEARNING_RATE),
+            actor_vs: actor_vs,
+            critic_vs: critic_vs,
+            target_vs: target_vs,
+            alpha_vs: alpha_vs,
+            actor: actor,
+            q1: q1,
+            q2: q2,
+            target_q1: target_q1,
+            target_q2: target_q2,
+            log_alpha: log_alpha,
+            target_entropy: -(action_size as f64),
+            low: low,
+            high: high,
+            buffer: ReplayBuffer::new(buffer_size),
+            last_action: None,
+            steps: 0,
+            rng: rng,
+        }
+    }
+
+    // Policy action in -1..1, random until the buffer has warmed up
+    fn act(&mut self, observation: &[f32]) -> Vec<f32> {
+        if self.steps < WARMUP {
+            let rng = &mut self.rng;
+            return self.low.iter().map(|_| rng.gen_range(-1.0..=1.0)).collect();
+        }
+        tch::no_grad(|| {
+            let obs = Tensor::of_slice(observation).unsqueeze(0);
+            let (action, _) = self.actor.sample(&obs);
+            Vec::<f32>::from(&action.view([-1]))
+        })
+    }
+
+    // Scale from -1..1 to the action space bounds
+    fn scale(&self, action: &[f32]) -> Vec<f32> {
+        action
+            .iter()
+            .zip(self.low.iter().zip(self.high.iter()))
+            .map(|(a, (low, high))| low + (a + 1.0) * 0.5 * (high - low))
+            .collect()
+    }
+
+    fn min_q(q1: &nn::Sequential, q2: &nn::Sequential, obs: &Tensor, action: &Tensor) -> Tensor {
+        let input = Tensor::cat(&[obs, action], 1);
+        let q1 = input.apply(q1).view([-1]);
+        let q2 = input.apply(q2).view([-1]);
+        // min(a, b) written as a - relu(a - b)
+        &q1 - (&q1 - &q2).relu()
+    }
+
+    pub fn train(&mut self) {
+        let ReplayBatch {
+            observations: obs,
+            actions,
+            rewards,
+            next_observations: next_obs,
+            not_done,
+        } = self.buffer.sample(&mut self.rng, BATCH_SIZE);
+        let alpha = self.log_alpha.exp().detach();
+
+        // Critics, soft bellman target from the target networks
+        let targets = tch::no_grad(|| {
+            let (next_actions, next_log_probs) = self.actor.sample(&next_obs);
+            let next_q = Self::min_q(&self.target_q1, &self.target_q2, &next_obs, &next_actions)
+                - &alpha * next_log_probs;
+            rewards + next_q * not_done * GAMMA
+        });
+        let input = Tensor::cat(&[&obs, &actions], 1);
+        let q1_loss = input.apply(&self.q1).view([-1]).mse_loss(&targets, Reduction::Mean);
+        let q2_loss = input.apply(&self.q2).view([-1]).mse_loss(&targets, Reduction::Mean);
+        self.critic_opt.backward_step(&(q1_loss + q2_loss));
+
+        // Actor, maximize q and entropy
+        let (new_actions, log_probs) = self.actor.sample(&obs);
+        let q = Self::min_q(&self.q1, &self.q2, &obs, &new_actions);
+        let actor_loss = (&alpha * &log_probs - q).mean(Float);
+        self.actor_opt.backward_step(&actor_loss);
+
+        // Temperature, keep the entropy near the target
+        let alpha_loss =
+            -(&self.log_alpha * (log_probs.detach() + self.target_entropy)).mean(Float);
+        self.alpha_opt.backward_step(&alpha_loss);
+
+        self.update_targets();
+    }
+
+    // Polyak averaging, matched by variable name
+    fn update_targets(&mut self) {
+        let critic = self.critic_vs.variables();
+        tch::no_grad(|| {
+            for (name, mut target) in self.target_vs.variables() {
+                let updated = &target * (1.0 - TAU) + &critic[&name] * TAU;
+                target.copy_(&updated);
+            }
+        });
+    }
+}
+
+impl MLModel for SacModel {
+    fn update_action(world: &mut World) {
+        let state = world.get_resource::<EnvironmentState>().unwrap().clone();
+        let mut sac = world.get_non_send_resource_mut::<SacModel>().unwrap();
+
+        // Store what our last action did, then learn from a random batch
+        if let (Some((observation, action)), Some(is_done)) = (sac.last_action.take(), state.is_done) {
+            sac.buffer.push(Transition {
+                observation: observation,
+                action: action,
+                reward: state.reward,
+                next_observation: state.observation.clone(),
+                terminal: is_done && !state.info.contains_key("truncated"),
+            });
+            sac.steps += 1;
+            if sac.steps >= WARMUP {
+                sac.train();
+            }
+            if is_done {
+                return;
+            }
+        }
+
+        let action = sac.act(&state.observation);
+        let scaled = sac.scale(&action);
+        sac.last_action = Some((state.observation, action));
+
+        world.get_resource_mut::<EnvironmentState>().unwrap().action = Some(Action::Box(scaled));
+    }
+}
+
+impl Checkpoint for SacModel {
+    fn var_stores(&mut self) -> Vec<(&'static str, &mut nn::VarStore)> {
+        vec![
+            ("actor", &mut self.actor_vs),
+            ("critic", &mut self.critic_vs),
+            ("target", &mut self.target_vs),
+            ("alpha", &mut self.alpha_vs),
+            ("actor_adam", self.actor_opt.var_store()),
+            ("critic_adam", self.critic_opt.var_store()),
+            ("alpha_adam", self.alpha_opt.var_store()),
+        ]
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn set_steps(&mut self, steps: usize) {
+        self.steps = steps;
+    }
+}
@@ -0,0 +1,95 @@
+// Self-play
+//
+// A frozen snapshot of the model being trained drives the Opponent of a two player
+// environment. The snapshot is taken again every so often, so the opponent keeps
+// up with the learner without chasing every update.
+use bevy::prelude::*;
+use tch::{nn, Kind, Tensor};
+
+use crate::{environment::Opponent, space::Action};
+
+use super::checkpoint::Checkpoint;
+
+const SNAPSHOT_STEPS: usize = 10_000; // learner steps between snapshots
+
+// A copy of a model's policy network, it no longer learns
+pub struct FrozenPolicy {
+    _vs: nn::VarStore, // owns the copied weights
+    net: nn::Sequential,
+    greedy: bool,
+}
+
+impl FrozenPolicy {
+    // Samples actions from the network's logits
+    pub fn sample(vs: nn::VarStore, net: nn::Sequential) -> Self {
+        Self {
+            _vs: vs,
+            net: net,
+            greedy: false,
+        }
+    }
+
+    // Takes the largest output, for q values
+    pub fn greedy(vs: nn::VarStore, net: nn::Sequential) -> Self {
+        Self {
+            _vs: vs,
+            net: net,
+            greedy: true,
+        }
+    }
+
+    pub fn act(&self, observation: &[f32]) -> Action {
+        tch::no_grad(|| {
+            let output = Tensor::of_slice(observation).unsqueeze(0).apply(&self.net);
+            let action = if self.greedy {
+                output.argmax(1, false)
+            } else {
+                output.softmax(1, Kind::Float).multinomial(1, true)
+            };
+            Action::Discrete(i64::from(&action) as usize)
+        })
+    }
+}
+
+pub trait SelfPlay: Checkpoint {
+    fn snapshot(&self) -> FrozenPolicy;
+}
+
+// The current snapshot, a non send resource like the models
+#[derive(Default)]
+pub struct SelfPlayOpponent {
+    policy: Option<FrozenPolicy>,
+    taken_at: usize,
+}
+
+// Picks every Opponent's action from its last observation, taking a new snapshot when due
+pub fn self_play_system<M: SelfPlay + 'static>(world: &mut World) {
+    let steps = world.get_non_send_resource::<M>().unwrap().steps();
+    let due = {
+        let opponent = world.get_non_send_resource::<SelfPlayOpponent>().unwrap();
+        opponent.policy.is_none() || steps >= opponent.taken_at + SNAPSHOT_STEPS
+    };
+    if due {
+        let policy = world.get_non_send_resource::<M>().unwrap().snapshot();
+        let mut opponent = world.get_non_send_resource_mut::<SelfPlayOpponent>().unwrap();
+        opponent.policy = Some(policy);
+        opponent.taken_at = steps;
+    }
+
+    let observations: Vec<(Entity, Vec<f32>)> = world
+        .query::<(Entity, &Opponent)>()
+        .iter(world)
+        .filter(|(_, opponent)| !opponent.observation.is_empty())
+        .map(|(e, opponent)| (e, opponent.observation.clone()))
+        .collect();
+    let actions: Vec<(Entity, Action)> = {
+        let policy = world.get_non_send_resource::<SelfPlayOpponent>().unwrap().policy.as_ref().unwrap();
+        observations
+            .into_iter()
+            .map(|(e, observation)| (e, policy.act(&observation)))
+            .collect()
+    };
+    for (e, action) in actions {
+        world.get_mut::<Opponent>(e).unwrap().action = Some(action);
+    }
+}
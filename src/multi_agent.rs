@@ -0,0 +1,252 @@
+// Multi-agent environments
+//
+// PettingZoo style parallel API, every live agent acts at once and gets its own step
+// back, keyed by agent id. Agents are done on their own, like flappy's birds, once done
+// they get no more actions and the episode is over when none are left.
+//
+// Agents share the environment's spaces. Wrappers and pixels are single agent only.
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+    environment::*,
+    environments::EnvironmentType,
+    error::GymError,
+    gym::Gym,
+    space::{Action, Space},
+};
+
+pub type AgentId = usize;
+
+pub trait MultiAgentEnv: Env {
+    // Every agent that can take part in an episode
+    fn possible_agents(world: &World) -> Vec<AgentId>;
+
+    // Clear the instance and spawn it in its initial state, returns every agent's first observation
+    fn reset_agents(world: &mut World, instance: &Instance) -> HashMap<AgentId, Vec<f32>>;
+
+    // Actions only for live agents, each is in the action space
+    fn act_agents(world: &mut World, instance: &Instance, actions: &HashMap<AgentId, Action>);
+
+    // A step for every agent that was live before this step
+    fn observe_agents(world: &mut World, instance: &Instance) -> HashMap<AgentId, Step>;
+}
+
+// The multi-agent functions, lets MultiAgentGym drive any multi-agent environment
+#[derive(Clone, Copy)]
+pub struct MultiAgentFns {
+    pub possible_agents: fn(&World) -> Vec<AgentId>,
+    pub reset: fn(&mut World, &Instance) -> HashMap<AgentId, Vec<f32>>,
+    pub act: fn(&mut World, &Instance, &HashMap<AgentId, Action>),
+    pub observe: fn(&mut World, &Instance) -> HashMap<AgentId, Step>,
+}
+
+// Marks the environment as multi-agent, call alongside add_env_systems
+pub fn add_multi_agent_fns<E: MultiAgentEnv>(app: &mut AppBuilder) {
+    app.insert_resource(MultiAgentFns {
+        possible_agents: E::possible_agents,
+        reset: E::reset_agents,
+        act: E::act_agents,
+        observe: E::observe_agents,
+    });
+}
+
+// The first instance's agents, like EnvironmentState for models run as systems
+#[derive(Debug, Default)]
+pub struct MultiAgentState {
+    pub agents: Vec<AgentId>, // live
+    pub observations: HashMap<AgentId, Vec<f32>>,
+    pub actions: HashMap<AgentId, Action>,
+    pub steps: HashMap<AgentId, Step>, // from the last step, agents done in it included
+    pub returns: HashMap<AgentId, f32>, // summed rewards this episode
+}
+
+impl MultiAgentState {
+    fn reset(&mut self, observations: HashMap<AgentId, Vec<f32>>) {
+        self.agents = observations.keys().cloned().collect();
+        self.agents.sort_unstable();
+        self.returns = self.agents.iter().map(|agent| (*agent, 0.0)).collect();
+        self.observations = observations;
+        self.actions.clear();
+        self.steps.clear();
+    }
+}
+
+// Drives the first instance through MultiAgentState, the single agent systems are
+// off while it's there. Same order as add_env_systems, it resets once every agent is
+// done. ManualStep turns these off too, for MultiAgentGym.
+pub fn add_multi_agent_systems<E: MultiAgentEnv>(app: &mut AppBuilder) {
+    add_multi_agent_fns::<E>(app);
+    app.insert_resource(MultiAgentState::default())
+        .add_startup_system(multi_agent_reset_system::<E>.exclusive_system())
+        .add_system(multi_agent_act_system::<E>.exclusive_system().at_end())
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            multi_agent_observe_system::<E>.exclusive_system().at_start(),
+        );
+}
+
+fn multi_agent_reset_system<E: MultiAgentEnv>(world: &mut World) {
+    if world.contains_resource::<ManualStep>() {
+        return;
+    }
+    let observations = E::reset_agents(world, &Instance::default());
+    world.get_resource_mut::<MultiAgentState>().unwrap().reset(observations);
+}
+
+fn multi_agent_act_system<E: MultiAgentEnv>(world: &mut World) {
+    if world.contains_resource::<ManualStep>() {
+        return;
+    }
+    let state = world.get_resource::<MultiAgentState>().unwrap();
+    if state.agents.is_empty() {
+        multi_agent_reset_system::<E>(world);
+        return;
+    }
+    let actions: HashMap<AgentId, Action> = state
+        .actions
+        .iter()
+        .filter(|(agent, _)| state.agents.contains(agent))
+        .map(|(agent, action)| (*agent, action.clone()))
+        .collect();
+    E::act_agents(world, &Instance::default(), &actions);
+}
+
+fn multi_agent_observe_system<E: MultiAgentEnv>(world: &mut World) {
+    if world.contains_resource::<ManualStep>() {
+        return;
+    }
+    let steps = E::observe_agents(world, &Instance::default());
+    let mut state = world.get_resource_mut::<MultiAgentState>().unwrap();
+    for (agent, step) in steps.iter() {
+        *state.returns.entry(*agent).or_default() += step.reward;
+        state.observations.insert(*agent, step.observation.clone());
+    }
+    state.agents.retain(|agent| steps.get(agent).map_or(false, |step| !step.is_done));
+    state.steps = steps;
+}
+
+// Headless multi-agent gym, drive a multi-agent environment from rust code
+pub struct MultiAgentGym {
+    app: App,
+    fns: MultiAgentFns,
+    agents: Vec<AgentId>,
+    action_space: Space,
+}
+
+#[allow(dead_code)]
+impl MultiAgentGym {
+    pub fn new(env: EnvironmentType, config: EnvironmentConfig) -> Self {
+        let mut builder = Gym::builder(env, config);
+        // We drive the agents ourselves
+        builder.insert_resource(ManualStep);
+        let mut app = std::mem::take(&mut builder.app);
+        // Run the startup systems
+        app.update();
+
+        let fns = *app
+            .world
+            .get_resource::<MultiAgentFns>()
+            .unwrap_or_else(|| panic!("{} is not a multi-agent environment", env));
+        let action_space = app.world.get_resource::<Environment>().unwrap().action_space.clone();
+        let mut gym = Self {
+            app,
+            fns,
+            agents: Vec::new(),
+            action_space,
+        };
+        gym.reset(None);
+        gym
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.app.world.get_resource::<Environment>().unwrap().clone()
+    }
+
+    pub fn possible_agents(&self) -> Vec<AgentId> {
+        (self.fns.possible_agents)(&self.app.world)
+    }
+
+    // Agents still in the episode
+    pub fn agents(&self) -> &[AgentId] {
+        &self.agents
+    }
+
+    // Clear and respawn the environment, returns every agent's first observation
+    //
+    // With a seed the episode is replayed exactly
+    pub fn reset(&mut self, seed: Option<u64>) -> HashMap<AgentId, Vec<f32>> {
+        if seed.is_some() {
+            self.app.world.insert_resource(EnvRng::new(seed));
+        }
+        let observations = (self.fns.reset)(&mut self.app.world, &Instance::default());
+        self.agents = observations.keys().cloned().collect();
+        self.agents.sort_unstable();
+        observations
+    }
+
+    // One action for every live agent, then advance the world one physics step
+    //
+    // Agents done in this step are dropped, once none are left call reset
+    pub fn step(&mut self, actions: &HashMap<AgentId, Action>) -> Result<HashMap<AgentId, Step>, GymError> {
+        let valid = actions.len() == self.agents.len()
+            && self.agents.iter().all(|agent| {
+                actions
+                    .get(agent)
+                    .map_or(false, |action| self.action_space.contains(action))
+            });
+        if !valid {
+            return Err(GymError::InvalidAction);
+        }
+
+        (self.fns.act)(&mut self.app.world, &Instance::default(), actions);
+        self.app.update();
+        let steps = (self.fns.observe)(&mut self.app.world, &Instance::default());
+        self.agents
+            .retain(|agent| steps.get(agent).map_or(false, |step| !step.is_done));
+        Ok(steps)
+    }
+
+    pub fn world(&self) -> &World {
+        &self.app.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.app.world
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::test_config;
+
+    #[test]
+    fn pong_steps_both_players_by_id() {
+        let mut gym = MultiAgentGym::new("pong".parse().unwrap(), test_config(Some(3)));
+        assert_eq!(gym.possible_agents(), vec![0, 1]);
+        assert_eq!(gym.agents(), &[0, 1]);
+
+        // Every live agent needs an action
+        let only_one: HashMap<AgentId, Action> = vec![(0, Action::Discrete(1))].into_iter().collect();
+        assert!(gym.step(&only_one).is_err());
+
+        // Both stand still until someone misses
+        let actions: HashMap<AgentId, Action> =
+            vec![(0, Action::Discrete(1)), (1, Action::Discrete(1))].into_iter().collect();
+        for _ in 0..5000 {
+            let steps = gym.step(&actions).unwrap();
+            assert_eq!(steps.len(), 2);
+            let (bottom, top) = (&steps[&0], &steps[&1]);
+            assert_eq!(bottom.is_done, top.is_done);
+            if bottom.reward != 0.0 {
+                // A point for one is a point against the other
+                assert_eq!(bottom.reward, -top.reward);
+                assert_eq!(bottom.info["score"], top.info["opponent_score"]);
+                return;
+            }
+        }
+        panic!("no point scored");
+    }
+}
@@ -0,0 +1,180 @@
+// Pixel observations
+//
+// Draws an instance's colliders with their ColliderDebugRender colors, on the cpu so it
+// works headless with no window or gpu. Pixels are 0..1, channels first, row 0 at the top,
+// the view is centered on the instance origin.
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use bevy_rapier2d::rapier::parry::query::PointQuery;
+
+use crate::{environment::Instance, space::Space};
+
+const BACKGROUND: f32 = 1.0; // same as the white ClearColor
+
+#[derive(Debug, Clone, Copy)]
+pub struct PixelConfig {
+    pub width: usize,
+    pub height: usize,
+    pub grayscale: bool,
+}
+
+impl PixelConfig {
+    pub fn channels(&self) -> usize {
+        if self.grayscale {
+            1
+        } else {
+            3
+        }
+    }
+
+    // Observation space, shaped [channels, height, width]
+    pub fn space(&self) -> Space {
+        let size = self.channels() * self.height * self.width;
+        Space::Box {
+            low: vec![0.0; size],
+            high: vec![1.0; size],
+            shape: vec![self.channels(), self.height, self.width],
+        }
+    }
+}
+
+// view_half_size is how much of the world around the origin to draw, later colliders draw on top
+pub fn rasterize(
+    world: &mut World,
+    instance: &Instance,
+    view_half_size: Vec2,
+    config: &PixelConfig,
+) -> Vec<f32> {
+    let (width, height) = (config.width, config.height);
+    let mut pixels = vec![BACKGROUND; config.channels() * width * height];
+    let pixel_size = Vec2::new(
+        view_half_size.x * 2.0 / width as f32,
+        view_half_size.y * 2.0 / height as f32,
+    );
+    // Anything within half a pixel is drawn, so lines like polyline ground still show up
+    let thickness = pixel_size.max_element() * 0.5;
+    let top_left = instance.origin + Vec2::new(-view_half_size.x, view_half_size.y);
+
+    let mut colliders = world.query::<(Entity, &Instance, &ColliderShape, &ColliderPosition, &ColliderDebugRender)>();
+    let world = &*world;
+    for (entity, _, shape, position, render) in colliders.iter(world).filter(|(_, i, ..)| i.index == instance.index) {
+        let position = world_position(world, entity, position);
+        let color = [render.color.r(), render.color.g(), render.color.b()];
+        let gray = 0.299 * color[0] + 0.587 * color[1] + 0.114 * color[2];
+
+        // Only test the pixels under the collider's bounds
+        let aabb = shape.compute_aabb(&position);
+        let col = |x: f32| ((x - top_left.x) / pixel_size.x).max(0.0) as usize;
+        let row = |y: f32| ((top_left.y - y) / pixel_size.y).max(0.0) as usize;
+        let cols = col(aabb.mins.x - thickness)..(col(aabb.maxs.x + thickness) + 1).min(width);
+        let rows = row(aabb.maxs.y + thickness)..(row(aabb.mins.y - thickness) + 1).min(height);
+
+        for y in rows {
+            for x in cols.clone() {
+                let point = Point::new(
+                    top_left.x + (x as f32 + 0.5) * pixel_size.x,
+                    top_left.y - (y as f32 + 0.5) * pixel_size.y,
+                );
+                if shape.distance_to_point(&position, &point, true) > thickness {
+                    continue;
+                }
+                let index = y * width + x;
+                if config.grayscale {
+                    pixels[index] = gray;
+                } else {
+                    for (channel, value) in color.iter().enumerate() {
+                        pixels[channel * width * height + index] = *value;
+                    }
+                }
+            }
+        }
+    }
+    pixels
+}
+
+// Rapier places colliders during the physics step, one spawned since then still has
+// its position relative to its body
+fn world_position(world: &World, entity: Entity, position: &ColliderPosition) -> Isometry<Real> {
+    if world.get::<ColliderParent>(entity).is_some() {
+        return position.0;
+    }
+    let body = world.get::<Parent>(entity).map_or(entity, |parent| parent.0);
+    match world.get::<RigidBodyPosition>(body) {
+        Some(rb_pos) => rb_pos.position * position.0,
+        None => position.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4x4 pixels over a 4x4 view, pixel centers at -1.5, -0.5, 0.5 and 1.5 from the origin
+    fn spawn_square(world: &mut World, instance: Instance, offset: Vec2, color: Color) {
+        let position: ColliderPosition = (instance.origin + offset).into();
+        world.spawn().insert_bundle((
+            instance,
+            ColliderShape::cuboid(0.25, 0.25),
+            position,
+            ColliderDebugRender::from(color),
+        ));
+    }
+
+    fn draw(world: &mut World, instance: &Instance, grayscale: bool) -> Vec<f32> {
+        let config = PixelConfig {
+            width: 4,
+            height: 4,
+            grayscale: grayscale,
+        };
+        rasterize(world, instance, Vec2::new(2.0, 2.0), &config)
+    }
+
+    #[test]
+    fn draws_colliders_top_row_first() {
+        let mut world = World::default();
+        let instance = Instance {
+            index: 1,
+            origin: Vec2::new(10.0, 5.0),
+        };
+        // Top left pixel
+        spawn_square(&mut world, instance, Vec2::new(-1.5, 1.5), Color::RED);
+        // Bottom left, partly left of the view
+        spawn_square(&mut world, instance, Vec2::new(-2.1, -1.5), Color::BLUE);
+        // Another instance's collider on top of ours isn't drawn
+        spawn_square(&mut world, Instance::default(), Vec2::new(10.0 - 1.5, 5.0 - 1.5), Color::BLACK);
+
+        let gray = draw(&mut world, &instance, true);
+        let mut expected = vec![BACKGROUND; 16];
+        expected[0] = 0.299;
+        expected[3 * 4] = 0.114;
+        assert_eq!(gray, expected);
+
+        // Channels first, each a full image
+        let rgb = draw(&mut world, &instance, false);
+        assert_eq!(rgb.len(), 3 * 16);
+        assert_eq!([rgb[0], rgb[16], rgb[32]], [1.0, 0.0, 0.0]);
+        assert_eq!([rgb[12], rgb[16 + 12], rgb[32 + 12]], [0.0, 0.0, 1.0]);
+        assert_eq!(rgb.iter().filter(|v| **v != BACKGROUND).count(), 4);
+    }
+
+    #[test]
+    fn draws_colliders_placed_on_their_body_before_a_physics_step() {
+        let mut world = World::default();
+        let instance = Instance::default();
+        // Right of the second row, partly right of the view, still relative to its body
+        let body: RigidBodyPosition = (instance.origin + Vec2::new(2.1, 0.5)).into();
+        let position: ColliderPosition = Vec2::ZERO.into();
+        world.spawn().insert_bundle((
+            instance,
+            body,
+            ColliderShape::cuboid(0.25, 0.25),
+            position,
+            ColliderDebugRender::from(Color::BLACK),
+        ));
+
+        let gray = draw(&mut world, &instance, true);
+        let mut expected = vec![BACKGROUND; 16];
+        expected[4 + 3] = 0.0;
+        assert_eq!(gray, expected);
+    }
+}
@@ -0,0 +1,248 @@
+// Action and observation spaces
+//
+// Based on OpenAI Gym spaces, describes what actions an environment accepts and
+// what its observations look like, so models can size themselves.
+
+use rand::Rng;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Space {
+    // n options, 0..n
+    Discrete(usize),
+    // Continuous values between low and high, bounds can be infinite
+    Box {
+        low: Vec<f32>,
+        high: Vec<f32>,
+        shape: Vec<usize>,
+    },
+    // Several discrete options, each 0..n
+    MultiDiscrete(Vec<usize>),
+    // n on or off values
+    MultiBinary(usize),
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Discrete(usize),
+    Box(Vec<f32>),
+    MultiDiscrete(Vec<usize>),
+    MultiBinary(Vec<bool>),
+}
+
+#[allow(dead_code)]
+impl Space {
+    // One dimensional box
+    pub fn boxed(low: Vec<f32>, high: Vec<f32>) -> Self {
+        assert_eq!(low.len(), high.len(), "Box bounds should be the same length");
+        let shape = vec![low.len()];
+        Space::Box { low, high, shape }
+    }
+
+    // One dimensional box with no bounds
+    pub fn unbounded(size: usize) -> Self {
+        Space::boxed(vec![f32::NEG_INFINITY; size], vec![f32::INFINITY; size])
+    }
+
+    // Number of values when flattened into a vector, what an observation will be
+    pub fn flat_size(&self) -> usize {
+        match self {
+            Space::Discrete(_) => 1,
+            Space::Box { shape, .. } => shape.iter().product(),
+            Space::MultiDiscrete(nvec) => nvec.len(),
+            Space::MultiBinary(n) => *n,
+        }
+    }
+
+    // Shape of an observation from this space, flat unless a box says otherwise
+    pub fn shape(&self) -> Vec<usize> {
+        match self {
+            Space::Box { shape, .. } => shape.clone(),
+            _ => vec![self.flat_size()],
+        }
+    }
+
+    // Number of outputs a network needs to pick from this space
+    pub fn output_size(&self) -> usize {
+        match self {
+            Space::Discrete(n) => *n,
+            Space::Box { shape, .. } => shape.iter().product(),
+            Space::MultiDiscrete(nvec) => nvec.iter().sum(),
+            Space::MultiBinary(n) => *n,
+        }
+    }
+
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Action {
+        match self {
+            Space::Discrete(n) => Action::Discrete(rng.gen_range(0..*n)),
+            Space::Box { low, high, .. } => Action::Box(
+                low.iter()
+                    .zip(high.iter())
+                    .map(|(low, high)| sample_bounded(rng, *low, *high))
+                    .collect(),
+            ),
+            Space::MultiDiscrete(nvec) => {
+                Action::MultiDiscrete(nvec.iter().map(|n| rng.gen_range(0..*n)).collect())
+            }
+            Space::MultiBinary(n) => Action::MultiBinary((0..*n).map(|_| rng.gen()).collect()),
+        }
+    }
+
+    pub fn contains(&self, action: &Action) -> bool {
+        match (self, action) {
+            (Space::Discrete(n), Action::Discrete(a)) => a < n,
+            (Space::Box { .. }, Action::Box(values)) => self.contains_observation(values),
+            (Space::MultiDiscrete(nvec), Action::MultiDiscrete(values)) => {
+                nvec.len() == values.len() && values.iter().zip(nvec.iter()).all(|(v, n)| v < n)
+            }
+            (Space::MultiBinary(n), Action::MultiBinary(values)) => values.len() == *n,
+            _ => false,
+        }
+    }
+
+    // Check a flat observation fits in the space
+    pub fn contains_observation(&self, observation: &[f32]) -> bool {
+        if observation.len() != self.flat_size() {
+            return false;
+        }
+        match self {
+            Space::Discrete(n) => observation[0] >= 0.0 && (observation[0] as usize) < *n,
+            Space::Box { low, high, .. } => observation
+                .iter()
+                .zip(low.iter().zip(high.iter()))
+                .all(|(v, (low, high))| v >= low && v <= high),
+            Space::MultiDiscrete(nvec) => observation
+                .iter()
+                .zip(nvec.iter())
+                .all(|(v, n)| *v >= 0.0 && (*v as usize) < *n),
+            Space::MultiBinary(_) => observation.iter().all(|v| *v == 0.0 || *v == 1.0),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Action {
+    // Most environments only take discrete actions
+    pub fn discrete(&self) -> usize {
+        match self {
+            Action::Discrete(a) => *a,
+            _ => panic!("action should be discrete: {:?}", self),
+        }
+    }
+
+    // Flatten into a vector, used to record history
+    pub fn to_vec(&self) -> Vec<f32> {
+        match self {
+            Action::Discrete(a) => vec![*a as f32],
+            Action::Box(values) => values.clone(),
+            Action::MultiDiscrete(values) => values.iter().map(|v| *v as f32).collect(),
+            Action::MultiBinary(values) => values.iter().map(|v| *v as u8 as f32).collect(),
+        }
+    }
+}
+
+// Same as gym, uniform when bounded, otherwise normal or exponential
+fn sample_bounded<R: Rng + ?Sized>(rng: &mut R, low: f32, high: f32) -> f32 {
+    match (low.is_finite(), high.is_finite()) {
+        (true, true) => rng.gen_range(low..=high),
+        (true, false) => low + sample_exponential(rng),
+        (false, true) => high - sample_exponential(rng),
+        (false, false) => sample_normal(rng),
+    }
+}
+
+fn sample_exponential<R: Rng + ?Sized>(rng: &mut R) -> f32 {
+    -(1.0 - rng.gen::<f32>()).ln()
+}
+
+// Box-Muller
+fn sample_normal<R: Rng + ?Sized>(rng: &mut R) -> f32 {
+    let u1 = 1.0 - rng.gen::<f32>();
+    let u2 = rng.gen::<f32>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    fn spaces() -> Vec<Space> {
+        vec![
+            Space::Discrete(3),
+            Space::boxed(vec![-1.0, 0.0], vec![1.0, 5.0]),
+            Space::unbounded(3),
+            Space::boxed(vec![0.0, f32::NEG_INFINITY], vec![f32::INFINITY, 0.0]),
+            Space::MultiDiscrete(vec![2, 5, 3]),
+            Space::MultiBinary(4),
+        ]
+    }
+
+    #[test]
+    fn samples_are_contained() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        for space in spaces() {
+            for _ in 0..100 {
+                let action = space.sample(&mut rng);
+                assert!(space.contains(&action), "{:?} not in {:?}", action, space);
+                assert_eq!(action.to_vec().len(), space.flat_size());
+            }
+        }
+    }
+
+    #[test]
+    fn contains_checks_bounds_and_kind() {
+        assert!(Space::Discrete(3).contains(&Action::Discrete(2)));
+        assert!(!Space::Discrete(3).contains(&Action::Discrete(3)));
+        assert!(!Space::Discrete(3).contains(&Action::Box(vec![0.0])));
+
+        let space = Space::boxed(vec![-1.0, 0.0], vec![1.0, 5.0]);
+        assert!(space.contains(&Action::Box(vec![-1.0, 5.0])));
+        assert!(!space.contains(&Action::Box(vec![-1.1, 1.0])));
+        assert!(!space.contains(&Action::Box(vec![0.0])));
+
+        let space = Space::MultiDiscrete(vec![2, 5]);
+        assert!(space.contains(&Action::MultiDiscrete(vec![1, 4])));
+        assert!(!space.contains(&Action::MultiDiscrete(vec![2, 0])));
+        assert!(!space.contains(&Action::MultiDiscrete(vec![1])));
+
+        assert!(Space::MultiBinary(2).contains(&Action::MultiBinary(vec![true, false])));
+        assert!(!Space::MultiBinary(2).contains(&Action::MultiBinary(vec![true])));
+    }
+
+    #[test]
+    fn contains_observation_checks_size() {
+        assert!(Space::Discrete(3).contains_observation(&[2.0]));
+        assert!(!Space::Discrete(3).contains_observation(&[3.0]));
+        assert!(Space::unbounded(2).contains_observation(&[1e9, -1e9]));
+        assert!(!Space::unbounded(2).contains_observation(&[0.0]));
+        assert!(Space::MultiBinary(2).contains_observation(&[0.0, 1.0]));
+        assert!(!Space::MultiBinary(2).contains_observation(&[0.5, 1.0]));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(Space::Discrete(3).flat_size(), 1);
+        assert_eq!(Space::Discrete(3).output_size(), 3);
+        assert_eq!(Space::Discrete(3).shape(), vec![1]);
+
+        let image = Space::Box {
+            low: vec![0.0; 2 * 3 * 4],
+            high: vec![1.0; 2 * 3 * 4],
+            shape: vec![2, 3, 4],
+        };
+        assert_eq!(image.flat_size(), 24);
+        assert_eq!(image.output_size(), 24);
+        assert_eq!(image.shape(), vec![2, 3, 4]);
+
+        let space = Space::MultiDiscrete(vec![2, 5, 3]);
+        assert_eq!(space.flat_size(), 3);
+        assert_eq!(space.output_size(), 10);
+        assert_eq!(space.shape(), vec![3]);
+
+        assert_eq!(Space::MultiBinary(4).flat_size(), 4);
+        assert_eq!(Space::MultiBinary(4).output_size(), 4);
+    }
+}
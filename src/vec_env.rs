@@ -0,0 +1,180 @@
+// Vectorized environment, N copies of an environment stepped in lockstep
+//
+// Every copy lives in the same world, each is an Instance offset from the last with
+// its own collision group, so they never touch. One tick steps physics for all of
+// them, which is much cheaper than running N apps.
+
+use bevy::prelude::*;
+
+use crate::environment::*;
+use crate::environments::EnvironmentType;
+use crate::error::GymError;
+use crate::gym::Gym;
+use crate::space::{Action, Space};
+
+// Batched result of a step, one entry per instance
+#[derive(Debug, Clone, Default)]
+pub struct VecStep {
+    pub observations: Vec<Vec<f32>>,
+    pub rewards: Vec<f32>,
+    pub dones: Vec<bool>,
+    pub infos: Vec<Info>,
+    // Last observation of the episodes that finished, their entry in observations is
+    // already the next episode's first, gym has it in info as final_observation
+    pub final_observations: Vec<Option<Vec<f32>>>,
+}
+
+pub struct VecEnv {
+    app: App,
+    fns: EnvironmentFns,
+    instances: Vec<Instance>,
+    action_space: Space,
+}
+
+impl VecEnv {
+    pub fn new(env: EnvironmentType, n: usize, config: EnvironmentConfig) -> Self {
+        assert!(n > 0, "VecEnv needs at least one instance");
+        let mut builder = Gym::builder(env, config);
+        // We drive every instance ourselves
+        builder.insert_resource(ManualStep);
+        let mut app = std::mem::take(&mut builder.app);
+        // Run the startup systems
+        app.update();
+
+        let fns = *app.world.get_resource::<EnvironmentFns>().unwrap();
+        let action_space = app.world.get_resource::<Environment>().unwrap().action_space.clone();
+        let offset = (fns.instance_offset)(&app.world);
+        let mut vec_env = Self {
+            app,
+            fns,
+            instances: (0..n).map(|i| Instance::new(i, offset)).collect(),
+            action_space,
+        };
+        vec_env.reset(None);
+        vec_env
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.app.world.get_resource::<Environment>().unwrap().clone()
+    }
+
+    // Reset every instance, returns the first observations
+    //
+    // Instances share one rng and reset in order, so a seed replays all of them
+    pub fn reset(&mut self, seed: Option<u64>) -> Vec<Vec<f32>> {
+        if seed.is_some() {
+            self.app.world.insert_resource(EnvRng::new(seed));
+        }
+        let world = &mut self.app.world;
+        let fns = self.fns;
+        self.instances
+            .iter()
+            .map(|instance| (fns.reset)(world, instance))
+            .collect()
+    }
+
+    // Apply one action per instance and advance the world one physics step, or more
+    // with action repeat
+    //
+    // Instances that finish are reset right away, their observation is the first
+    // of the new episode and the last is in final_observations, same as gym's vector envs
+    pub fn step(&mut self, actions: &[Action]) -> Result<VecStep, GymError> {
+        if actions.len() != self.instances.len()
+            || !actions.iter().all(|a| self.action_space.contains(a))
+        {
+            return Err(GymError::InvalidAction);
+        }
+
+        // Keep stepping until every instance is done repeating its action, finished
+        // instances sit idle until the rest catch up
+        let mut steps: Vec<Option<Step>> = vec![None; self.instances.len()];
+        let App { world, schedule, .. } = &mut self.app;
+        while steps.iter().any(|step| step.is_none()) {
+            let mut tick = |world: &mut World| schedule.run(world);
+            step_lockstep(world, self.fns, &self.instances, actions, &mut steps, &mut tick);
+        }
+
+        let mut result = VecStep::default();
+        for (instance, step) in self.instances.iter().zip(steps.into_iter()) {
+            let step = step.unwrap();
+            if step.is_done {
+                result.observations.push((self.fns.reset)(&mut self.app.world, instance));
+                result.final_observations.push(Some(step.observation));
+            } else {
+                result.observations.push(step.observation);
+                result.final_observations.push(None);
+            }
+            result.rewards.push(step.reward);
+            result.dones.push(step.is_done);
+            result.infos.push(step.info);
+        }
+        Ok(result)
+    }
+}
+
+// Steps every instance that hasn't got its step yet with a single tick between them
+//
+// Each instance advances the world by stepping the next one and the last runs the
+// schedule, so they all act, physics runs once, then they all observe.
+fn step_lockstep(
+    world: &mut World,
+    fns: EnvironmentFns,
+    instances: &[Instance],
+    actions: &[Action],
+    steps: &mut [Option<Step>],
+    tick: &mut dyn FnMut(&mut World),
+) {
+    let (instance, rest) = match instances.split_first() {
+        Some(split) => split,
+        None => return tick(world),
+    };
+    let (step, rest_steps) = steps.split_first_mut().unwrap();
+    let mut advance = |world: &mut World| step_lockstep(world, fns, rest, &actions[1..], rest_steps, tick);
+    if step.is_none() {
+        *step = (fns.step)(world, instance, &actions[0], &mut advance);
+    } else {
+        advance(world);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::test_config;
+
+    #[test]
+    fn instances_step_independently_and_reset() {
+        let mut vec_env = VecEnv::new("cartpole".parse().unwrap(), 3, test_config(Some(7)));
+        let actions = vec![Action::Discrete(0), Action::Discrete(1), Action::Discrete(0)];
+
+        // Pushed left and right, the carts head opposite ways
+        let mut step = vec_env.step(&actions).unwrap();
+        for _ in 0..4 {
+            step = vec_env.step(&actions).unwrap();
+        }
+        assert_eq!(step.observations.len(), 3);
+        assert!(step.observations[0][1] < 0.0);
+        assert!(step.observations[1][1] > 0.0);
+
+        // Keep pushing until a cart runs off or a pole falls
+        let mut steps = 0;
+        while !step.dones.iter().any(|d| *d) {
+            assert!(steps < 1000, "no instance finished");
+            step = vec_env.step(&actions).unwrap();
+            steps += 1;
+        }
+        for i in 0..3 {
+            if step.dones[i] {
+                // Back at the start with the end kept aside
+                assert!(step.final_observations[i].is_some());
+                assert!(step.observations[i][0].abs() < 0.1);
+            } else {
+                assert!(step.final_observations[i].is_none());
+            }
+        }
+    }
+}
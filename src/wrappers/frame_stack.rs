@@ -0,0 +1,59 @@
+// Frame stacking
+//
+// Observations become the last n observations of an instance, oldest first, so a model
+// can see motion. Image frames stack on the channel dimension.
+use std::collections::{HashMap, VecDeque};
+
+use crate::space::Space;
+
+pub struct FrameStack {
+    n: usize,
+    frames: HashMap<usize, VecDeque<Vec<f32>>>,
+}
+
+impl FrameStack {
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0, "Frame stack needs at least one frame");
+        Self {
+            n: n,
+            frames: HashMap::new(),
+        }
+    }
+
+    // The observation space once stacked
+    pub fn space(&self, space: &Space) -> Space {
+        match space {
+            Space::Box { low, high, shape } => {
+                let mut shape = shape.clone();
+                shape[0] *= self.n;
+                Space::Box {
+                    low: low.repeat(self.n),
+                    high: high.repeat(self.n),
+                    shape: shape,
+                }
+            }
+            _ => panic!("Can only stack box observations, got {:?}", space),
+        }
+    }
+
+    // Start the instance over, its first observation fills every frame
+    pub fn reset(&mut self, index: usize, observation: Vec<f32>) -> Vec<f32> {
+        let frames = std::iter::repeat(observation).take(self.n).collect();
+        self.frames.insert(index, frames);
+        self.stacked(index)
+    }
+
+    pub fn push(&mut self, index: usize, observation: Vec<f32>) -> Vec<f32> {
+        if !self.frames.contains_key(&index) {
+            return self.reset(index, observation);
+        }
+        let frames = self.frames.get_mut(&index).unwrap();
+        frames.pop_front();
+        frames.push_back(observation);
+        self.stacked(index)
+    }
+
+    fn stacked(&self, index: usize) -> Vec<f32> {
+        self.frames[&index].iter().flatten().cloned().collect()
+    }
+}
@@ -0,0 +1,317 @@
+// Wrappers
+//
+// Change what an agent sees of any environment, applied around each step by
+// reset_instance and observe_instance, in order:
+// action repeat, time limit, reward scale and clip, observation normalize and clip, frame stack
+//
+// Set from the command line, or a toml file with the same names, see params/wrappers.toml
+mod frame_stack;
+mod normalize;
+
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{environment::Step, space::Space};
+
+pub use self::{frame_stack::FrameStack, normalize::RunningMeanStd};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WrapperConfig {
+    // Normalize observations with their running mean and variance
+    pub normalize_observation: bool,
+    // Clip observations to +-, after normalizing
+    pub clip_observation: Option<f32>,
+    pub reward_scale: f32,
+    // Clip rewards to +-, after scaling
+    pub clip_reward: Option<f32>,
+    // Observations are the last n observations, 1 is off
+    pub frame_stack: usize,
+    // Each action is applied for n physics steps, rewards are summed
+    pub action_repeat: usize,
+    // Episodes are cut short after n steps, info has truncated set
+    pub time_limit: Option<usize>,
+}
+
+impl Default for WrapperConfig {
+    fn default() -> Self {
+        Self {
+            normalize_observation: false,
+            clip_observation: None,
+            reward_scale: 1.0,
+            clip_reward: None,
+            frame_stack: 1,
+            action_repeat: 1,
+            time_limit: None,
+        }
+    }
+}
+
+impl WrapperConfig {
+    // Keys in the file replace the defaults, the rest are kept, like pixels' frame stack
+    pub fn load(path: &Path, defaults: WrapperConfig) -> Self {
+        let file = match fs::read_to_string(path) {
+            Ok(file) => file,
+            Err(e) => panic!("Couldn't read wrappers file {}: {}", path.display(), e),
+        };
+        match defaults.merge(&file) {
+            Ok(config) => config,
+            Err(e) => panic!("Couldn't parse wrappers file {}: {}", path.display(), e),
+        }
+    }
+
+    fn merge(self, file: &str) -> Result<Self, toml::de::Error> {
+        let file: toml::value::Table = toml::from_str(file)?;
+        let mut config = match toml::Value::try_from(self).unwrap() {
+            toml::Value::Table(config) => config,
+            _ => unreachable!(),
+        };
+        config.extend(file);
+        toml::Value::Table(config).try_into()
+    }
+}
+
+// Where an instance is in its episode
+#[derive(Debug, Default)]
+struct Episode {
+    steps: usize,
+    frames: usize, // physics steps into the current action
+    reward: f32,   // summed over the current action
+}
+
+pub struct Wrappers {
+    config: WrapperConfig,
+    normalize: Option<RunningMeanStd>,
+    frame_stack: Option<FrameStack>,
+    episodes: HashMap<usize, Episode>,
+}
+
+impl Wrappers {
+    // observation_space is what the environment observes, before any wrapper
+    pub fn new(config: WrapperConfig, observation_space: &Space) -> Self {
+        assert!(config.action_repeat > 0, "Action repeat needs to be at least 1");
+        let normalize = match config.normalize_observation {
+            true => Some(RunningMeanStd::new(observation_space.flat_size(), config.clip_observation)),
+            false => None,
+        };
+        let frame_stack = match config.frame_stack {
+            n if n > 1 => Some(FrameStack::new(n)),
+            _ => None,
+        };
+        Self {
+            config: config,
+            normalize: normalize,
+            frame_stack: frame_stack,
+            episodes: HashMap::new(),
+        }
+    }
+
+    // The observation space an agent sees
+    pub fn space(&self, space: &Space) -> Space {
+        let mut space = space.clone();
+        if let Some(normalize) = &self.normalize {
+            space = normalize.space(&space);
+        } else if let (Some(clip), Space::Box { low, high, .. }) = (self.config.clip_observation, &mut space) {
+            low.iter_mut().for_each(|low| *low = low.max(-clip));
+            high.iter_mut().for_each(|high| *high = high.min(clip));
+        }
+        if let Some(frame_stack) = &self.frame_stack {
+            space = frame_stack.space(&space);
+        }
+        space
+    }
+
+    // Start the instance's episode over, returns the first observation
+    pub fn reset(&mut self, index: usize, observation: Vec<f32>) -> Vec<f32> {
+        self.episodes.insert(index, Episode::default());
+        let observation = self.observation(observation);
+        match &mut self.frame_stack {
+            Some(frame_stack) => frame_stack.reset(index, observation),
+            None => observation,
+        }
+    }
+
+    // Still repeating the instance's last action, nothing to observe yet
+    pub fn is_repeating(&self, index: usize) -> bool {
+        self.episodes.get(&index).map_or(false, |episode| episode.frames > 0)
+    }
+
+    // Call after each physics step, false while the action is still being repeated,
+    // otherwise the step's reward is summed over the repeat
+    pub fn repeat(&mut self, index: usize, step: &mut Step) -> bool {
+        let episode = self.episodes.entry(index).or_default();
+        episode.frames += 1;
+        episode.reward += step.reward;
+        if episode.frames < self.config.action_repeat && !step.is_done {
+            return false;
+        }
+        step.reward = episode.reward;
+        episode.frames = 0;
+        episode.reward = 0.0;
+        true
+    }
+
+    // Once the action is done repeating, wraps the rest of the step
+    pub fn step(&mut self, index: usize, step: &mut Step) {
+        let episode = self.episodes.entry(index).or_default();
+        episode.steps += 1;
+        if let Some(limit) = self.config.time_limit {
+            if episode.steps >= limit && !step.is_done {
+                step.is_done = true;
+                step.info.insert("truncated", 1.0);
+            }
+        }
+
+        step.reward *= self.config.reward_scale;
+        if let Some(clip) = self.config.clip_reward {
+            step.reward = step.reward.clamp(-clip, clip);
+        }
+
+        let observation = self.observation(std::mem::take(&mut step.observation));
+        step.observation = match &mut self.frame_stack {
+            Some(frame_stack) => frame_stack.push(index, observation),
+            None => observation,
+        };
+    }
+
+    fn observation(&mut self, observation: Vec<f32>) -> Vec<f32> {
+        match (&mut self.normalize, self.config.clip_observation) {
+            (Some(normalize), _) => normalize.normalize(&observation),
+            (None, Some(clip)) => observation.iter().map(|x| x.clamp(-clip, clip)).collect(),
+            (None, None) => observation,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(observation: Vec<f32>, reward: f32) -> Step {
+        Step {
+            observation: observation,
+            reward: reward,
+            ..Default::default()
+        }
+    }
+
+    fn wrappers(config: WrapperConfig) -> Wrappers {
+        Wrappers::new(config, &Space::unbounded(2))
+    }
+
+    #[test]
+    fn action_repeat_sums_rewards() {
+        let mut wrappers = wrappers(WrapperConfig {
+            action_repeat: 3,
+            ..Default::default()
+        });
+        let mut s = step(vec![0.0, 0.0], 1.0);
+        assert!(!wrappers.repeat(0, &mut s));
+        assert!(wrappers.is_repeating(0));
+        assert!(!wrappers.repeat(0, &mut s));
+        assert!(wrappers.repeat(0, &mut s));
+        assert_eq!(s.reward, 3.0);
+        assert!(!wrappers.is_repeating(0));
+
+        // Done cuts the repeat short
+        let mut s = step(vec![0.0, 0.0], 1.0);
+        s.is_done = true;
+        assert!(wrappers.repeat(0, &mut s));
+        assert_eq!(s.reward, 1.0);
+    }
+
+    #[test]
+    fn time_limit_truncates() {
+        let mut wrappers = wrappers(WrapperConfig {
+            time_limit: Some(2),
+            ..Default::default()
+        });
+        wrappers.reset(0, vec![0.0, 0.0]);
+        let mut s = step(vec![0.0, 0.0], 1.0);
+        wrappers.step(0, &mut s);
+        assert!(!s.is_done);
+        wrappers.step(0, &mut s);
+        assert!(s.is_done);
+        assert_eq!(s.info.get("truncated"), Some(&1.0));
+
+        // A real end isn't a truncation, and reset starts the count over
+        wrappers.reset(0, vec![0.0, 0.0]);
+        let mut s = step(vec![0.0, 0.0], 1.0);
+        s.is_done = true;
+        wrappers.step(0, &mut s);
+        assert!(!s.info.contains_key("truncated"));
+    }
+
+    #[test]
+    fn reward_scaled_then_clipped() {
+        let mut wrappers = wrappers(WrapperConfig {
+            reward_scale: 0.5,
+            clip_reward: Some(1.0),
+            ..Default::default()
+        });
+        let mut s = step(vec![0.0, 0.0], 10.0);
+        wrappers.step(0, &mut s);
+        assert_eq!(s.reward, 1.0);
+        let mut s = step(vec![0.0, 0.0], -1.0);
+        wrappers.step(0, &mut s);
+        assert_eq!(s.reward, -0.5);
+    }
+
+    #[test]
+    fn normalize_centers_and_clips() {
+        let mut wrappers = wrappers(WrapperConfig {
+            normalize_observation: true,
+            clip_observation: Some(5.0),
+            ..Default::default()
+        });
+        let mut last = vec![];
+        for i in 0..1000 {
+            let mut s = step(vec![100.0 + (i % 2) as f32 * 2.0, -3.0], 0.0);
+            wrappers.step(0, &mut s);
+            last = s.observation;
+        }
+        // 100 and 102 around a mean of 101 with a std of 1
+        assert!((last[0].abs() - 1.0).abs() < 0.01);
+        // Constant is the mean, 0 once normalized
+        assert!(last[1].abs() < 0.01);
+
+        let mut s = step(vec![1e6, -3.0], 0.0);
+        wrappers.step(0, &mut s);
+        assert_eq!(s.observation[0], 5.0);
+        assert_eq!(
+            wrappers.space(&Space::unbounded(2)),
+            Space::boxed(vec![-5.0, -5.0], vec![5.0, 5.0])
+        );
+    }
+
+    #[test]
+    fn frame_stack_keeps_last_frames() {
+        let mut wrappers = wrappers(WrapperConfig {
+            frame_stack: 3,
+            ..Default::default()
+        });
+        assert_eq!(wrappers.reset(0, vec![1.0, 1.0]), vec![1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+        let mut s = step(vec![2.0, 2.0], 0.0);
+        wrappers.step(0, &mut s);
+        assert_eq!(s.observation, vec![1.0, 1.0, 1.0, 1.0, 2.0, 2.0]);
+        // Instances stack apart
+        assert_eq!(wrappers.reset(1, vec![3.0, 3.0]), vec![3.0; 6]);
+        assert_eq!(wrappers.space(&Space::unbounded(2)).shape(), vec![6]);
+    }
+
+    #[test]
+    fn file_merges_over_defaults() {
+        let pixels = WrapperConfig {
+            frame_stack: 4,
+            ..Default::default()
+        };
+        let config = pixels.clone().merge("time_limit = 100\nclip_reward = 1.0").unwrap();
+        assert_eq!(config.frame_stack, 4);
+        assert_eq!(config.time_limit, Some(100));
+        assert_eq!(config.clip_reward, Some(1.0));
+
+        assert_eq!(pixels.clone().merge("frame_stack = 2").unwrap().frame_stack, 2);
+        assert!(pixels.merge("frame_stacks = 2").is_err());
+    }
+}
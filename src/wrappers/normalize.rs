@@ -0,0 +1,66 @@
+// Running mean and variance of observations
+//
+// Updated with every observation an agent sees, shared by all instances. Stats
+// aren't saved with checkpoints, a resumed run starts them over.
+use crate::space::Space;
+
+const EPSILON: f32 = 1e-8;
+
+pub struct RunningMeanStd {
+    mean: Vec<f64>,
+    var: Vec<f64>,
+    count: f64,
+    clip: Option<f32>,
+}
+
+impl RunningMeanStd {
+    pub fn new(size: usize, clip: Option<f32>) -> Self {
+        Self {
+            mean: vec![0.0; size],
+            var: vec![1.0; size],
+            count: EPSILON as f64,
+            clip: clip,
+        }
+    }
+
+    // Normalized observations are around 0, bounded by clip if set
+    pub fn space(&self, space: &Space) -> Space {
+        let bound = self.clip.unwrap_or(f32::INFINITY);
+        match space {
+            Space::Box { shape, .. } => Space::Box {
+                low: vec![-bound; self.mean.len()],
+                high: vec![bound; self.mean.len()],
+                shape: shape.clone(),
+            },
+            _ => panic!("Can only normalize box observations, got {:?}", space),
+        }
+    }
+
+    // Update with the observation then normalize it
+    pub fn normalize(&mut self, observation: &[f32]) -> Vec<f32> {
+        self.update(observation);
+        observation
+            .iter()
+            .zip(self.mean.iter().zip(self.var.iter()))
+            .map(|(x, (mean, var))| {
+                let x = ((*x as f64 - mean) / (var + EPSILON as f64).sqrt()) as f32;
+                match self.clip {
+                    Some(clip) => x.clamp(-clip, clip),
+                    None => x,
+                }
+            })
+            .collect()
+    }
+
+    // Welford's update with a batch of one
+    fn update(&mut self, observation: &[f32]) {
+        let count = self.count + 1.0;
+        for (i, x) in observation.iter().enumerate() {
+            let delta = *x as f64 - self.mean[i];
+            self.mean[i] += delta / count;
+            let m2 = self.var[i] * self.count + delta * delta * self.count / count;
+            self.var[i] = m2 / count;
+        }
+        self.count = count;
+    }
+}